@@ -0,0 +1,147 @@
+//! Direct calls to the Subsonic REST API, for the parts `sunk` doesn't cover.
+
+use md5;
+use rand::{self, Rng};
+use reqwest;
+use serde_json::Value;
+
+use conf::Conf;
+use error::Error;
+
+/// API version the calls are written against.
+const VERSION: &str = "1.16.1";
+
+pub struct Api {
+    http: reqwest::Client,
+    url: String,
+    username: String,
+    password: String,
+}
+
+impl Api {
+    pub fn new(cfg: &Conf) -> Api {
+        Api {
+            http: reqwest::Client::new(),
+            url: cfg.url.trim_end_matches('/').to_string(),
+            username: cfg.username.clone(),
+            password: cfg.password.clone(),
+        }
+    }
+
+    /// The full URL for calling a method, including authentication.
+    pub fn url(&self, method: &str, params: &[(&str, String)]) -> String {
+        let salt = format!("{:x}", rand::thread_rng().gen::<u64>());
+        let token =
+            format!("{:x}", md5::compute(format!("{}{}", self.password, salt)));
+        let mut query = vec![
+            ("u", self.username.clone()),
+            ("t", token),
+            ("s", salt),
+            ("v", VERSION.to_string()),
+            ("c", "subs".to_string()),
+            ("f", "json".to_string()),
+        ];
+        query.extend(params.iter().cloned());
+
+        let query = query
+            .iter()
+            .map(|&(k, ref v)| format!("{}={}", k, encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{}/rest/{}?{}", self.url, method, query)
+    }
+
+    /// Calls a method, returning the body of the response.
+    pub fn call(
+        &self,
+        method: &str,
+        params: &[(&str, String)],
+    ) -> ::std::result::Result<Value, Error> {
+        let url = self.url(method, params);
+        let mut body: Value = self.http.get(&url).send()?.json()?;
+        let res = body["subsonic-response"].take();
+        if res["status"] == "ok" {
+            Ok(res)
+        } else {
+            Err(Error::Service(format!(
+                "{} failed: {}",
+                method,
+                res["error"]["message"].as_str().unwrap_or("no response")
+            )))
+        }
+    }
+}
+
+/// Percent-encodes a query parameter.
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use md5;
+    use std::collections::HashMap;
+
+    use super::{encode, Api};
+    use conf::Conf;
+    use testhttp;
+
+    /// An API pointed at a server that answers one request with `body`.
+    fn serve(body: &'static str) -> Api {
+        Api::new(&Conf {
+            url: testhttp::serve(vec![(200, body)]).0,
+            ..Conf::default()
+        })
+    }
+
+    #[test]
+    fn encodes_reserved_characters() {
+        assert_eq!(encode("a-z_0.9~"), "a-z_0.9~");
+        assert_eq!(encode("AC/DC & co"), "AC%2FDC%20%26%20co");
+        assert_eq!(encode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn urls_carry_a_salted_token() {
+        let api = Api::new(&Conf {
+            url: "https://music.example/".into(),
+            username: "me".into(),
+            password: "s3cret".into(),
+            ..Conf::default()
+        });
+        let url = api.url("getSong", &[("id", "7".into())]);
+        assert!(url.starts_with("https://music.example/rest/getSong?"));
+
+        let query: HashMap<_, _> = url.splitn(2, '?')
+            .nth(1)
+            .unwrap()
+            .split('&')
+            .map(|p| {
+                let mut kv = p.splitn(2, '=');
+                (kv.next().unwrap(), kv.next().unwrap())
+            })
+            .collect();
+        let token = md5::compute(format!("s3cret{}", query["s"]));
+        assert_eq!(query["t"], format!("{:x}", token));
+        assert_eq!(query["u"], "me");
+        assert_eq!(query["id"], "7");
+        assert!(!url.contains("s3cret"));
+    }
+
+    #[test]
+    fn call_returns_the_response_or_its_error() {
+        let ok = r#"{"subsonic-response": {"status": "ok", "x": 1}}"#;
+        assert_eq!(serve(ok).call("ping", &[]).unwrap()["x"], 1);
+
+        let failed = r#"{"subsonic-response": {"status": "failed",
+                         "error": {"code": 70, "message": "Not found"}}}"#;
+        let err = serve(failed).call("getSong", &[]).unwrap_err();
+        assert_eq!(err.to_string(), "getSong failed: Not found");
+    }
+}
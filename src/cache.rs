@@ -0,0 +1,456 @@
+//! Audio for upcoming songs, downloaded ahead of time so playback doesn't
+//! depend on the connection holding up.
+//!
+//! When a song starts, the next `Conf::prefetch` songs in the queue are
+//! downloaded in the background. The cache holds at most
+//! `Conf::audio_cache` megabytes; when it's full, the song played longest
+//! ago is removed. Pinned songs are never removed to make room, so whole
+//! albums can be kept for listening offline.
+//!
+//! Songs are downloaded at the quality they'd be streamed at, and each file
+//! is named for its quality so a song is only played from the cache at the
+//! quality currently asked for.
+
+use crossbeam_channel::{self, Receiver, Sender};
+use reqwest;
+use serde_json;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use conf::Conf;
+use error::Error;
+use quality::{Preferences, Quality};
+
+/// How full the cache is.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Usage {
+    pub songs: usize,
+    pub pinned: usize,
+    pub bytes: u64,
+    pub max: u64,
+}
+
+struct State {
+    /// Cached files and their sizes, most recently played last.
+    recent: VecDeque<(String, u64)>,
+    pinned: HashSet<u64>,
+    /// Files waiting to be downloaded, so they're only asked for once.
+    pending: HashSet<String>,
+}
+
+impl State {
+    fn bytes(&self) -> u64 {
+        self.recent.iter().map(|&(_, size)| size).sum()
+    }
+}
+
+pub struct AudioCache {
+    dir: PathBuf,
+    /// Most bytes to keep, or 0 if the cache is turned off.
+    max: u64,
+    prefetch: usize,
+    prefs: Arc<Preferences>,
+    state: Mutex<State>,
+    send: Sender<(u64, Quality)>,
+    recv: Receiver<(u64, Quality)>,
+}
+
+impl AudioCache {
+    /// Loads what's in the cache directory. With the cache turned off it's
+    /// left alone, so turning it off doesn't throw away what's there.
+    pub fn new(cfg: &Conf, prefs: Arc<Preferences>) -> AudioCache {
+        let dir = cfg.cache.join("audio");
+        let max = cfg.audio_cache * 1024 * 1024;
+        let (send, recv) = crossbeam_channel::unbounded();
+        let cache = AudioCache {
+            dir,
+            max,
+            prefetch: cfg.prefetch,
+            prefs,
+            state: Mutex::new(State {
+                recent: VecDeque::new(),
+                pinned: HashSet::new(),
+                pending: HashSet::new(),
+            }),
+            send,
+            recv,
+        };
+        if max > 0 {
+            cache.load();
+        }
+        cache
+    }
+
+    fn load(&self) {
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            warn!("unable to create {}: {}", self.dir.display(), e);
+        }
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir).into_iter().flat_map(|d| d) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Downloads interrupted by the daemon stopping.
+            if name.ends_with(".part") {
+                let _ = fs::remove_file(entry.path());
+                continue
+            }
+            if song_id(&name).is_none() {
+                continue
+            }
+            if let Ok(meta) = entry.metadata() {
+                if let Ok(modified) = meta.modified() {
+                    files.push((modified, name, meta.len()));
+                }
+            }
+        }
+        files.sort();
+
+        let mut state = self.state.lock().unwrap();
+        state.recent = files.into_iter().map(|(_, n, s)| (n, s)).collect();
+        state.pinned = File::open(self.dir.join("pinned.json"))
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+            .unwrap_or_default();
+        self.prune(&mut state);
+    }
+
+    /// How many upcoming songs to download ahead of time.
+    pub fn prefetch_count(&self) -> usize {
+        if self.max == 0 {
+            0
+        } else {
+            self.prefetch
+        }
+    }
+
+    /// Where the song's audio is, if it's been downloaded at the quality
+    /// it would be streamed at.
+    pub fn path(&self, id: u64) -> Option<PathBuf> {
+        let name = file_name(id, &self.prefs.current().0);
+        let mut state = self.state.lock().unwrap();
+        let i = state.recent.iter().position(|&(ref n, _)| *n == name)?;
+        let entry = state.recent.remove(i).unwrap();
+        state.recent.push_back(entry);
+        Some(self.dir.join(name))
+    }
+
+    /// Queues songs to be downloaded in the background, skipping ones that
+    /// are already cached or on their way.
+    pub fn prefetch(&self, ids: &[u64]) {
+        if self.max == 0 {
+            return
+        }
+        let quality = self.prefs.current().0;
+        let mut state = self.state.lock().unwrap();
+        for &id in ids {
+            let name = file_name(id, &quality);
+            let cached = state.recent.iter().any(|&(ref n, _)| *n == name);
+            if !cached && state.pending.insert(name) {
+                self.send.send((id, quality.clone())).unwrap();
+            }
+        }
+    }
+
+    /// Keeps the songs in the cache for good, downloading them if needed.
+    pub fn pin(&self, ids: &[u64]) -> ::std::result::Result<(), Error> {
+        if self.max == 0 {
+            return Err("The audio cache is turned off".into())
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            state.pinned.extend(ids);
+            self.save_pins(&state)?;
+        }
+        self.prefetch(ids);
+        Ok(())
+    }
+
+    pub fn usage(&self) -> Usage {
+        let state = self.state.lock().unwrap();
+        Usage {
+            songs: state.recent.len(),
+            pinned: state.pinned.len(),
+            bytes: state.bytes(),
+            max: self.max,
+        }
+    }
+
+    /// Removes every song, pinned or not, returning how many files went.
+    /// Files that can't be removed are kept track of.
+    pub fn clear(&self) -> ::std::result::Result<usize, Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.recent.len();
+        let dir = &self.dir;
+        state.recent.retain(|&(ref name, _)| {
+            match fs::remove_file(dir.join(name)) {
+                Ok(_) => false,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => {
+                    warn!("unable to remove cached file {}: {}", name, e);
+                    true
+                }
+            }
+        });
+        let count = before - state.recent.len();
+        state.pinned.clear();
+        self.save_pins(&state)?;
+        Ok(count)
+    }
+
+    fn fetch(
+        &self,
+        id: u64,
+        quality: &Quality,
+    ) -> ::std::result::Result<(), Error> {
+        let url = self.prefs.stream_url_at(id, quality);
+        let name = file_name(id, quality);
+
+        debug!("downloading song {} into the cache", id);
+        let part = self.dir.join(format!("{}.part", name));
+        let mut res = reqwest::get(&url)?;
+        if !res.status().is_success() {
+            return Err(Error::Service(format!(
+                "Download failed: {}",
+                res.status()
+            )))
+        }
+        let size = io::copy(&mut res, &mut File::create(&part)?)?;
+        fs::rename(&part, self.dir.join(&name))?;
+
+        let mut state = self.state.lock().unwrap();
+        state.recent.push_back((name, size));
+        self.prune(&mut state);
+        Ok(())
+    }
+
+    /// Removes the least recently played songs until the cache fits, leaving
+    /// pinned ones alone.
+    fn prune(&self, state: &mut State) {
+        let mut bytes = state.bytes();
+        let mut i = 0;
+        while bytes > self.max && i < state.recent.len() {
+            let pinned = song_id(&state.recent[i].0)
+                .map_or(false, |id| state.pinned.contains(&id));
+            if pinned {
+                i += 1;
+                continue
+            }
+            let (name, size) = state.recent.remove(i).unwrap();
+            bytes -= size;
+            if let Err(e) = fs::remove_file(self.dir.join(&name)) {
+                warn!("unable to remove cached file {}: {}", name, e);
+            }
+        }
+    }
+
+    fn save_pins(&self, state: &State) -> ::std::result::Result<(), Error> {
+        let file = File::create(self.dir.join("pinned.json"))?;
+        serde_json::to_writer(file, &state.pinned)?;
+        Ok(())
+    }
+}
+
+/// The file holding a song's audio at a quality, such as `42` for the
+/// original or `42-opus-96k` for a transcoded version.
+fn file_name(id: u64, quality: &Quality) -> String {
+    let mut name = id.to_string();
+    if let Some(ref format) = quality.format {
+        name.push('-');
+        name.extend(format.chars().filter(|c| c.is_ascii_alphanumeric()));
+    }
+    if let Some(rate) = quality.max_bit_rate {
+        name.push_str(&format!("-{}k", rate));
+    }
+    name
+}
+
+fn song_id(name: &str) -> Option<u64> {
+    name.split('-').next()?.parse().ok()
+}
+
+/// Downloads songs queued with `prefetch` and `pin`, one at a time.
+pub fn spawn(cache: Arc<AudioCache>) {
+    let recv = cache.recv.clone();
+    thread::spawn(move || {
+        while let Ok((id, quality)) = recv.recv() {
+            let name = file_name(id, &quality);
+            if let Err(e) = cache.fetch(id, &quality) {
+                warn!("unable to cache song {}: {}", id, e);
+                let part = cache.dir.join(format!("{}.part", name));
+                let _ = fs::remove_file(part);
+            }
+            cache.state.lock().unwrap().pending.remove(&name);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{file_name, song_id, AudioCache};
+    use conf::Conf;
+    use quality::{Preferences, Quality};
+
+    const SONG: u64 = 400 * 1024;
+
+    /// A 1 MB cache holding songs 1, 2 and 3 at their original quality,
+    /// oldest first, with `pinned` pinned.
+    fn conf(name: &str, pinned: &str) -> Conf {
+        let cache = env::temp_dir()
+            .join(format!("subs-test-audio-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&cache);
+        let dir = cache.join("audio");
+        fs::create_dir_all(&dir).unwrap();
+        for id in 1..4 {
+            let mut f = File::create(dir.join(id.to_string())).unwrap();
+            f.write_all(&vec![0; SONG as usize]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        File::create(dir.join("4.part")).unwrap();
+        File::create(dir.join("pinned.json"))
+            .unwrap()
+            .write_all(pinned.as_bytes())
+            .unwrap();
+        Conf {
+            cache,
+            audio_cache: 1,
+            prefetch: 2,
+            metered: Some(false),
+            ..Conf::default()
+        }
+    }
+
+    fn cache(cfg: &Conf) -> AudioCache {
+        AudioCache::new(cfg, Arc::new(Preferences::new(cfg)))
+    }
+
+    #[test]
+    fn loading_makes_room_and_drops_partial_downloads() {
+        let cfg = conf("load", "[]");
+        let cache = cache(&cfg);
+        let usage = cache.usage();
+        assert_eq!((usage.songs, usage.bytes), (2, 2 * SONG));
+        assert_eq!(cache.path(1), None);
+        assert!(cache.path(2).is_some());
+        assert!(!cfg.cache.join("audio/4.part").exists());
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+
+    #[test]
+    fn pinned_and_recently_played_songs_stay() {
+        let cfg = conf("pinned", "[1]");
+        let cache = cache(&cfg);
+        assert!(cache.path(1).is_some());
+        assert_eq!(cache.path(2), None);
+
+        // Song 3 was played more recently than song 1, but 1 is pinned.
+        assert!(cache.path(3).is_some());
+        File::create(cfg.cache.join("audio/5")).unwrap();
+        {
+            let mut state = cache.state.lock().unwrap();
+            state.recent.push_back(("5".into(), SONG));
+            cache.prune(&mut state);
+        }
+        assert_eq!(cache.path(3), None);
+        assert!(cache.path(1).is_some());
+        assert!(cache.path(5).is_some());
+
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert_eq!(cache.usage().pinned, 0);
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+
+    #[test]
+    fn clear_keeps_track_of_what_it_cannot_remove() {
+        let cfg = conf("clear", "[3]");
+        let cache = cache(&cfg);
+        // A directory can't be removed as a file.
+        fs::create_dir_all(cfg.cache.join("audio/7/x")).unwrap();
+        cache.state.lock().unwrap().recent.push_back(("7".into(), 0));
+
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert!(!cfg.cache.join("audio/3").exists());
+        assert_eq!(cache.usage().songs, 1);
+        assert_eq!(cache.usage().pinned, 0);
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+
+    #[test]
+    fn prefetches_each_song_once() {
+        let cfg = conf("prefetch", "[]");
+        let cache = cache(&cfg);
+        assert_eq!(cache.prefetch_count(), 2);
+        cache.prefetch(&[3, 6, 6, 7]);
+        cache.prefetch(&[7]);
+        let next = || cache.recv.try_recv().ok().map(|(id, _)| id);
+        assert_eq!(next(), Some(6));
+        assert_eq!(next(), Some(7));
+        assert_eq!(next(), None);
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+
+    #[test]
+    fn only_plays_audio_at_the_current_quality() {
+        let cfg = conf("quality", "[]");
+        let prefs = Arc::new(Preferences::new(&cfg));
+        let cache = AudioCache::new(&cfg, prefs.clone());
+        assert!(cache.path(3).is_some());
+
+        let opus = Quality {
+            max_bit_rate: Some(96),
+            format: Some("opus".into()),
+        };
+        prefs.set(Some(opus.clone()));
+        assert_eq!(cache.path(3), None);
+        cache.prefetch(&[3]);
+        let (id, quality) = cache.recv.try_recv().unwrap();
+        assert_eq!((id, quality), (3, opus));
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+
+    #[test]
+    fn names_files_for_their_quality() {
+        let mut q = Quality::default();
+        assert_eq!(file_name(42, &q), "42");
+        q.max_bit_rate = Some(320);
+        assert_eq!(file_name(42, &q), "42-320k");
+        q.format = Some("../opus".into());
+        assert_eq!(file_name(42, &q), "42-opus-320k");
+        assert_eq!(song_id("42-opus-320k"), Some(42));
+        assert_eq!(song_id("pinned.json"), None);
+    }
+
+    #[test]
+    fn leaves_files_alone_when_turned_off() {
+        let cfg = Conf {
+            audio_cache: 0,
+            ..conf("off", "[1]")
+        };
+        let cache = cache(&cfg);
+        assert_eq!(cache.prefetch_count(), 0);
+        assert_eq!(cache.path(1), None);
+        assert!(cache.pin(&[1]).is_err());
+        cache.prefetch(&[1]);
+        assert!(cache.recv.try_recv().is_err());
+
+        for name in &["1", "2", "3", "4.part", "pinned.json"] {
+            assert!(cfg.cache.join("audio").join(name).exists());
+        }
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use clap;
 use clap::Shell;
 
+use statusbar::Format;
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "subs", about = "Subsonic player",
             setting_raw = "clap::AppSettings::VersionlessSubcommands")]
@@ -9,6 +11,10 @@ pub struct App {
     #[structopt(short = "v", long = "verbose", global_raw = "true")]
     pub verbosity: u64,
 
+    /// Controls the daemon at the given address instead of the local one
+    #[structopt(long = "host", global_raw = "true")]
+    pub host: Option<String>,
+
     #[structopt(subcommand)]
     pub cmd: AppCommand,
 }
@@ -22,6 +28,52 @@ pub enum AppCommand {
         name: String,
     },
 
+    /// Save the current playlist as a playlist on the server
+    #[structopt(name = "save")]
+    Save {
+        /// Name of the playlist
+        name: String,
+
+        /// Make the playlist visible to other users
+        #[structopt(long = "public")]
+        public: bool,
+
+        /// Comment to attach to the playlist
+        #[structopt(long = "comment")]
+        comment: Option<String>,
+
+        /// Replace a playlist that already has this name
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+    },
+
+    /// Write the current playlist to an M3U or XSPF file
+    #[structopt(name = "export")]
+    Export {
+        /// File to write; the extension picks the format
+        file: String,
+
+        /// Write stream URLs instead of song IDs, for use in other players.
+        /// The URLs carry your credentials, so anyone who can read the file
+        /// can log in as you
+        #[structopt(long = "urls-with-credentials")]
+        urls: bool,
+    },
+
+    /// Add the songs from an M3U or XSPF file to the current playlist
+    #[structopt(name = "import")]
+    Import {
+        /// File to read
+        file: String,
+    },
+
+    /// Manage playlists on the server
+    #[structopt(name = "playlist")]
+    Playlist {
+        #[structopt(subcommand)]
+        cmd: PlaylistCommand,
+    },
+
     /// List information from the library
     #[structopt(name = "list", alias = "ls")]
     List {
@@ -95,19 +147,23 @@ pub enum AppCommand {
 
     /// Initiate a scan of the library
     #[structopt(name = "update")]
-    Update,
+    Update {
+        /// Wait for the scan to finish, showing its progress
+        #[structopt(short = "w", long = "wait")]
+        wait: bool,
+    },
 
     /// Add a song to the current playlist
     #[structopt(name = "add")]
     Add {
-        /// Adds the first result for the query
+        /// Adds the first result for the query, or a local file or URL
         query: Vec<String>,
     },
 
     /// Add a song to play after the current song
     #[structopt(name = "addnext")]
     AddNext {
-        /// Adds the first result for the query
+        /// Adds the first result for the query, or a local file or URL
         query: Vec<String>,
     },
 
@@ -115,6 +171,163 @@ pub enum AppCommand {
     #[structopt(name = "status")]
     Status,
 
+    /// Display where the current song's cover art is cached
+    #[structopt(name = "cover")]
+    Cover {
+        /// Write the image to this file instead
+        #[structopt(short = "o", long = "out")]
+        out: Option<String>,
+    },
+
+    /// Display the lyrics of the current song
+    #[structopt(name = "lyrics")]
+    Lyrics {
+        /// Keep printing lines in time with the song, following along to the
+        /// next ones
+        #[structopt(short = "f", long = "follow")]
+        follow: bool,
+    },
+
+    /// Browse the library and control playback full-screen
+    #[structopt(name = "tui")]
+    Tui,
+
+    /// Print the status whenever it changes, for status bars
+    #[structopt(name = "statusbar")]
+    Statusbar {
+        /// Output format: waybar, i3blocks or polybar
+        #[structopt(short = "f", long = "format", default_value = "waybar")]
+        format: Format,
+
+        /// Seconds between updates while playing
+        #[structopt(short = "i", long = "interval", default_value = "5")]
+        interval: u64,
+    },
+
+    /// Display details of the current song, or of a song by ID or query
+    #[structopt(name = "info")]
+    Info {
+        /// Show the album instead of the song
+        #[structopt(short = "b", long = "album", conflicts_with = "artist")]
+        album: bool,
+
+        /// Show the artist instead of the song
+        #[structopt(short = "a", long = "artist", conflicts_with = "album")]
+        artist: bool,
+
+        /// ID or query to look up, or "current"
+        query: Vec<String>,
+    },
+
+    /// Star the current song, or the first match for a query
+    #[structopt(name = "star")]
+    Star {
+        /// Star the album instead of the song
+        #[structopt(short = "b", long = "album", conflicts_with = "artist")]
+        album: bool,
+
+        /// Star the artist instead of the song
+        #[structopt(short = "a", long = "artist", conflicts_with = "album")]
+        artist: bool,
+
+        /// Stars the first result for the query
+        query: Vec<String>,
+    },
+
+    /// Unstar the current song, or the first match for a query
+    #[structopt(name = "unstar")]
+    Unstar {
+        /// Unstar the album instead of the song
+        #[structopt(short = "b", long = "album", conflicts_with = "artist")]
+        album: bool,
+
+        /// Unstar the artist instead of the song
+        #[structopt(short = "a", long = "artist", conflicts_with = "album")]
+        artist: bool,
+
+        /// Unstars the first result for the query
+        query: Vec<String>,
+    },
+
+    /// Rate the current song, or the first match for a query
+    #[structopt(name = "rate")]
+    Rate {
+        /// Rating from 1 to 5
+        rating: u8,
+
+        /// Rate the album instead of the song
+        #[structopt(short = "b", long = "album", conflicts_with = "artist")]
+        album: bool,
+
+        /// Rate the artist instead of the song
+        #[structopt(short = "a", long = "artist", conflicts_with = "album")]
+        artist: bool,
+
+        /// Rates the first result for the query
+        query: Vec<String>,
+    },
+
+    /// Run commands read from stdin, one per line, as a single request
+    #[structopt(name = "batch")]
+    Batch {
+        /// Stop at the first command that fails
+        #[structopt(short = "e", long = "stop-on-error")]
+        stop_on_error: bool,
+    },
+
+    /// Download songs, albums or playlists to a directory
+    #[structopt(name = "download")]
+    Download {
+        /// Download the whole album
+        #[structopt(short = "b", long = "album", conflicts_with = "artist")]
+        album: bool,
+
+        /// Download everything by the artist
+        #[structopt(short = "a", long = "artist", conflicts_with = "album")]
+        artist: bool,
+
+        /// Download the playlist with this name instead
+        #[structopt(short = "p", long = "playlist")]
+        playlist: Option<String>,
+
+        /// Directory to download to
+        #[structopt(short = "t", long = "to", default_value = ".")]
+        to: String,
+
+        /// Download the streamed, transcoded version instead of the original
+        #[structopt(long = "transcode")]
+        transcode: bool,
+
+        /// Downloads the first result for the query, or "current"
+        query: Vec<String>,
+    },
+
+    /// Display or change the quality songs are streamed at
+    #[structopt(name = "quality")]
+    Quality {
+        /// Highest bitrate in kbps, "original" to stream the original
+        /// files, or "auto" to go back to the configured quality
+        setting: Option<String>,
+
+        /// Format for the server to transcode to, such as mp3 or opus
+        #[structopt(short = "f", long = "format")]
+        format: Option<String>,
+    },
+
+    /// Manage the cache of downloaded songs
+    #[structopt(name = "cache")]
+    Cache {
+        #[structopt(subcommand)]
+        cmd: CacheCommand,
+    },
+
+    /// Play and manage the server's internet radio stations
+    #[structopt(name = "radio")]
+    Radio {
+        #[structopt(subcommand)]
+        cmd: RadioCommand,
+    },
+
     /// Control the client daemon
     #[structopt(name = "daemon")]
     Daemon {
@@ -143,6 +356,122 @@ pub enum ListCommand {
         #[structopt(short = "n", default_value = "20")]
         number: usize,
     },
+
+    /// Display all starred artists, albums and songs
+    #[structopt(name = "starred")]
+    Starred {
+        /// Add the starred songs and albums to the current playlist
+        #[structopt(long = "add")]
+        add: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CacheCommand {
+    /// Display how much of the cache is used
+    #[structopt(name = "status")]
+    Status,
+
+    /// Remove every song from the cache, including pinned ones
+    #[structopt(name = "clear")]
+    Clear,
+
+    /// Download an album and keep it in the cache
+    #[structopt(name = "pin")]
+    Pin {
+        /// Pins the first album matching the query
+        query: Vec<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum RadioCommand {
+    /// Display every station
+    #[structopt(name = "list")]
+    List,
+
+    /// Play a station now, ahead of the rest of the queue
+    #[structopt(name = "play")]
+    Play {
+        /// Plays the first station whose name matches
+        name: Vec<String>,
+    },
+
+    /// Add a station; needs permission to manage radio stations
+    #[structopt(name = "create")]
+    Create {
+        name: String,
+
+        /// URL of the station's stream
+        url: String,
+
+        #[structopt(long = "homepage")]
+        homepage: Option<String>,
+    },
+
+    /// Remove a station, given its full name; needs permission to manage
+    /// radio stations
+    #[structopt(name = "delete")]
+    Delete { name: String },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum PlaylistCommand {
+    /// Display the songs in a playlist
+    #[structopt(name = "show")]
+    Show {
+        /// Name of the playlist
+        name: String,
+    },
+
+    /// Create a new playlist
+    #[structopt(name = "create")]
+    Create {
+        /// Name of the playlist
+        name: String,
+
+        /// Start the playlist with the songs in the current playlist
+        #[structopt(short = "q", long = "from-queue")]
+        from_queue: bool,
+    },
+
+    /// Add the current song, or the first match for a query, to a playlist
+    #[structopt(name = "add")]
+    Add {
+        /// Name of the playlist
+        name: String,
+
+        /// Adds the first result for the query; `current` or nothing adds the
+        /// current song
+        query: Vec<String>,
+    },
+
+    /// Remove a song from a playlist
+    #[structopt(name = "rm")]
+    Remove {
+        /// Name of the playlist
+        name: String,
+
+        /// Position of the song, as shown by `playlist show`
+        index: usize,
+    },
+
+    /// Rename a playlist
+    #[structopt(name = "rename")]
+    Rename {
+        /// Current name of the playlist
+        name: String,
+
+        /// New name for the playlist
+        new_name: String,
+    },
+
+    /// Delete a playlist
+    #[structopt(name = "delete")]
+    Delete {
+        /// Name of the playlist
+        name: String,
+    },
 }
 
 #[derive(Debug, StructOpt)]
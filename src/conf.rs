@@ -9,6 +9,70 @@ pub struct Conf {
     pub url: String,
     pub username: String,
     pub password: String,
+    /// Address for the MPD-compatible server to listen on, if any.
+    pub mpd_address: Option<String>,
+    /// Whether to offer an MPRIS2 service on the D-Bus session bus.
+    pub mpris: bool,
+    /// Address for the HTTP API to listen on, if any.
+    pub http_address: Option<String>,
+    pub http_port: u16,
+    /// Token HTTP clients must present, if any. Needed unless the API
+    /// listens on a loopback address.
+    pub http_token: Option<String>,
+    /// Address to listen on for remote `subs` clients, if any.
+    pub tcp_address: Option<String>,
+    /// Remote daemon to control instead of the local one.
+    pub host: Option<String>,
+    /// Secret shared between the daemon and its remote clients.
+    pub secret: Option<String>,
+    /// Whether to report plays to the Subsonic server.
+    pub scrobble: bool,
+    pub lastfm_url: String,
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    /// Session key from authorising the API key with a Last.fm account.
+    pub lastfm_session_key: Option<String>,
+    pub listenbrainz_url: String,
+    pub listenbrainz_token: Option<String>,
+    /// Size to download cover art at, in pixels.
+    pub cover_size: usize,
+    /// Most cover images to keep in the cache.
+    pub cover_cache: usize,
+    /// Whether to show a desktop notification when a song starts.
+    pub notify: bool,
+    /// Templates for the notification, filled in with `{title}`, `{artist}`,
+    /// `{album}` and `{duration}`.
+    pub notify_summary: String,
+    pub notify_body: String,
+    /// Commands run through `sh -c` when the player does something, with
+    /// details of the song in `SUBS_*` environment variables.
+    pub hook_track_start: Option<String>,
+    pub hook_track_end: Option<String>,
+    pub hook_pause: Option<String>,
+    pub hook_resume: Option<String>,
+    pub hook_queue_empty: Option<String>,
+    pub hook_error: Option<String>,
+    /// Seconds a hook can run before it's killed.
+    pub hook_timeout: u64,
+    /// Seconds between syncs of the local library index; 0 turns it off.
+    pub library_sync: u64,
+    /// Most audio to keep cached, in megabytes; 0 turns the cache off.
+    pub audio_cache: u64,
+    /// How many upcoming songs to download ahead of time.
+    pub prefetch: usize,
+    /// Where `subs download` puts each song, filled in with `{artist}`,
+    /// `{album}`, `{title}`, `{track}`, `{disc}`, `{year}`, `{id}` and
+    /// `{suffix}`; numbers can be padded, as in `{track:02}`.
+    pub download_template: String,
+    /// Highest bitrate to stream at, in kbps, and format to transcode to;
+    /// the original files are streamed if neither is set.
+    pub max_bit_rate: Option<u32>,
+    pub format: Option<String>,
+    /// Whether the network is metered; NetworkManager is asked if unset.
+    pub metered: Option<bool>,
+    /// Used instead of `max_bit_rate` and `format` on metered networks.
+    pub metered_bit_rate: Option<u32>,
+    pub metered_format: Option<String>,
 }
 
 impl Conf {
@@ -25,12 +89,6 @@ impl Conf {
 
         debug!("Using config: {:?}", cfg);
 
-        if !cfg.socket.exists() {
-            return Err("Socket file doesn't exist; did you try running `subs \
-                        daemon start`?"
-                .into())
-        }
-
         macro_rules! chk {
             ($f:ident) => (if cfg.$f == Self::default().$f {
                 warn!("`SUBS_{}` is the default; do you want this?", stringify!($f).to_uppercase())
@@ -56,6 +114,43 @@ impl Default for Conf {
             url: "http://demo.subsonic.org".into(),
             username: "guest3".into(),
             password: "guest".into(),
+            mpd_address: None,
+            mpris: false,
+            http_address: None,
+            http_port: 6680,
+            http_token: None,
+            tcp_address: None,
+            host: None,
+            secret: None,
+            scrobble: false,
+            lastfm_url: "https://ws.audioscrobbler.com/2.0/".into(),
+            lastfm_api_key: None,
+            lastfm_api_secret: None,
+            lastfm_session_key: None,
+            listenbrainz_url: "https://api.listenbrainz.org".into(),
+            listenbrainz_token: None,
+            cover_size: 512,
+            cover_cache: 200,
+            notify: false,
+            notify_summary: "{title}".into(),
+            notify_body: "{artist}\n{album}".into(),
+            hook_track_start: None,
+            hook_track_end: None,
+            hook_pause: None,
+            hook_resume: None,
+            hook_queue_empty: None,
+            hook_error: None,
+            hook_timeout: 10,
+            library_sync: 0,
+            audio_cache: 0,
+            prefetch: 3,
+            download_template: "{artist}/{album}/{track:02} - {title}.{suffix}"
+                .into(),
+            max_bit_rate: None,
+            format: None,
+            metered: None,
+            metered_bit_rate: None,
+            metered_format: None,
         }
     }
 }
@@ -0,0 +1,196 @@
+//! Cover art, downloaded when each song starts and kept in the cache
+//! directory.
+//!
+//! The cache holds at most `Conf::cover_cache` images. When it's full, the
+//! one used longest ago is removed; after a restart, that's judged by when
+//! each image was downloaded.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use sunk::song::Song;
+use sunk::{Client, Media};
+
+use conf::Conf;
+use error::Error;
+use event::{Bus, Event};
+
+pub struct Covers {
+    dir: PathBuf,
+    /// Size to ask the server to scale images to, in pixels.
+    size: usize,
+    max: usize,
+    /// File names in the cache, most recently used last.
+    recent: Mutex<VecDeque<String>>,
+}
+
+impl Covers {
+    pub fn new(cfg: &Conf) -> Covers {
+        let dir = cfg.cache.join("covers");
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("unable to create {}: {}", dir.display(), e);
+        }
+
+        let mut files = fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| {
+                        let modified = e.metadata().ok()?.modified().ok()?;
+                        Some((modified, e.file_name().into_string().ok()?))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        files.sort();
+
+        let covers = Covers {
+            dir,
+            size: cfg.cover_size,
+            max: cfg.cover_cache,
+            recent: Mutex::new(files.into_iter().map(|(_, f)| f).collect()),
+        };
+        covers.prune(&mut covers.recent.lock().unwrap());
+        covers
+    }
+
+    fn name(&self, cover_id: &str) -> String {
+        let id = cover_id
+            .chars()
+            .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+            .collect::<String>();
+        format!("{}-{}.jpg", id, self.size)
+    }
+
+    /// Where the song's cover is, if it's been downloaded.
+    pub fn cached(&self, song: &Song) -> Option<PathBuf> {
+        self.cached_id(song.cover_id()?)
+    }
+
+    /// Where the cover with the given ID is, if it's been downloaded.
+    pub fn cached_id(&self, cover_id: &str) -> Option<PathBuf> {
+        let name = self.name(cover_id);
+        let mut recent = self.recent.lock().unwrap();
+        let i = recent.iter().position(|f| *f == name)?;
+        recent.remove(i);
+        recent.push_back(name.clone());
+        Some(self.dir.join(name))
+    }
+
+    /// Where the song's cover is, downloading it first if needed. Songs
+    /// without a cover give `None`.
+    pub fn fetch(
+        &self,
+        cli: &Client,
+        song: &Song,
+    ) -> ::std::result::Result<Option<PathBuf>, Error> {
+        if let Some(path) = self.cached(song) {
+            return Ok(Some(path))
+        }
+        let name = match song.cover_id() {
+            Some(id) => self.name(id),
+            None => return Ok(None),
+        };
+
+        debug!("downloading cover art for {}", song.id);
+        let image = song.cover_art(cli, Some(self.size))?;
+        let path = self.dir.join(&name);
+        File::create(&path)?.write_all(&image)?;
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(name);
+        self.prune(&mut recent);
+        Ok(Some(path))
+    }
+
+    fn prune(&self, recent: &mut VecDeque<String>) {
+        while recent.len() > self.max {
+            let old = recent.pop_front().unwrap();
+            if let Err(e) = fs::remove_file(self.dir.join(&old)) {
+                warn!("unable to remove cached cover {}: {}", old, e);
+            }
+        }
+    }
+}
+
+/// Downloads the cover of each song as it starts, in the background.
+pub fn spawn(covers: Arc<Covers>, client: Arc<Mutex<Client>>, bus: &Bus) {
+    let events = bus.subscribe();
+    thread::spawn(move || {
+        while let Ok(ev) = events.recv() {
+            if let Event::TrackStart(id) = ev {
+                let cli = &*client.lock().unwrap();
+                let res = Song::get(cli, id)
+                    .map_err(Error::from)
+                    .and_then(|song| covers.fetch(cli, &song));
+                if let Err(e) = res {
+                    warn!("unable to get cover art for {}: {}", id, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Covers;
+    use conf::Conf;
+
+    fn conf(name: &str) -> Conf {
+        let cache = env::temp_dir()
+            .join(format!("subs-test-covers-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&cache);
+        fs::create_dir_all(cache.join("covers")).unwrap();
+        Conf {
+            cache,
+            cover_size: 300,
+            cover_cache: 2,
+            ..Conf::default()
+        }
+    }
+
+    #[test]
+    fn names_are_safe_file_names() {
+        let cfg = conf("names");
+        let covers = Covers::new(&cfg);
+        assert_eq!(covers.name("al-12"), "al-12-300.jpg");
+        assert_eq!(covers.name("../../etc/passwd"), "etcpasswd-300.jpg");
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_most_recently_used() {
+        let cfg = conf("prune");
+        let dir = cfg.cache.join("covers");
+        for id in &["a", "b", "c"] {
+            File::create(dir.join(format!("{}-300.jpg", id))).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        // The oldest goes when the cache is loaded.
+        let covers = Covers::new(&cfg);
+        assert!(!dir.join("a-300.jpg").exists());
+        assert_eq!(covers.cached_id("a"), None);
+
+        // Using one makes it the last to go.
+        assert_eq!(covers.cached_id("b"), Some(dir.join("b-300.jpg")));
+        {
+            let mut recent = covers.recent.lock().unwrap();
+            recent.push_back("d-300.jpg".into());
+            covers.prune(&mut recent);
+        }
+        assert!(!dir.join("c-300.jpg").exists());
+        assert!(dir.join("b-300.jpg").exists());
+
+        fs::remove_dir_all(&cfg.cache).unwrap();
+    }
+}
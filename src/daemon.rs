@@ -1,18 +1,40 @@
 use crossbeam_channel::*;
 use serde_json;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use sunk::{Client, Genre};
+use sunk::{Album, Annotatable, ApiError, Artist, Client, Genre, Playlist};
+use sunk::playlist;
 use sunk::search::{self, SearchPage};
 use sunk::song::Song;
 use unix_socket::{UnixListener, UnixStream};
 
+use api::Api;
 use error::{Error, Result};
-use player::Player;
+use cache::{self, AudioCache, Usage};
+use conf::Conf;
+use cover::{self, Covers};
+use download::Download;
+use event::{Bus, Event};
+use hooks;
+use http;
+use info::{AlbumInfo, ArtistInfo, SongInfo};
+use library::{self, normalise, Library};
+use lyrics;
+use mpd;
+use mpris;
+use notify;
+use player::{Player, Status, Tags, Track};
+use playlist_file::{self, Entry};
+use quality::{Preferences, Quality};
+use queue::Source;
+use radio::{self, Station};
+use remote;
+use scrobble;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum Command {
     Play,
     Pause,
@@ -27,8 +49,21 @@ pub enum Command {
     AddNext(u64),
     AddNextSearch(String),
     Search(String, bool, bool, bool, usize),
+    SearchSongs(String, usize),
+    Seek(u64),
+    SetVolume(u8),
     StatusReq,
-    Status(String),
+    Status(Status),
+    QueueReq,
+    Queue(Vec<Source>),
+    /// Adds a local file or URL to the queue, to play next if the flag is
+    /// set.
+    AddSource(Source, bool),
+    /// Plays a file, URL or radio station straight away, keeping the rest
+    /// of the queue.
+    PlayNow(Source),
+    /// Plays the entry at the given queue position, counting from 0.
+    PlayAt(usize),
     Random(usize),
     RandomWith {
         size: usize,
@@ -36,6 +71,217 @@ pub enum Command {
         from: usize,
         to: usize,
     },
+    /// Runs each command in order without interleaving other clients. If the
+    /// flag is set, the batch stops at the first command that fails.
+    Batch(Vec<Command>, bool),
+    /// Stars the first match for the query, or the current song (or its
+    /// album or artist) if there's no query.
+    Star(Kind, Option<String>),
+    Unstar(Kind, Option<String>),
+    Rate(Kind, Option<String>, u8),
+    /// Lists everything starred, adding the songs and albums to the queue if
+    /// the flag is set.
+    Starred(bool),
+    /// Replaces the queue with a playlist's songs.
+    Load(String),
+    Playlists,
+    PlaylistShow(String),
+    /// Creates a playlist, starting with the songs in the queue if the flag
+    /// is set.
+    PlaylistCreate(String, bool),
+    /// Adds the first match for the query, or the current song if there's
+    /// no query.
+    PlaylistAdd(String, Option<String>),
+    /// Removes the song at the given position, counting from 1.
+    PlaylistRemove(String, usize),
+    PlaylistRename(String, String),
+    PlaylistDelete(String),
+    /// Saves the queue as the named playlist, made public if the first flag
+    /// is set and with an optional comment. An existing playlist with the
+    /// same name is only replaced if the last flag is set.
+    Save(String, bool, Option<String>, bool),
+    /// Lists the queue as playlist file entries, located by stream URL if
+    /// the flag is set or by song ID otherwise.
+    Export(bool),
+    /// Adds playlist file entries to the queue, matching them to songs by ID
+    /// or by searching for them.
+    Import(Vec<Entry>),
+    /// Shows details of the song, album or artist with the given ID, the
+    /// first match for a query, or the current song's if there's neither.
+    Info(Kind, Option<String>),
+    /// Gets the lyrics for the current song.
+    Lyrics,
+    /// Finds the current song's cover in the cache, downloading it if
+    /// needed. The image itself is included if the flag is set, for clients
+    /// that can't read the daemon's cache.
+    Cover(bool),
+    /// Lists every artist in the library.
+    Artists,
+    ArtistAlbums(u64),
+    AlbumSongs(u64),
+    /// Keeps the connection open, sending each player event as a line of
+    /// JSON instead of a reply.
+    Subscribe,
+    /// Starts the server scanning the library for changes.
+    Scan,
+    ScanStatus,
+    CacheStatus,
+    /// Empties the audio cache, pinned songs included.
+    CacheClear,
+    /// Keeps the first album matching the query in the audio cache.
+    CachePin(String),
+    /// Lists the songs to download for the first match for a query, or for
+    /// the current song (or its album or artist) if there's no query.
+    Downloads(Kind, Option<String>),
+    PlaylistDownloads(String),
+    /// Sets the quality to stream at from the next song on, until the
+    /// daemon stops, or goes back to the configured quality if `None`.
+    SetQuality(Option<Quality>),
+    QualityReq,
+    /// Lists the server's internet radio stations.
+    Stations,
+    /// Plays the first station matching the name straight away.
+    RadioPlay(String),
+    /// Adds a station with the given name, stream URL and home page.
+    RadioCreate(String, String, Option<String>),
+    RadioDelete(String),
+}
+
+/// The kind of library item a command applies to.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Song,
+    Album,
+    Artist,
+}
+
+/// Clients other than `subs` can send any rating, so they're checked here
+/// rather than on the command line.
+fn check_rating(rating: u8) -> Result {
+    if rating < 1 || rating > 5 {
+        return Err("Ratings must be between 1 and 5".into())
+    }
+    Ok(())
+}
+
+/// Whether the server said there's nothing with the ID asked for.
+fn missing(err: &Error) -> bool {
+    match *err {
+        Error::Subsonic(::sunk::Error::Api(ApiError::NotFound(_))) => true,
+        _ => false,
+    }
+}
+
+fn find_playlist(
+    cli: &Client,
+    name: &str,
+) -> ::std::result::Result<Playlist, Error> {
+    playlist::get_playlists(cli, None)?
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| {
+            Error::Response(format!("No playlist named \"{}\".", name))
+        })
+}
+
+/// Saves the songs as a playlist, replacing one with the same name only if
+/// `force` is set.
+fn save_playlist(
+    cli: &Client,
+    name: &str,
+    songs: &[u64],
+    public: bool,
+    comment: Option<&str>,
+    force: bool,
+) -> Result {
+    let existing = find_playlist(cli, name).ok();
+    if existing.is_some() && !force {
+        return Err(Error::Response(format!(
+            "A playlist named \"{}\" already exists; use --force to \
+             replace it.",
+            name
+        )))
+    }
+
+    let pl = match existing {
+        Some(pl) => {
+            // Clear out the old songs while adding the new ones.
+            let old = (0..pl.song_count).collect::<Vec<_>>();
+            playlist::update_playlist(
+                cli,
+                pl.id,
+                None,
+                None,
+                None,
+                songs,
+                &old,
+            )?;
+            pl
+        }
+        None => {
+            playlist::create_playlist(cli, name.to_string(), songs)?;
+            find_playlist(cli, name)?
+        }
+    };
+
+    playlist::update_playlist(
+        cli,
+        pl.id,
+        None,
+        comment,
+        Some(public),
+        &[],
+        &[],
+    )?;
+    Ok(())
+}
+
+/// A library item that can be starred and rated.
+enum Item {
+    Song(Song),
+    Album(Album),
+    Artist(Artist),
+}
+
+impl Item {
+    fn star(&self, cli: &Client) -> ::std::result::Result<(), Error> {
+        match *self {
+            Item::Song(ref s) => s.star(cli)?,
+            Item::Album(ref a) => a.star(cli)?,
+            Item::Artist(ref a) => a.star(cli)?,
+        }
+        Ok(())
+    }
+
+    fn unstar(&self, cli: &Client) -> ::std::result::Result<(), Error> {
+        match *self {
+            Item::Song(ref s) => s.unstar(cli)?,
+            Item::Album(ref a) => a.unstar(cli)?,
+            Item::Artist(ref a) => a.unstar(cli)?,
+        }
+        Ok(())
+    }
+
+    fn rate(
+        &self,
+        cli: &Client,
+        rating: u8,
+    ) -> ::std::result::Result<(), Error> {
+        match *self {
+            Item::Song(ref s) => s.set_rating(cli, rating)?,
+            Item::Album(ref a) => a.set_rating(cli, rating)?,
+            Item::Artist(ref a) => a.set_rating(cli, rating)?,
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Item::Song(ref s) => s.to_string(),
+            Item::Album(ref a) => a.to_string(),
+            Item::Artist(ref a) => a.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -45,7 +291,35 @@ pub enum Reply {
         albums: Vec<String>,
         songs: Vec<String>,
     },
+    Batch(Vec<Reply>),
+    Status(Status),
+    Songs(Vec<Track>),
+    /// Names of playlists and how many songs each has.
+    Playlists(Vec<(String, u64)>),
+    /// IDs and names of artists or albums.
+    Listing(Vec<(u64, String)>),
+    Entries(Vec<Entry>),
+    Imported {
+        added: usize,
+        unmatched: Vec<String>,
+    },
+    SongInfo(SongInfo),
+    AlbumInfo(AlbumInfo),
+    ArtistInfo(ArtistInfo),
+    Lyrics(lyrics::Lyrics),
+    /// Where the cover is cached, and the image if it was asked for.
+    Cover(String, Option<Vec<u8>>),
+    /// How full the audio cache is.
+    Cache(Usage),
+    Downloads(Vec<Download>),
+    /// The quality songs are streamed at, whether it was set with `subs
+    /// quality`, and whether the network is metered.
+    Quality(Quality, bool, bool),
+    Stations(Vec<Station>),
+    /// Whether the server is scanning, and how many items it has scanned.
+    Scan(bool, u64),
     Other(String),
+    Error(String),
     None,
 }
 
@@ -53,6 +327,13 @@ pub struct Daemon {
     client: Arc<Mutex<Client>>,
     socket: UnixListener,
     cache_file: PathBuf,
+    bus: Bus,
+    lyrics: lyrics::Fetcher,
+    covers: Arc<Covers>,
+    library: Arc<Library>,
+    cache: Arc<AudioCache>,
+    prefs: Arc<Preferences>,
+    api: Api,
 
     player_send: Sender<Command>,
     player_recv: Receiver<Command>,
@@ -66,15 +347,77 @@ impl Daemon {
 
         let cache_file = cfg.cache.clone();
 
+        let bus = Bus::new();
+        let lyrics = lyrics::Fetcher::new(&cfg);
+
+        let covers = Arc::new(Covers::new(&cfg));
+        cover::spawn(covers.clone(), client.clone(), &bus);
+        notify::spawn(&cfg, client.clone(), covers.clone(), &bus);
+        hooks::spawn(&cfg, client.clone(), covers.clone(), &bus);
+
+        let library = Arc::new(Library::load(&cfg));
+        if cfg.library_sync > 0 {
+            library::spawn(library.clone(), cfg.library_sync);
+        }
+
+        let prefs = Arc::new(Preferences::new(&cfg));
+        let cache = Arc::new(AudioCache::new(&cfg, prefs.clone()));
+        cache::spawn(cache.clone());
+        let api = Api::new(&cfg);
+
         let (player_send, daemon_recv) = unbounded();
         let (daemon_send, player_recv) = unbounded();
         let player_cli = client.clone();
+        let player_bus = bus.clone();
+        let player_covers = covers.clone();
+        let player_library = library.clone();
+        let player_cache = cache.clone();
+        let player_prefs = prefs.clone();
         thread::spawn(|| {
-            let mut player = Player::new(daemon_recv, daemon_send, player_cli);
+            let mut player = Player::new(
+                daemon_recv,
+                daemon_send,
+                player_cli,
+                player_bus,
+                player_covers,
+                player_library,
+                player_cache,
+                player_prefs,
+            );
 
             player.run();
         });
 
+        scrobble::spawn(&cfg, client.clone(), library.clone(), &bus);
+
+        if let Some(ref addr) = cfg.mpd_address {
+            mpd::spawn(addr, &cfg.socket, bus.clone())
+                .expect("unable to start MPD server");
+        }
+
+        if cfg.mpris {
+            mpris::spawn(&cfg.socket, bus.clone());
+        }
+
+        if let Some(ref addr) = cfg.http_address {
+            if cfg.http_token.is_none() {
+                warn!("HTTP API has no token; anyone on this machine can \
+                       control the daemon");
+            }
+            http::spawn(
+                addr,
+                cfg.http_port,
+                cfg.http_token.clone(),
+                &cfg.socket,
+                bus.clone(),
+            ).expect("unable to start HTTP server");
+        }
+
+        if let Some(ref addr) = cfg.tcp_address {
+            remote::spawn(addr, cfg.secret.clone(), &cfg.socket)
+                .expect("unable to listen for remote clients");
+        }
+
         let socket = UnixListener::bind(cfg.socket.clone())
             .expect("unable to bind to socket");
 
@@ -96,6 +439,13 @@ impl Daemon {
             client,
             socket,
             cache_file,
+            bus,
+            lyrics,
+            covers,
+            library,
+            cache,
+            prefs,
+            api,
             player_send,
             player_recv,
         }
@@ -109,8 +459,13 @@ impl Daemon {
                     s.read_to_string(&mut buf)?;
                     let cmd = serde_json::from_str::<Command>(&buf)?;
 
+                    if let Command::Subscribe = cmd {
+                        self.stream_events(s);
+                        continue
+                    }
+
                     debug!("running {:?}", cmd);
-                    match self.run_cmd(cmd) {
+                    let reply = match self.run_cmd(cmd) {
                         Err(Error::ExplicitStop) => {
                             info!("stop signal received.");
                             info!("stopping server.");
@@ -119,13 +474,19 @@ impl Daemon {
                         }
                         Err(e) => {
                             error!("Encountered error: {}", e);
-                            // return Err(e)
-                        }
-                        Ok(res) => {
-                            s.write_all(res.as_bytes())?;
-                            debug!("sent reply");
+                            self.bus.publish(Event::Error(e.to_string()));
+                            Reply::Error(e.to_string())
                         }
+                        Ok(res) => res,
                     };
+
+                    // Fire-and-forget clients have already hung up, so a
+                    // failed write isn't worth stopping the daemon over.
+                    match s.write_all(serde_json::to_string(&reply)?.as_bytes())
+                    {
+                        Ok(_) => debug!("sent reply"),
+                        Err(e) => debug!("unable to send reply: {}", e),
+                    }
                 }
                 Err(e) => {
                     error!("encountered error: {}", e);
@@ -138,42 +499,42 @@ impl Daemon {
         Ok(())
     }
 
-    fn run_cmd(&self, cmd: Command) -> ::std::result::Result<String, Error> {
+    fn run_cmd(&self, cmd: Command) -> ::std::result::Result<Reply, Error> {
         use self::Command::*;
         match cmd {
             Stop => return Err(Error::ExplicitStop),
-            Play | Pause | Toggle | Next | Prev | Clear => {
+            Play | Pause | Toggle | Next | Prev | Clear | Add(_)
+            | AddMany(_) | AddNext(_) | PlayNow(_) | Seek(_)
+            | SetVolume(_) => {
                 self.player_send.send(cmd).unwrap()
             }
+            PlayAt(pos) => {
+                if pos >= self.queue().len() {
+                    return Err(Error::Response(format!(
+                        "There's no song at position {}.",
+                        pos + 1
+                    )))
+                }
+                self.player_send.send(PlayAt(pos)).unwrap()
+            }
+            AddSource(source, next) => {
+                let name = source.name();
+                self.player_send.send(AddSource(source, next)).unwrap();
+                return Ok(Reply::Other(format!("Adding {}.", name)))
+            }
             AddSearch(q) => {
-                let n = search::NONE;
-                let s = SearchPage::new().with_size(1);
-                let sr = self.client.lock().unwrap().search(&q, n, n, s)?;
-                return Ok(serde_json::to_string(&Reply::Other(if let Some(
-                    song,
-                ) =
-                    sr.songs.get(0)
-                {
-                    self.player_send.send(Add(song.id)).unwrap();
-                    format!("Adding {}.", song.title).into()
-                } else {
-                    format!("Nothing found for \"{}\".", q)
-                }))?)
+                let (id, title) = self.first_song(&q)?.ok_or_else(|| {
+                    Error::Response(format!("Nothing found for \"{}\".", q))
+                })?;
+                self.player_send.send(Add(id)).unwrap();
+                return Ok(Reply::Other(format!("Adding {}.", title)))
             }
             AddNextSearch(q) => {
-                let n = search::NONE;
-                let s = SearchPage::new().with_size(1);
-                let sr = self.client.lock().unwrap().search(&q, n, n, s)?;
-                return Ok(serde_json::to_string(&Reply::Other(if let Some(
-                    song,
-                ) =
-                    sr.songs.get(0)
-                {
-                    self.player_send.send(AddNext(song.id)).unwrap();
-                    format!("Adding {}.", song.title).into()
-                } else {
-                    format!("Nothing found for \"{}\".", q)
-                }))?)
+                let (id, title) = self.first_song(&q)?.ok_or_else(|| {
+                    Error::Response(format!("Nothing found for \"{}\".", q))
+                })?;
+                self.player_send.send(AddNext(id)).unwrap();
+                return Ok(Reply::Other(format!("Adding {}.", title)))
             }
             Random(n) => {
                 let songs = {
@@ -188,12 +549,69 @@ impl Daemon {
                 self.player_send.send(Command::StatusReq).unwrap();
                 let st = self.player_recv.recv().unwrap();
                 if let Command::Status(s) = st {
-                    return Ok(serde_json::to_string(&Reply::Other(s))?)
+                    return Ok(Reply::Status(s))
                 } else {
                     unreachable!()
                 }
             }
+            QueueReq => {
+                let sources = self.queue();
+                let cli = &*self.client.lock().unwrap();
+                let mut songs = Vec::with_capacity(sources.len());
+                for source in sources {
+                    let id = match source {
+                        Source::Song(id) => id,
+                        _ => {
+                            let tags = Tags::default();
+                            songs.push(Track::local(&source, &tags, 0));
+                            continue
+                        }
+                    };
+                    songs.push(match self.library.song(id) {
+                        Some(entry) => self.library.track(&entry),
+                        None => Track::new(cli, &Song::get(cli, id)?),
+                    });
+                }
+                return Ok(Reply::Songs(songs))
+            }
+            SearchSongs(q, n) => {
+                if let Some(lib) = self.index() {
+                    return Ok(Reply::Songs(
+                        lib.search_songs(&q, n)
+                            .iter()
+                            .map(|s| lib.track(s))
+                            .collect(),
+                    ))
+                }
+                let none = search::NONE;
+                let page = SearchPage::new().with_size(n);
+                let cli = &*self.client.lock().unwrap();
+                let sr = cli.search(&q, none, none, page)?;
+                return Ok(Reply::Songs(
+                    sr.songs.iter().map(|s| Track::new(cli, s)).collect(),
+                ))
+            }
             Search(q, r, a, s, n) => {
+                if let Some(lib) = self.index() {
+                    return Ok(Reply::Search {
+                        albums: if a {
+                            strings(&lib.search_albums(&q, n))
+                        } else {
+                            Vec::new()
+                        },
+                        artists: if r {
+                            lib.search_artists(&q, n)
+                        } else {
+                            Vec::new()
+                        },
+                        songs: if s {
+                            strings(&lib.search_songs(&q, n))
+                        } else {
+                            Vec::new()
+                        },
+                    })
+                }
+
                 macro_rules! chk {
                     ($t:ident) => (if $t {
                         SearchPage::new().with_size(n)
@@ -207,20 +625,657 @@ impl Daemon {
                 let al = chk!(a);
                 let sn = chk!(s);
                 let sr = self.client.lock().unwrap().search(&q, ar, al, sn)?;
-                debug!("serializing reply");
-                let res = Reply::Search {
+                return Ok(Reply::Search {
                     albums: sr.albums.iter().map(|a| a.to_string()).collect(),
                     artists: sr.artists.iter().map(|a| a.to_string()).collect(),
                     songs: sr.songs.iter().map(|s| s.to_string()).collect(),
+                })
+            }
+            Star(kind, query) => {
+                let item = self.find(kind, query)?;
+                item.star(&*self.client.lock().unwrap())?;
+                return Ok(Reply::Other(format!("Starred {}.", item.name())))
+            }
+            Unstar(kind, query) => {
+                let item = self.find(kind, query)?;
+                item.unstar(&*self.client.lock().unwrap())?;
+                return Ok(Reply::Other(format!("Unstarred {}.", item.name())))
+            }
+            Rate(kind, query, rating) => {
+                check_rating(rating)?;
+                let item = self.find(kind, query)?;
+                item.rate(&*self.client.lock().unwrap(), rating)?;
+                return Ok(Reply::Other(format!(
+                    "Rated {} {}/5.",
+                    item.name(),
+                    rating
+                )))
+            }
+            Starred(add) => {
+                let cli = &*self.client.lock().unwrap();
+                let starred = cli.starred(None)?;
+                if add {
+                    let mut songs =
+                        starred.songs.iter().map(|s| s.id).collect::<Vec<_>>();
+                    for album in &starred.albums {
+                        songs.extend(album.songs(cli)?.iter().map(|s| s.id));
+                    }
+                    self.player_send.send(Command::AddMany(songs)).unwrap();
+                }
+                return Ok(Reply::Search {
+                    albums: strings(&starred.albums),
+                    artists: strings(&starred.artists),
+                    songs: strings(&starred.songs),
+                })
+            }
+            Load(name) => {
+                let pl = self.playlist(&name)?;
+                let songs = pl.songs(&*self.client.lock().unwrap())?;
+                let ids = songs.iter().map(|s| s.id).collect();
+                self.player_send.send(Command::Clear).unwrap();
+                self.player_send.send(Command::AddMany(ids)).unwrap();
+                return Ok(Reply::Other(format!(
+                    "Loaded {} songs from {}.",
+                    songs.len(),
+                    pl.name
+                )))
+            }
+            Playlists => {
+                let cli = &*self.client.lock().unwrap();
+                return Ok(Reply::Playlists(
+                    playlist::get_playlists(cli, None)?
+                        .into_iter()
+                        .map(|p| (p.name, p.song_count))
+                        .collect(),
+                ))
+            }
+            PlaylistShow(name) => {
+                let pl = self.playlist(&name)?;
+                let cli = &*self.client.lock().unwrap();
+                return Ok(Reply::Songs(
+                    pl.songs(cli)?.iter().map(|s| Track::new(cli, s)).collect(),
+                ))
+            }
+            PlaylistCreate(name, from_queue) => {
+                if self.playlist(&name).is_ok() {
+                    return Err(Error::Response(format!(
+                        "A playlist named \"{}\" already exists.",
+                        name
+                    )))
+                }
+                let songs = if from_queue {
+                    self.queue_songs()
+                } else {
+                    Vec::new()
+                };
+                let cli = &*self.client.lock().unwrap();
+                playlist::create_playlist(cli, name.clone(), &songs)?;
+                return Ok(Reply::Other(format!(
+                    "Created {} with {} songs.",
+                    name,
+                    songs.len()
+                )))
+            }
+            PlaylistAdd(name, query) => {
+                let pl = self.playlist(&name)?;
+                let song = match self.find(Kind::Song, query)? {
+                    Item::Song(s) => s,
+                    _ => unreachable!(),
+                };
+                let cli = &*self.client.lock().unwrap();
+                playlist::update_playlist(
+                    cli,
+                    pl.id,
+                    None,
+                    None,
+                    None,
+                    &[song.id],
+                    &[],
+                )?;
+                return Ok(Reply::Other(format!(
+                    "Added {} to {}.",
+                    song.title, pl.name
+                )))
+            }
+            PlaylistRemove(name, index) => {
+                let pl = self.playlist(&name)?;
+                if index < 1 || index as u64 > pl.song_count {
+                    return Err(Error::Response(format!(
+                        "{} has no song {}.",
+                        pl.name, index
+                    )))
+                }
+                let cli = &*self.client.lock().unwrap();
+                playlist::update_playlist(
+                    cli,
+                    pl.id,
+                    None,
+                    None,
+                    None,
+                    &[],
+                    &[index as u64 - 1],
+                )?;
+                return Ok(Reply::Other(format!(
+                    "Removed song {} from {}.",
+                    index, pl.name
+                )))
+            }
+            PlaylistRename(name, new_name) => {
+                let pl = self.playlist(&name)?;
+                let cli = &*self.client.lock().unwrap();
+                playlist::update_playlist(
+                    cli,
+                    pl.id,
+                    Some(new_name.as_str()),
+                    None,
+                    None,
+                    &[],
+                    &[],
+                )?;
+                return Ok(Reply::Other(format!(
+                    "Renamed {} to {}.",
+                    pl.name, new_name
+                )))
+            }
+            PlaylistDelete(name) => {
+                let pl = self.playlist(&name)?;
+                let cli = &*self.client.lock().unwrap();
+                playlist::delete_playlist(cli, pl.id)?;
+                return Ok(Reply::Other(format!("Deleted {}.", pl.name)))
+            }
+            Save(name, public, comment, force) => {
+                let songs = self.queue_songs();
+                if songs.is_empty() {
+                    return Err("The queue is empty".into())
+                }
+                let cli = &*self.client.lock().unwrap();
+                let comment = comment.as_ref().map(|c| c.as_str());
+                save_playlist(cli, &name, &songs, public, comment, force)?;
+                return Ok(Reply::Other(format!(
+                    "Saved {} songs to {}.",
+                    songs.len(),
+                    name
+                )))
+            }
+            Export(urls) => {
+                let sources = self.queue();
+                let cli = &*self.client.lock().unwrap();
+                let mut entries = Vec::with_capacity(sources.len());
+                for source in sources {
+                    let id = match source {
+                        Source::Song(id) => id,
+                        Source::File(location) | Source::Url(location) => {
+                            entries.push(Entry {
+                                location: Some(location),
+                                title: None,
+                                artist: None,
+                                album: None,
+                                duration: None,
+                            });
+                            continue
+                        }
+                        Source::Radio { name, url } => {
+                            entries.push(Entry {
+                                location: Some(url),
+                                title: Some(name),
+                                artist: None,
+                                album: None,
+                                duration: None,
+                            });
+                            continue
+                        }
+                    };
+                    let song = Song::get(cli, id)?;
+                    let location = if urls {
+                        song.stream_url(cli)?
+                    } else {
+                        format!("{}{}", playlist_file::ID_SCHEME, id)
+                    };
+                    entries.push(Entry {
+                        location: Some(location),
+                        title: Some(song.title.clone()),
+                        artist: song.artist.clone(),
+                        album: song.album.clone(),
+                        duration: song.duration.map(|d| d as u64),
+                    });
+                }
+                return Ok(Reply::Entries(entries))
+            }
+            Import(entries) => {
+                let mut ids = Vec::with_capacity(entries.len());
+                let mut unmatched = Vec::new();
+                for entry in &entries {
+                    match self.resolve(entry)? {
+                        Some(id) => ids.push(id),
+                        None => unmatched.push(entry.describe()),
+                    }
+                }
+                let added = ids.len();
+                self.player_send.send(Command::AddMany(ids)).unwrap();
+                return Ok(Reply::Imported { added, unmatched })
+            }
+            Info(kind, query) => {
+                // Titles can be numbers too, like "1999", so anything that
+                // isn't an ID is searched for instead.
+                let id = query.as_ref().and_then(|q| q.trim().parse().ok());
+                let found = match id.map(|id| self.get(kind, id)) {
+                    Some(Err(ref e)) if missing(e) => None,
+                    Some(res) => Some(res?),
+                    None => None,
+                };
+                let item = match found {
+                    Some(item) => item,
+                    None => self.find(kind, query)?,
+                };
+                let cli = &*self.client.lock().unwrap();
+                return Ok(match item {
+                    Item::Song(s) => Reply::SongInfo(SongInfo::new(&s)),
+                    Item::Album(a) => {
+                        Reply::AlbumInfo(AlbumInfo::new(cli, &a)?)
+                    }
+                    Item::Artist(a) => {
+                        Reply::ArtistInfo(ArtistInfo::new(cli, &a)?)
+                    }
+                })
+            }
+            Lyrics => {
+                let song = self.current_song()?;
+                let track = Track::new(&*self.client.lock().unwrap(), &song);
+                return Ok(Reply::Lyrics(self.lyrics.get(&track)?))
+            }
+            Cover(with_image) => {
+                let song = self.current_song()?;
+                let cli = &*self.client.lock().unwrap();
+                let path = match self.covers.fetch(cli, &song)? {
+                    Some(p) => p,
+                    None => {
+                        return Err(Error::Response(format!(
+                            "{} has no cover art.",
+                            song.title
+                        )))
+                    }
+                };
+                let image = if with_image {
+                    let mut buf = Vec::new();
+                    ::std::fs::File::open(&path)?.read_to_end(&mut buf)?;
+                    Some(buf)
+                } else {
+                    None
+                };
+                return Ok(Reply::Cover(
+                    path.to_string_lossy().into_owned(),
+                    image,
+                ))
+            }
+            Artists => {
+                if let Some(lib) = self.index() {
+                    return Ok(Reply::Listing(lib.artists()))
+                }
+                let cli = &*self.client.lock().unwrap();
+                return Ok(Reply::Listing(
+                    Artist::list(cli, None)?
+                        .into_iter()
+                        .map(|a| (a.id, a.name))
+                        .collect(),
+                ))
+            }
+            ArtistAlbums(id) => {
+                if let Some(albums) = self.index().and_then(|l| l.albums(id)) {
+                    return Ok(Reply::Listing(albums))
+                }
+                let cli = &*self.client.lock().unwrap();
+                return Ok(Reply::Listing(
+                    Artist::get(cli, id)?
+                        .albums(cli)?
+                        .iter()
+                        .map(|a| (a.id, a.to_string()))
+                        .collect(),
+                ))
+            }
+            AlbumSongs(id) => {
+                if let Some(lib) = self.index() {
+                    if let Some(songs) = lib.album_songs(id) {
+                        return Ok(Reply::Songs(
+                            songs.iter().map(|s| lib.track(s)).collect(),
+                        ))
+                    }
+                }
+                let cli = &*self.client.lock().unwrap();
+                let songs = Album::get(cli, id)?.songs(cli)?;
+                return Ok(Reply::Songs(
+                    songs.iter().map(|s| Track::new(cli, s)).collect(),
+                ))
+            }
+            Scan => {
+                let cli = &*self.client.lock().unwrap();
+                cli.start_scan().map_err(admin)?;
+                let (scanning, count) = cli.scan_status().map_err(admin)?;
+                return Ok(Reply::Scan(scanning, count))
+            }
+            ScanStatus => {
+                let cli = &*self.client.lock().unwrap();
+                let (scanning, count) = cli.scan_status().map_err(admin)?;
+                return Ok(Reply::Scan(scanning, count))
+            }
+            CacheStatus => return Ok(Reply::Cache(self.cache.usage())),
+            CacheClear => {
+                let count = self.cache.clear()?;
+                return Ok(Reply::Other(format!(
+                    "Removed {} songs from the cache.",
+                    count
+                )))
+            }
+            CachePin(q) => {
+                let album = match self.find(Kind::Album, Some(q))? {
+                    Item::Album(a) => a,
+                    _ => unreachable!(),
                 };
-                return Ok(serde_json::to_string(&res)?)
+                let ids = {
+                    let cli = &*self.client.lock().unwrap();
+                    album.songs(cli)?.iter().map(|s| s.id).collect::<Vec<_>>()
+                };
+                self.cache.pin(&ids)?;
+                return Ok(Reply::Other(format!(
+                    "Pinned {} songs from {}.",
+                    ids.len(),
+                    album.name
+                )))
+            }
+            Downloads(kind, query) => {
+                let item = self.find(kind, query)?;
+                let cli = &*self.client.lock().unwrap();
+                let songs = match item {
+                    Item::Song(s) => vec![s],
+                    Item::Album(a) => a.songs(cli)?,
+                    Item::Artist(a) => {
+                        let mut songs = Vec::new();
+                        for album in a.albums(cli)? {
+                            songs.extend(album.songs(cli)?);
+                        }
+                        songs
+                    }
+                };
+                return Ok(Reply::Downloads(
+                    songs.iter().map(Download::new).collect(),
+                ))
+            }
+            PlaylistDownloads(name) => {
+                let pl = self.playlist(&name)?;
+                let cli = &*self.client.lock().unwrap();
+                return Ok(Reply::Downloads(
+                    pl.songs(cli)?.iter().map(Download::new).collect(),
+                ))
+            }
+            SetQuality(quality) => self.prefs.set(quality),
+            QualityReq => {
+                let (quality, session) = self.prefs.current();
+                return Ok(Reply::Quality(
+                    quality,
+                    session,
+                    self.prefs.is_metered(),
+                ))
+            }
+            Stations => return Ok(Reply::Stations(radio::list(&self.api)?)),
+            RadioPlay(name) => {
+                let station = radio::find(&self.api, &name)?;
+                self.player_send
+                    .send(Command::PlayNow(Source::Radio {
+                        name: station.name.clone(),
+                        url: station.stream_url,
+                    }))
+                    .unwrap();
+                return Ok(Reply::Other(format!("Playing {}.", station.name)))
+            }
+            RadioCreate(name, url, home_page) => {
+                radio::create(
+                    &self.api,
+                    &name,
+                    &url,
+                    home_page.as_ref().map(|s| s.as_str()),
+                )?;
+                return Ok(Reply::Other(format!("Added {}.", name)))
+            }
+            RadioDelete(name) => {
+                let station = radio::find_exact(&self.api, &name)?;
+                radio::delete(&self.api, &station.id)?;
+                return Ok(Reply::Other(format!("Deleted {}.", station.name)))
+            }
+            Batch(cmds, stop_on_error) => {
+                let mut replies = Vec::with_capacity(cmds.len());
+                for cmd in cmds {
+                    debug!("running {:?} in batch", cmd);
+                    match self.run_cmd(cmd) {
+                        Err(Error::ExplicitStop) => {
+                            return Err(Error::ExplicitStop)
+                        }
+                        Err(e) => {
+                            replies.push(Reply::Error(e.to_string()));
+                            if stop_on_error {
+                                debug!("stopping batch at first error");
+                                break
+                            }
+                        }
+                        Ok(reply) => replies.push(reply),
+                    }
+                }
+                return Ok(Reply::Batch(replies))
             }
             _ => (),
         }
-        Ok("".into())
+        Ok(Reply::None)
+    }
+}
+
+impl Daemon {
+    /// Sends events to a subscribed client until it hangs up.
+    fn stream_events(&self, mut stream: UnixStream) {
+        debug!("client subscribed to events");
+        let events = self.bus.subscribe();
+        thread::spawn(move || {
+            while let Ok(ev) = events.recv() {
+                let line = match serde_json::to_string(&ev) {
+                    Ok(l) => l + "\n",
+                    Err(e) => return error!("unable to serialise event: {}", e),
+                };
+                if stream.write_all(line.as_bytes()).is_err() {
+                    debug!("subscribed client hung up");
+                    break
+                }
+            }
+        });
+    }
+
+    /// Everything in the player's queue.
+    fn queue(&self) -> Vec<Source> {
+        self.player_send.send(Command::QueueReq).unwrap();
+        match self.player_recv.recv().unwrap() {
+            Command::Queue(sources) => sources,
+            _ => unreachable!(),
+        }
+    }
+
+    /// IDs of the songs in the player's queue, leaving out files and URLs.
+    fn queue_songs(&self) -> Vec<u64> {
+        self.queue().iter().filter_map(|s| s.song()).collect()
+    }
+
+    /// Finds one of the user's playlists by name.
+    fn playlist(&self, name: &str) -> ::std::result::Result<Playlist, Error> {
+        find_playlist(&*self.client.lock().unwrap(), name)
+    }
+
+    /// Looks up the song the player is on.
+    fn current_song(&self) -> ::std::result::Result<Song, Error> {
+        self.player_send.send(Command::StatusReq).unwrap();
+        let track = match self.player_recv.recv().unwrap() {
+            Command::Status(st) => st.track,
+            _ => unreachable!(),
+        };
+        match track {
+            Some(ref t) if t.location.is_some() => {
+                Err("The current song isn't from the server".into())
+            }
+            Some(t) => Ok(Song::get(&*self.client.lock().unwrap(), t.id)?),
+            None => Err("Nothing is playing".into()),
+        }
+    }
+
+    /// Looks up an item by its ID.
+    fn get(&self, kind: Kind, id: u64) -> ::std::result::Result<Item, Error> {
+        let cli = &*self.client.lock().unwrap();
+        Ok(match kind {
+            Kind::Song => Item::Song(Song::get(cli, id)?),
+            Kind::Album => Item::Album(Album::get(cli, id)?),
+            Kind::Artist => Item::Artist(Artist::get(cli, id)?),
+        })
+    }
+
+    /// The library index, if it's been synced.
+    fn index(&self) -> Option<&Library> {
+        if self.library.is_empty() {
+            None
+        } else {
+            Some(&self.library)
+        }
+    }
+
+    /// The ID and title of the first song matching a query.
+    fn first_song(
+        &self,
+        q: &str,
+    ) -> ::std::result::Result<Option<(u64, String)>, Error> {
+        if let Some(lib) = self.index() {
+            return Ok(lib.search_songs(q, 1)
+                .into_iter()
+                .next()
+                .map(|s| (s.id, s.title)))
+        }
+        let n = search::NONE;
+        let s = SearchPage::new().with_size(1);
+        let sr = self.client.lock().unwrap().search(q, n, n, s)?;
+        Ok(sr.songs.into_iter().next().map(|s| (s.id, s.title)))
+    }
+
+    /// Finds the first match for a query, or the current song's item of the
+    /// given kind if there's no query.
+    fn find(
+        &self,
+        kind: Kind,
+        query: Option<String>,
+    ) -> ::std::result::Result<Item, Error> {
+        if let Some(q) = query {
+            let n = search::NONE;
+            let one = SearchPage::new().with_size(1);
+            let cli = &*self.client.lock().unwrap();
+            let found = match kind {
+                Kind::Song => cli.search(&q, n, n, one)?
+                    .songs
+                    .into_iter()
+                    .next()
+                    .map(Item::Song),
+                Kind::Album => cli.search(&q, n, one, n)?
+                    .albums
+                    .into_iter()
+                    .next()
+                    .map(Item::Album),
+                Kind::Artist => cli.search(&q, one, n, n)?
+                    .artists
+                    .into_iter()
+                    .next()
+                    .map(Item::Artist),
+            };
+            return found.ok_or_else(|| {
+                Error::Response(format!("Nothing found for \"{}\".", q))
+            })
+        }
+
+        let song = self.current_song()?;
+        let cli = &*self.client.lock().unwrap();
+        Ok(match kind {
+            Kind::Song => Item::Song(song),
+            Kind::Album => match song.album_id {
+                Some(id) => Item::Album(Album::get(cli, id)?),
+                None => return Err("Current song has no album".into()),
+            },
+            Kind::Artist => match song.artist_id {
+                Some(id) => Item::Artist(Artist::get(cli, id)?),
+                None => return Err("Current song has no artist".into()),
+            },
+        })
+    }
+
+    /// Matches a playlist file entry to a song, first by ID and then by
+    /// searching for its artist and title.
+    fn resolve(
+        &self,
+        entry: &Entry,
+    ) -> ::std::result::Result<Option<u64>, Error> {
+        let cli = &*self.client.lock().unwrap();
+        if let Some(id) = entry.id() {
+            if Song::get(cli, id).is_ok() {
+                return Ok(Some(id))
+            }
+            debug!("no song with ID {}, searching instead", id);
+        }
+
+        let query = match entry.query() {
+            Some(q) => q,
+            None => return Ok(None),
+        };
+        let n = search::NONE;
+        let page = SearchPage::new().with_size(10);
+        let found = cli.search(&query, n, n, page)?.songs;
+
+        // Without a title to check against, trust the search.
+        let title = match entry.title {
+            Some(ref t) => normalise(t),
+            None => return Ok(found.first().map(|s| s.id)),
+        };
+        let artist = entry.artist.as_ref().map(|a| normalise(a));
+        let best = found
+            .iter()
+            .filter_map(|song| {
+                let t = normalise(&song.title);
+                let mut score = if t == title {
+                    4
+                } else if t.contains(&title) || title.contains(&t) {
+                    2
+                } else {
+                    return None
+                };
+                if let (Some(a), Some(b)) = (&artist, &song.artist) {
+                    let b = normalise(b);
+                    if *a == b {
+                        score += 2;
+                    } else if b.contains(a.as_str()) || a.contains(&b) {
+                        score += 1;
+                    } else {
+                        return None
+                    }
+                }
+                Some((score, song.id))
+            })
+            .max_by_key(|&(score, _)| score);
+        Ok(best.map(|(_, id)| id))
+    }
+}
+
+/// Explains the error for commands that only admins can run.
+fn admin(err: ::sunk::Error) -> Error {
+    match err {
+        ::sunk::Error::Api(ApiError::NotAuthorized(_)) => Error::Response(
+            "Only admins can scan the library; ask your server's admin to \
+             give you that permission."
+                .into(),
+        ),
+        e => e.into(),
     }
 }
 
+fn strings<T: ToString>(items: &[T]) -> Vec<String> {
+    items.iter().map(|i| i.to_string()).collect()
+}
+
 impl Drop for Daemon {
     fn drop(&mut self) {
         ::std::fs::remove_file(
@@ -229,9 +1284,35 @@ impl Drop for Daemon {
     }
 }
 
-pub fn send(cmd: Command) -> Result {
-    let cfg = ::conf::Conf::new()?;
-    let mut stream = UnixStream::connect(cfg.socket)?;
+/// A connection to a daemon, either local or remote.
+trait Conn: Read + Write {
+    /// Tells the daemon the whole command has been sent.
+    fn close_write(&self) -> io::Result<()>;
+}
+
+impl Conn for UnixStream {
+    fn close_write(&self) -> io::Result<()> { self.shutdown(Shutdown::Write) }
+}
+
+impl Conn for TcpStream {
+    fn close_write(&self) -> io::Result<()> { self.shutdown(Shutdown::Write) }
+}
+
+fn connect(cfg: &Conf) -> ::std::result::Result<Box<Conn>, Error> {
+    if let Some(ref host) = cfg.host {
+        let secret = cfg.secret.as_ref().map(|s| s.as_str());
+        return Ok(Box::new(remote::connect(host, secret)?))
+    }
+    if !cfg.socket.exists() {
+        return Err("Socket file doesn't exist; did you try running `subs \
+                    daemon start`?"
+            .into())
+    }
+    Ok(Box::new(UnixStream::connect(&cfg.socket)?))
+}
+
+pub fn send(cfg: &Conf, cmd: Command) -> Result {
+    let mut stream = connect(cfg)?;
 
     let json = serde_json::to_string(&cmd)?;
     debug!("sending {}", json);
@@ -240,24 +1321,84 @@ pub fn send(cmd: Command) -> Result {
     Ok(())
 }
 
-pub fn send_recv(cmd: Command) -> ::std::result::Result<Reply, Error> {
-    let cfg = ::conf::Conf::new()?;
-    let mut stream = UnixStream::connect(cfg.socket)?;
+pub fn send_recv(
+    cfg: &Conf,
+    cmd: Command,
+) -> ::std::result::Result<Reply, Error> {
+    exchange(connect(cfg)?, cmd)
+}
+
+/// Sends a command to the daemon listening on `socket` and waits for its
+/// reply.
+pub fn send_recv_to<P: AsRef<Path>>(
+    socket: P,
+    cmd: Command,
+) -> ::std::result::Result<Reply, Error> {
+    exchange(Box::new(UnixStream::connect(socket)?), cmd)
+}
+
+/// Events streamed from the daemon, until it hangs up.
+pub struct Events {
+    reader: BufReader<Box<Conn>>,
+}
+
+impl Iterator for Events {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => match serde_json::from_str(&line) {
+                Ok(ev) => Some(ev),
+                Err(e) => {
+                    error!("unable to read event from daemon: {}", e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// Subscribes to the daemon's events.
+pub fn subscribe(cfg: &Conf) -> ::std::result::Result<Events, Error> {
+    let mut stream = connect(cfg)?;
+    stream.write_all(serde_json::to_string(&Command::Subscribe)?.as_bytes())?;
+    stream.close_write()?;
+    Ok(Events {
+        reader: BufReader::new(stream),
+    })
+}
+
+/// Subscribes to the events of the daemon listening on `socket`, returning
+/// the raw stream of JSON lines.
+pub fn subscribe_to<P: AsRef<Path>>(
+    socket: P,
+) -> ::std::result::Result<UnixStream, Error> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(serde_json::to_string(&Command::Subscribe)?.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(stream)
+}
 
+fn exchange(
+    mut stream: Box<Conn>,
+    cmd: Command,
+) -> ::std::result::Result<Reply, Error> {
     let json = serde_json::to_string(&cmd)?;
     debug!("sending {}", json);
     stream.write_all(json.as_bytes())?;
 
-    use std::net::Shutdown;
-    stream.shutdown(Shutdown::Write)?;
+    stream.close_write()?;
     let mut reply = String::new();
     stream.read_to_string(&mut reply)?;
-    Ok(serde_json::from_str::<Reply>(&reply)?)
+    match serde_json::from_str::<Reply>(&reply)? {
+        Reply::Error(e) => Err(Error::Response(e)),
+        r => Ok(r),
+    }
 }
 
-pub fn cmd_start() -> Result {
-    let cfg = ::conf::Conf::new()?;
-
+pub fn cmd_start(cfg: Conf) -> Result {
     debug!("Using config {:?}", cfg);
 
     // This otherwise induces a panic, not an error, so check here.
@@ -272,6 +1413,135 @@ pub fn cmd_start() -> Result {
     daemon.run()
 }
 
-pub fn cmd_stop() -> Result { self::send(Command::Stop) }
+pub fn cmd_stop(cfg: &Conf) -> Result { self::send(cfg, Command::Stop) }
 
 pub fn cmd_restart() -> Result { unimplemented!() }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+    use sunk::Client;
+    use sunk::song::Song;
+
+    use super::{check_rating, missing, save_playlist};
+    use testhttp::serve;
+
+    const OK: &str =
+        r#"{"subsonic-response": {"status": "ok", "version": "1.16.1"}}"#;
+
+    const NO_PLAYLISTS: &str = r#"{"subsonic-response": {
+        "status": "ok", "version": "1.16.1",
+        "playlists": {"playlist": []}
+    }}"#;
+
+    const MIX: &str = r#"{"subsonic-response": {
+        "status": "ok", "version": "1.16.1",
+        "playlists": {"playlist": [{
+            "id": "3", "name": "mix", "comment": "", "owner": "guest",
+            "public": false, "songCount": 2, "duration": 400,
+            "created": "2018-01-01T00:00:00.000Z",
+            "changed": "2018-01-01T00:00:00.000Z", "coverArt": "pl-3"
+        }]}
+    }}"#;
+
+    const CREATED: &str = r#"{"subsonic-response": {
+        "status": "ok", "version": "1.16.1",
+        "playlist": {
+            "id": "3", "name": "mix", "comment": "", "owner": "guest",
+            "public": false, "songCount": 2, "duration": 400,
+            "created": "2018-01-01T00:00:00.000Z",
+            "changed": "2018-01-01T00:00:00.000Z", "coverArt": "pl-3",
+            "entry": []
+        }
+    }}"#;
+
+    const NO_SONG: &str = r#"{"subsonic-response": {
+        "status": "failed", "version": "1.16.1",
+        "error": {"code": 70, "message": "Song not found"}
+    }}"#;
+
+    fn client(url: &str) -> Client {
+        Client::new(url.trim_end_matches('/'), "guest", "guest").unwrap()
+    }
+
+    /// The request lines the server got, in order.
+    fn requested(requests: Receiver<(String, String)>) -> Vec<String> {
+        requests
+            .iter()
+            .map(|(head, _)| head.lines().next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_an_existing_playlist_unless_forced() {
+        let (url, requests) = serve(vec![(200, MIX)]);
+        let err = save_playlist(&client(&url), "mix", &[7], false, None, false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error from daemon: A playlist named \"mix\" already exists; use \
+             --force to replace it."
+        );
+
+        let requested = requested(requests);
+        assert_eq!(requested.len(), 1);
+        assert!(requested[0].contains("getPlaylists"));
+    }
+
+    #[test]
+    fn replaces_the_songs_of_a_playlist_when_forced() {
+        let (url, requests) = serve(vec![(200, MIX), (200, OK), (200, OK)]);
+        save_playlist(&client(&url), "mix", &[7], true, None, true).unwrap();
+
+        let requested = requested(requests);
+        assert_eq!(requested.len(), 3);
+        assert!(requested[1].contains("updatePlaylist"));
+        assert!(requested[1].contains("playlistId=3"));
+        assert!(requested[1].contains("songIdToAdd=7"));
+        assert!(requested[1].contains("songIndexToRemove=0"));
+        assert!(requested[1].contains("songIndexToRemove=1"));
+        assert!(requested[2].contains("public=true"));
+    }
+
+    #[test]
+    fn creates_a_playlist_that_is_not_there() {
+        let (url, requests) = serve(vec![
+            (200, NO_PLAYLISTS),
+            (200, CREATED),
+            (200, MIX),
+            (200, OK),
+        ]);
+        let cli = client(&url);
+        save_playlist(&cli, "mix", &[7, 8], false, Some("new"), false)
+            .unwrap();
+
+        let requested = requested(requests);
+        assert_eq!(requested.len(), 4);
+        assert!(requested[1].contains("createPlaylist"));
+        assert!(requested[1].contains("name=mix"));
+        assert!(requested[1].contains("songId=7"));
+        assert!(requested[1].contains("songId=8"));
+        assert!(requested[3].contains("playlistId=3"));
+        assert!(requested[3].contains("comment=new"));
+        assert!(requested[3].contains("public=false"));
+        assert!(!requested[3].contains("songIndexToRemove"));
+    }
+
+    #[test]
+    fn only_accepts_ratings_from_1_to_5() {
+        assert!(check_rating(0).is_err());
+        assert!(check_rating(1).is_ok());
+        assert!(check_rating(5).is_ok());
+        assert_eq!(
+            check_rating(6).unwrap_err().to_string(),
+            "Ratings must be between 1 and 5"
+        );
+    }
+
+    #[test]
+    fn tells_missing_ids_from_other_errors() {
+        let (url, _) = serve(vec![(200, NO_SONG)]);
+        assert!(missing(&Song::get(&client(&url), 1999).unwrap_err().into()));
+        assert!(!missing(&"Nothing is playing".into()));
+    }
+}
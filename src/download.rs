@@ -0,0 +1,348 @@
+//! Downloading songs to a local directory.
+//!
+//! The daemon works out which songs to fetch; the files themselves are
+//! downloaded by the client, so they end up on the machine `subs` was run on
+//! even when it's controlling a remote daemon. Each file is written to a
+//! `.part` file first, and an interrupted download picks up where it left
+//! off the next time.
+
+use reqwest::header::{ByteRangeSpec, ContentLength, ContentType, Range};
+use reqwest::{self, StatusCode};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use sunk::song::Song;
+
+use api::Api;
+use conf::Conf;
+use error::{Error, Result};
+use quality::Quality;
+
+/// The parts of a song needed to download and name it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Download {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u64>,
+    pub disc: Option<u64>,
+    pub year: Option<u64>,
+    pub suffix: Option<String>,
+    /// The suffix the server transcodes to when streaming, if it does.
+    pub transcoded_suffix: Option<String>,
+    /// Size of the original file, in bytes.
+    pub size: u64,
+}
+
+impl Download {
+    pub fn new(song: &Song) -> Download {
+        Download {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            track: song.track.map(|t| t as u64),
+            disc: song.disc_number.map(|d| d as u64),
+            year: song.year.map(|y| y as u64),
+            suffix: song.suffix.clone(),
+            transcoded_suffix: song.transcoded_suffix.clone(),
+            size: song.size,
+        }
+    }
+
+    /// The file's suffix, once it's transcoded to `quality` if given.
+    fn suffix(&self, quality: Option<&Quality>) -> String {
+        let suffix = match quality {
+            Some(q) => q.format
+                .as_ref()
+                .or(self.transcoded_suffix.as_ref())
+                .or(self.suffix.as_ref()),
+            None => self.suffix.as_ref(),
+        };
+        suffix.map_or("mp3".into(), |s| s.clone())
+    }
+
+    /// Fills in a template such as `{artist}/{album}/{track:02} - {title}`.
+    /// Numbers can be padded with zeroes. Slashes and leading dots in the
+    /// song's details are replaced, so they can't create directories or
+    /// climb out of them.
+    pub fn path(&self, template: &str, quality: Option<&Quality>) -> PathBuf {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(e) => start + e,
+                None => break,
+            };
+            out.push_str(&rest[..start]);
+            let (name, width) = {
+                let field = &rest[start + 1..end];
+                match field.find(':') {
+                    Some(i) => (&field[..i], field[i + 1..].parse().ok()),
+                    None => (field, None),
+                }
+            };
+
+            let number = |n: Option<u64>| match width {
+                Some(w) => format!("{:01$}", n.unwrap_or(0), w),
+                None => n.unwrap_or(0).to_string(),
+            };
+            let value = match name {
+                "id" => self.id.to_string(),
+                "title" => self.title.clone(),
+                "artist" => self.artist
+                    .clone()
+                    .unwrap_or_else(|| "Unknown Artist".into()),
+                "album" => self.album
+                    .clone()
+                    .unwrap_or_else(|| "Unknown Album".into()),
+                "track" => number(self.track),
+                "disc" => number(self.disc),
+                "year" => number(self.year),
+                "suffix" => self.suffix(quality),
+                _ => rest[start..end + 1].to_string(),
+            };
+            out.push_str(&clean(&value));
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        PathBuf::from(out)
+    }
+}
+
+/// Makes a song's detail safe to put in a path.
+fn clean(value: &str) -> String {
+    let value = value.replace('/', "_");
+    let dots = value.len() - value.trim_start_matches('.').len();
+    "_".repeat(dots) + &value[dots..]
+}
+
+/// Whether a relative path stays inside the directory it's joined to.
+fn inside(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+/// Downloads each song into `dir`, named with `Conf::download_template`.
+/// Songs that have already been downloaded are skipped.
+pub fn run(
+    cfg: &Conf,
+    songs: &[Download],
+    dir: &Path,
+    transcode: bool,
+) -> Result {
+    let api = Api::new(cfg);
+    let http = reqwest::Client::new();
+    let quality = Quality::from_conf(cfg);
+    let mut failed = 0;
+
+    for (i, song) in songs.iter().enumerate() {
+        let name = song.path(
+            &cfg.download_template,
+            if transcode { Some(&quality) } else { None },
+        );
+        let prefix =
+            format!("[{}/{}] {}", i + 1, songs.len(), name.display());
+        if !inside(&name) {
+            println!("{}: would be saved outside {}", prefix, dir.display());
+            failed += 1;
+            continue
+        }
+        let path = dir.join(&name);
+
+        if path.exists() {
+            println!("{}: already downloaded", prefix);
+            continue
+        }
+        let url = if transcode {
+            let mut params = vec![("id", song.id.to_string())];
+            params.extend(quality.params());
+            api.url("stream", &params)
+        } else {
+            api.url("download", &[("id", song.id.to_string())])
+        };
+        if let Err(e) = fetch(&http, &url, song, &path, transcode, &prefix) {
+            println!("\r{}: {}", prefix, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(Error::Response(format!(
+            "Unable to download {} of {} songs.",
+            failed,
+            songs.len()
+        )))
+    }
+    Ok(())
+}
+
+fn fetch(
+    http: &reqwest::Client,
+    url: &str,
+    song: &Download,
+    path: &Path,
+    transcode: bool,
+    prefix: &str,
+) -> Result {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part = PathBuf::from(format!("{}.part", path.display()));
+    let mut done = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+
+    let mut req = http.get(url);
+    if done > 0 {
+        req.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(done)]));
+    }
+    let mut res = req.send()?;
+
+    // Errors come back as a Subsonic response rather than an HTTP error.
+    let is_error = res.headers()
+        .get::<ContentType>()
+        .map(|ct| {
+            let ct = ct.to_string();
+            ct.contains("json") || ct.contains("xml")
+        })
+        .unwrap_or(false);
+    if !res.status().is_success() || is_error {
+        return Err(Error::Service(format!(
+            "download failed ({})",
+            res.status()
+        )))
+    }
+
+    // Servers that can't resume send the whole file again.
+    let resumed = res.status() == StatusCode::PartialContent;
+    if !resumed {
+        done = 0;
+    }
+    let total = res.headers()
+        .get::<ContentLength>()
+        .map(|l| l.0 + done)
+        .or(if transcode { None } else { Some(song.size) });
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&part)?;
+    let mut buf = [0; 64 * 1024];
+    let mut shown = None;
+    loop {
+        let n = res.read(&mut buf)?;
+        if n == 0 {
+            break
+        }
+        file.write_all(&buf[..n])?;
+        done += n as u64;
+
+        let percent = total.map(|t| done * 100 / ::std::cmp::max(t, 1));
+        if percent != shown || total.is_none() {
+            progress(prefix, done, percent)?;
+            shown = percent;
+        }
+    }
+
+    fs::rename(&part, path)?;
+    progress(prefix, done, Some(100))?;
+    println!();
+    Ok(())
+}
+
+fn progress(prefix: &str, done: u64, percent: Option<u64>) -> Result {
+    let mb = done as f64 / 1024.0 / 1024.0;
+    match percent {
+        Some(p) => print!("\r{}: {:>3}% ({:.1} MB)", prefix, p, mb),
+        None => print!("\r{}: {:.1} MB", prefix, mb),
+    }
+    io::stdout().flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{inside, Download};
+    use quality::Quality;
+
+    fn song() -> Download {
+        Download {
+            id: 12,
+            title: "Either/Or".into(),
+            artist: Some("Elliott Smith".into()),
+            album: None,
+            track: Some(3),
+            disc: Some(1),
+            year: None,
+            suffix: Some("flac".into()),
+            transcoded_suffix: Some("mp3".into()),
+            size: 1024,
+        }
+    }
+
+    #[test]
+    fn fills_in_and_pads_fields() {
+        let path = song().path(
+            "{artist}/{album}/{disc}-{track:02} - {title}.{suffix}",
+            None,
+        );
+        assert_eq!(
+            path,
+            PathBuf::from("Elliott Smith/Unknown Album/1-03 - Either_Or.flac")
+        );
+        assert_eq!(
+            song().path("{year:4} {id}", None),
+            PathBuf::from("0000 12")
+        );
+    }
+
+    #[test]
+    fn details_cannot_leave_the_directory() {
+        let song = Download {
+            artist: Some("..".into()),
+            album: Some(".hidden".into()),
+            title: "../../.bashrc".into(),
+            ..song()
+        };
+        let path = song.path("{artist}/{album}/{title}", None);
+        assert_eq!(path, PathBuf::from("__/_hidden/___.._.bashrc"));
+        assert!(inside(&path));
+    }
+
+    #[test]
+    fn templates_cannot_leave_the_directory() {
+        assert!(!inside(&song().path("../{title}", None)));
+        assert!(!inside(&song().path("/tmp/{title}", None)));
+        assert!(inside(Path::new("./a/b")));
+    }
+
+    #[test]
+    fn keeps_unknown_and_unclosed_fields() {
+        assert_eq!(
+            song().path("{genre}/{title", None),
+            PathBuf::from("{genre}/{title")
+        );
+    }
+
+    #[test]
+    fn suffix_follows_transcoding() {
+        let original = Quality::default();
+        let ogg = Quality {
+            max_bit_rate: None,
+            format: Some("ogg".into()),
+        };
+        let t = "{suffix}";
+        assert_eq!(song().path(t, Some(&original)), PathBuf::from("mp3"));
+        assert_eq!(song().path(t, Some(&ogg)), PathBuf::from("ogg"));
+        assert_eq!(
+            Download { suffix: None, ..song() }.path(t, None),
+            PathBuf::from("mp3")
+        );
+    }
+}
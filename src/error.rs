@@ -1,3 +1,5 @@
+use clap;
+use reqwest;
 use serde_json;
 use std::{io, result};
 use sunk;
@@ -15,6 +17,9 @@ pub enum Error {
     #[fail(display = "Serialisation error: {}", _0)]
     Serde(#[cause] serde_json::Error),
     #[fail(display = "Error from daemon: {}", _0)] Response(String),
+    #[fail(display = "{}", _0)] Cli(#[cause] clap::Error),
+    #[fail(display = "HTTP error: {}", _0)] Http(#[cause] reqwest::Error),
+    #[fail(display = "{}", _0)] Service(String),
 }
 
 macro_rules! box_err {
@@ -31,6 +36,8 @@ box_err!(sunk::Error, Subsonic);
 box_err!(config::ConfigError, Config);
 box_err!(io::Error, Io);
 box_err!(serde_json::Error, Serde);
+box_err!(clap::Error, Cli);
+box_err!(reqwest::Error, Http);
 
 impl From<&'static str> for Error {
     fn from(s: &'static str) -> Error { self::Error::Other(s) }
@@ -0,0 +1,88 @@
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Something that happened in the player that other parts of the daemon
+/// might want to react to.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Event {
+    /// A new song started playing.
+    TrackStart(u64),
+    /// A local file, URL or radio station started playing.
+    StreamStart,
+    /// The tags of the current file or stream changed, as when a radio
+    /// station moves on to a new song.
+    Tags,
+    /// A song finished or was skipped.
+    TrackEnd(u64),
+    Pause,
+    Resume,
+    /// The current song moved to a new position, in seconds.
+    Seek(u64),
+    /// Songs were added to or removed from the queue.
+    Queue,
+    /// The player reached the end of the queue.
+    QueueEmpty,
+    Volume(u8),
+    Error(String),
+}
+
+/// Hands out player events to anyone who subscribes.
+///
+/// Subscribers that have hung up are dropped the next time an event is
+/// published.
+#[derive(Clone, Default)]
+pub struct Bus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl Bus {
+    pub fn new() -> Bus { Bus::default() }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (send, recv) = unbounded();
+        self.subscribers
+            .lock()
+            .expect("unable to lock subscribers")
+            .push(send);
+        recv
+    }
+
+    pub fn publish(&self, event: Event) {
+        debug!("publishing {:?}", event);
+        self.subscribers
+            .lock()
+            .expect("unable to lock subscribers")
+            .retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Event};
+
+    #[test]
+    fn every_subscriber_gets_each_event() {
+        let bus = Bus::new();
+        let a = bus.subscribe();
+        let b = bus.clone().subscribe();
+        bus.publish(Event::TrackStart(3));
+        bus.publish(Event::Pause);
+
+        for recv in &[a, b] {
+            match (recv.try_recv(), recv.try_recv()) {
+                (Ok(Event::TrackStart(3)), Ok(Event::Pause)) => (),
+                evs => panic!("unexpected events {:?}", evs),
+            }
+        }
+    }
+
+    #[test]
+    fn drops_subscribers_that_hang_up() {
+        let bus = Bus::new();
+        let kept = bus.subscribe();
+        drop(bus.subscribe());
+        bus.publish(Event::Queue);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert!(kept.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+//! User commands run when the player does something.
+//!
+//! Each hook runs through `sh -c` on its own thread, so a slow one holds up
+//! neither the player nor the other hooks, and it's killed if it runs for
+//! longer than `Conf::hook_timeout`. Details are passed in the environment:
+//! `SUBS_EVENT` always, `SUBS_ID`, `SUBS_TITLE`, `SUBS_ARTIST`, `SUBS_ALBUM`,
+//! `SUBS_DURATION` and `SUBS_COVER` when there's a song, and `SUBS_ERROR` for
+//! errors.
+
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use sunk::song::Song;
+use sunk::Client;
+
+use conf::Conf;
+use cover::Covers;
+use event::{Bus, Event};
+
+/// How often to check whether a hook has finished.
+const POLL: u64 = 100;
+
+struct Hooks {
+    track_start: Option<String>,
+    track_end: Option<String>,
+    pause: Option<String>,
+    resume: Option<String>,
+    queue_empty: Option<String>,
+    error: Option<String>,
+    timeout: Duration,
+}
+
+impl Hooks {
+    fn from_conf(cfg: &Conf) -> Option<Hooks> {
+        let hooks = Hooks {
+            track_start: cfg.hook_track_start.clone(),
+            track_end: cfg.hook_track_end.clone(),
+            pause: cfg.hook_pause.clone(),
+            resume: cfg.hook_resume.clone(),
+            queue_empty: cfg.hook_queue_empty.clone(),
+            error: cfg.hook_error.clone(),
+            timeout: Duration::from_secs(cfg.hook_timeout),
+        };
+
+        let any = [
+            &hooks.track_start,
+            &hooks.track_end,
+            &hooks.pause,
+            &hooks.resume,
+            &hooks.queue_empty,
+            &hooks.error,
+        ].iter()
+            .any(|h| h.is_some());
+        if any {
+            Some(hooks)
+        } else {
+            None
+        }
+    }
+
+    /// The event's name and hook, if it has one.
+    fn get(&self, ev: &Event) -> Option<(&'static str, &str)> {
+        let (name, hook) = match *ev {
+            Event::TrackStart(_) | Event::StreamStart => {
+                ("track-start", &self.track_start)
+            }
+            Event::TrackEnd(_) => ("track-end", &self.track_end),
+            Event::Pause => ("pause", &self.pause),
+            Event::Resume => ("resume", &self.resume),
+            Event::QueueEmpty => ("queue-empty", &self.queue_empty),
+            Event::Error(_) => ("error", &self.error),
+            _ => return None,
+        };
+        hook.as_ref().map(|h| (name, h.as_str()))
+    }
+}
+
+/// Starts running hooks in the background, if any are set.
+pub fn spawn(
+    cfg: &Conf,
+    client: Arc<Mutex<Client>>,
+    covers: Arc<Covers>,
+    bus: &Bus,
+) {
+    let hooks = match Hooks::from_conf(cfg) {
+        Some(h) => h,
+        None => return,
+    };
+    let events = bus.subscribe();
+
+    thread::spawn(move || {
+        // Pausing and resuming don't say which song, so remember it.
+        let mut current = None;
+        while let Ok(ev) = events.recv() {
+            match ev {
+                Event::TrackStart(id) | Event::TrackEnd(id) => {
+                    current = Some(id)
+                }
+                Event::StreamStart | Event::QueueEmpty => current = None,
+                _ => (),
+            }
+
+            let (name, hook) = match hooks.get(&ev) {
+                Some(h) => h,
+                None => continue,
+            };
+
+            let mut env = vec![("SUBS_EVENT".to_string(), name.to_string())];
+            if let Event::Error(ref e) = ev {
+                env.push(("SUBS_ERROR".into(), e.clone()));
+            }
+            if let Some(id) = current {
+                env.extend(song_env(&client, &covers, id));
+            }
+            run(hook.to_string(), env, hooks.timeout);
+        }
+    });
+}
+
+fn song_env(
+    client: &Arc<Mutex<Client>>,
+    covers: &Covers,
+    id: u64,
+) -> Vec<(String, String)> {
+    let song = match Song::get(&*client.lock().unwrap(), id) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("unable to get song {} for hook: {}", id, e);
+            return vec![("SUBS_ID".into(), id.to_string())]
+        }
+    };
+
+    let mut env = vec![
+        ("SUBS_ID".into(), id.to_string()),
+        ("SUBS_TITLE".into(), song.title.clone()),
+        (
+            "SUBS_DURATION".into(),
+            song.duration.map(|d| d as u64).unwrap_or(0).to_string(),
+        ),
+    ];
+    if let Some(ref artist) = song.artist {
+        env.push(("SUBS_ARTIST".into(), artist.clone()));
+    }
+    if let Some(ref album) = song.album {
+        env.push(("SUBS_ALBUM".into(), album.clone()));
+    }
+    if let Some(cover) = covers.cached(&song) {
+        env.push(("SUBS_COVER".into(), cover.to_string_lossy().into_owned()));
+    }
+    env
+}
+
+/// Runs a hook on its own thread, killing it if it takes too long.
+fn run(hook: String, env: Vec<(String, String)>, timeout: Duration) {
+    thread::spawn(move || {
+        debug!("running hook `{}`", hook);
+        let mut child = match Command::new("sh")
+            .arg("-c")
+            .arg(&hook)
+            .envs(env)
+            .stdin(Stdio::null())
+            .spawn()
+        {
+            Ok(c) => c,
+            Err(e) => return error!("unable to run hook `{}`: {}", hook, e),
+        };
+
+        let started = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        warn!("hook `{}` exited with {}", hook, status);
+                    }
+                    return
+                }
+                Ok(None) if started.elapsed() >= timeout => {
+                    warn!("hook `{}` timed out, killing it", hook);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(POLL)),
+                Err(e) => {
+                    return error!("unable to wait for hook `{}`: {}", hook, e)
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{run, Hooks};
+    use conf::Conf;
+    use event::Event;
+
+    /// Waits up to two seconds for `f` to hold.
+    fn eventually<F: Fn() -> bool>(f: F) -> bool {
+        for _ in 0..20 {
+            if f() {
+                return true
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn only_set_hooks_run() {
+        assert!(Hooks::from_conf(&Conf::default()).is_none());
+
+        let hooks = Hooks::from_conf(&Conf {
+            hook_track_start: Some("notify-send started".into()),
+            ..Conf::default()
+        }).unwrap();
+        assert_eq!(
+            hooks.get(&Event::StreamStart),
+            Some(("track-start", "notify-send started"))
+        );
+        assert_eq!(hooks.get(&Event::TrackStart(3)).unwrap().0, "track-start");
+        assert_eq!(hooks.get(&Event::Pause), None);
+        assert_eq!(hooks.get(&Event::Queue), None);
+    }
+
+    #[test]
+    fn passes_details_in_the_environment() {
+        let out = env::temp_dir().join(format!("subs-hook-{}", process::id()));
+        let _ = fs::remove_file(&out);
+        run(
+            format!("printf '%s %s' \"$SUBS_EVENT\" \"$SUBS_ID\" > {:?}", out),
+            vec![
+                ("SUBS_EVENT".into(), "pause".into()),
+                ("SUBS_ID".into(), "12".into()),
+            ],
+            Duration::from_secs(5),
+        );
+        assert!(eventually(|| {
+            fs::read_to_string(&out).ok() == Some("pause 12".into())
+        }));
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn kills_hooks_that_run_too_long() {
+        let pid =
+            env::temp_dir().join(format!("subs-hook-pid-{}", process::id()));
+        let _ = fs::remove_file(&pid);
+        run(
+            format!("echo $$ > {:?}; sleep 30", pid),
+            Vec::new(),
+            Duration::from_millis(200),
+        );
+
+        assert!(eventually(|| fs::read_to_string(&pid).is_ok()));
+        let proc_dir = format!("/proc/{}", fs::read_to_string(&pid).unwrap());
+        assert!(eventually(|| !Path::new(proc_dir.trim()).exists()));
+        fs::remove_file(&pid).unwrap();
+    }
+}
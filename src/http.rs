@@ -0,0 +1,413 @@
+//! A JSON API over HTTP, for controlling the daemon from other machines.
+//!
+//! Requests are translated into daemon commands and sent over the daemon's
+//! own socket, and replies are the same `Reply` values the socket sends.
+
+use serde_json;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use daemon::{self, Command, Reply};
+use error::{Error, Result};
+use event::Bus;
+use remote;
+
+/// Number of results returned by `/search` if none is given.
+const SEARCH_SIZE: usize = 20;
+
+/// Starts the HTTP server in the background. Without a token it only
+/// listens on a loopback address.
+pub fn spawn(
+    addr: &str,
+    port: u16,
+    token: Option<String>,
+    socket: &Path,
+    bus: Bus,
+) -> Result {
+    if token.is_none() && !loopback(addr) {
+        error!("refusing to serve HTTP on {} without `http_token`", addr);
+        return Err("Set `http_token` to serve HTTP beyond this machine".into())
+    }
+
+    let server = match Server::http((addr, port)) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("unable to bind HTTP server to {}:{}: {}", addr, port, e);
+            return Err("Unable to start HTTP server".into())
+        }
+    };
+    info!("HTTP server listening on {}:{}", addr, port);
+
+    let api = Arc::new(Api {
+        socket: socket.to_path_buf(),
+        token,
+        bus,
+    });
+    thread::spawn(move || {
+        for req in server.incoming_requests() {
+            let api = api.clone();
+            thread::spawn(move || api.handle(req));
+        }
+    });
+
+    Ok(())
+}
+
+/// Whether the address only accepts connections from this machine.
+fn loopback(addr: &str) -> bool {
+    addr == "localhost"
+        || addr.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+}
+
+/// Body for `POST /queue`; one of `id`, `ids` or `query` should be given.
+#[derive(Debug, Deserialize)]
+struct AddRequest {
+    id: Option<u64>,
+    #[serde(default)]
+    ids: Vec<u64>,
+    query: Option<String>,
+    /// Add after the current song instead of at the end.
+    #[serde(default)]
+    next: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SeekRequest {
+    position: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct VolumeRequest {
+    volume: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct RandomRequest {
+    number: usize,
+}
+
+struct Api {
+    socket: PathBuf,
+    token: Option<String>,
+    bus: Bus,
+}
+
+impl Api {
+    fn handle(&self, mut req: Request) {
+        let method = req.method().clone();
+        let (path, query) = split_url(req.url());
+        debug!("HTTP {} {}", method, path);
+
+        if !self.authorised(&req, &query) {
+            return respond(req, 401, &Reply::Error("Unauthorised".into()))
+        }
+
+        if method == Method::Get && path == "/events" {
+            return self.stream_events(req)
+        }
+
+        let mut body = String::new();
+        if let Err(e) = req.as_reader().read_to_string(&mut body) {
+            return respond(req, 400, &Reply::Error(e.to_string()))
+        }
+
+        let cmd = match route(&method, &path, &query, &body) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => {
+                return respond(req, 404, &Reply::Error("Not found".into()))
+            }
+            Err(e) => return respond(req, 400, &Reply::Error(e.to_string())),
+        };
+
+        match daemon::send_recv_to(&self.socket, cmd) {
+            Ok(reply) => respond(req, 200, &reply),
+            Err(Error::Response(e)) => respond(req, 500, &Reply::Error(e)),
+            Err(e) => respond(req, 502, &Reply::Error(e.to_string())),
+        }
+    }
+
+    /// Checks the request's token, from either a bearer token or the `token`
+    /// query parameter (browsers can't set headers for event streams).
+    fn authorised(
+        &self,
+        req: &Request,
+        query: &HashMap<String, String>,
+    ) -> bool {
+        let token = match self.token {
+            Some(ref t) => t,
+            None => return true,
+        };
+
+        let given = req.headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str().trim_start_matches("Bearer ").to_string())
+            .or_else(|| query.get("token").cloned());
+
+        given.map_or(false, |g| remote::eq(g.as_bytes(), token.as_bytes()))
+    }
+
+    /// Sends player events as they happen, as a stream of Server-Sent Events.
+    fn stream_events(&self, req: Request) {
+        let events = self.bus.subscribe();
+        let mut out = req.into_writer();
+
+        let head = "HTTP/1.1 200 OK\r\n\
+                    Content-Type: text/event-stream\r\n\
+                    Cache-Control: no-cache\r\n\
+                    Connection: keep-alive\r\n\r\n";
+        if out.write_all(head.as_bytes()).and_then(|_| out.flush()).is_err() {
+            return
+        }
+
+        while let Ok(ev) = events.recv() {
+            let json =
+                serde_json::to_string(&ev).expect("error serializing event");
+            let msg = format!("data: {}\n\n", json);
+            if out.write_all(msg.as_bytes()).and_then(|_| out.flush()).is_err()
+            {
+                debug!("event stream closed");
+                break
+            }
+        }
+    }
+}
+
+/// Works out the daemon command for a request, or `None` if nothing matches.
+fn route(
+    method: &Method,
+    path: &str,
+    query: &HashMap<String, String>,
+    body: &str,
+) -> ::std::result::Result<Option<Command>, Error> {
+    use daemon::Command::*;
+    Ok(Some(match (method, path) {
+        (&Method::Get, "/status") => StatusReq,
+        (&Method::Get, "/queue") => QueueReq,
+        (&Method::Delete, "/queue") => Clear,
+        (&Method::Post, "/queue") => {
+            let add: AddRequest = serde_json::from_str(body)?;
+            match (add.id, add.query, add.next) {
+                (Some(id), _, false) => Add(id),
+                (Some(id), _, true) => AddNext(id),
+                (None, Some(q), false) => AddSearch(q),
+                (None, Some(q), true) => AddNextSearch(q),
+                (None, None, _) if !add.ids.is_empty() => AddMany(add.ids),
+                _ => {
+                    return Err("Expected one of `id`, `ids` or `query`".into())
+                }
+            }
+        }
+        (&Method::Post, "/queue/random") => {
+            Random(serde_json::from_str::<RandomRequest>(body)?.number)
+        }
+        (&Method::Get, "/search") => {
+            let q = match query.get("q") {
+                Some(q) => q.clone(),
+                None => return Err("Missing `q` parameter".into()),
+            };
+            let n = query
+                .get("n")
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(SEARCH_SIZE);
+            SearchSongs(q, n)
+        }
+        (&Method::Post, "/player/play") => Play,
+        (&Method::Post, "/player/pause") => Pause,
+        (&Method::Post, "/player/toggle") => Toggle,
+        (&Method::Post, "/player/next") => Next,
+        (&Method::Post, "/player/prev") => Prev,
+        (&Method::Post, "/player/seek") => {
+            Seek(serde_json::from_str::<SeekRequest>(body)?.position)
+        }
+        (&Method::Post, "/player/volume") => {
+            SetVolume(serde_json::from_str::<VolumeRequest>(body)?.volume)
+        }
+        (&Method::Post, "/command") => {
+            let cmd: Command = serde_json::from_str(body)?;
+            if !one_shot(&cmd) {
+                return Err("That command can't be sent over HTTP".into())
+            }
+            cmd
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Whether the command gets a single reply, so it can be answered over
+/// HTTP. Stopping the daemon is left to its own machine, and subscribing
+/// would hold the connection open for good.
+fn one_shot(cmd: &Command) -> bool {
+    use daemon::Command::*;
+    match *cmd {
+        Batch(ref cmds, _) => cmds.iter().all(one_shot),
+        Play | Pause | Toggle | Next | Prev | Clear | Add(_) | AddMany(_)
+        | AddSearch(_) | AddNext(_) | AddNextSearch(_)
+        | Search(..) | SearchSongs(..) | Seek(_) | SetVolume(_)
+        | StatusReq | QueueReq | AddSource(..) | PlayNow(_) | PlayAt(_)
+        | Random(_)
+        | RandomWith { .. } | Star(..) | Unstar(..) | Rate(..)
+        | Starred(_) | Load(_) | Playlists | PlaylistShow(_)
+        | PlaylistCreate(..) | PlaylistAdd(..) | PlaylistRemove(..)
+        | PlaylistRename(..) | PlaylistDelete(_) | Save(..)
+        | Export(_) | Import(_) | Info(..) | Lyrics | Cover(_) | Artists
+        | ArtistAlbums(_) | AlbumSongs(_) | Scan | ScanStatus
+        | CacheStatus | CacheClear | CachePin(_) | Downloads(..)
+        | PlaylistDownloads(_) | SetQuality(_) | QualityReq | Stations
+        | RadioPlay(_) | RadioCreate(..) | RadioDelete(_) => true,
+        Stop | Subscribe | Status(_) | Queue(_) => false,
+    }
+}
+
+fn respond(req: Request, code: u16, reply: &Reply) {
+    let json = serde_json::to_string(reply).expect("error serializing reply");
+    let header =
+        Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+            .expect("invalid header");
+    let res = Response::from_string(json)
+        .with_status_code(code)
+        .with_header(header);
+    if let Err(e) = req.respond(res) {
+        debug!("unable to send HTTP response: {}", e);
+    }
+}
+
+/// Splits a request URL into its path and decoded query parameters.
+fn split_url(url: &str) -> (String, HashMap<String, String>) {
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap_or("/").trim_end_matches('/');
+    let path = if path.is_empty() { "/" } else { path };
+
+    let query = parts
+        .next()
+        .unwrap_or("")
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let mut kv = p.splitn(2, '=');
+            let k = decode(kv.next().unwrap_or(""));
+            let v = decode(kv.next().unwrap_or(""));
+            (k, v)
+        })
+        .collect();
+
+    (path.to_string(), query)
+}
+
+/// Decodes a `application/x-www-form-urlencoded` string.
+fn decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = iter.by_ref().take(2).collect::<Vec<_>>();
+                let parsed = ::std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match parsed {
+                    Some(b) => bytes.push(b),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex);
+                    }
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use tiny_http::Method;
+
+    use super::{decode, loopback, route, split_url};
+    use daemon::Command::{self, *};
+    use error::Error;
+
+    fn route_url(
+        method: Method,
+        url: &str,
+        body: &str,
+    ) -> Result<Option<Command>, Error> {
+        let (path, query) = split_url(url);
+        route(&method, &path, &query, body)
+    }
+
+    fn cmd(method: Method, url: &str, body: &str) -> Option<Command> {
+        route_url(method, url, body).unwrap()
+    }
+
+    fn err(method: Method, url: &str, body: &str) -> String {
+        route_url(method, url, body).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn splits_and_decodes_urls() {
+        let (path, query) = split_url("/search/?q=sigur+r%C3%B3s&n=5&flag");
+        assert_eq!(path, "/search");
+        let mut expected = HashMap::new();
+        expected.insert("q".to_string(), "sigur rós".to_string());
+        expected.insert("n".to_string(), "5".to_string());
+        expected.insert("flag".to_string(), String::new());
+        assert_eq!(query, expected);
+
+        assert_eq!(split_url("/").0, "/");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%41"), "%zzA");
+    }
+
+    #[test]
+    fn routes_requests() {
+        assert_eq!(cmd(Method::Get, "/status", ""), Some(StatusReq));
+        assert_eq!(
+            cmd(Method::Post, "/queue", r#"{"id": 4, "next": true}"#),
+            Some(AddNext(4))
+        );
+        assert_eq!(
+            cmd(Method::Post, "/queue", r#"{"ids": [1, 2]}"#),
+            Some(AddMany(vec![1, 2]))
+        );
+        assert_eq!(
+            cmd(Method::Get, "/search?q=blue", ""),
+            Some(SearchSongs("blue".into(), 20))
+        );
+        assert_eq!(cmd(Method::Put, "/status", ""), None);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        assert_eq!(
+            err(Method::Post, "/queue", "{}"),
+            "Expected one of `id`, `ids` or `query`"
+        );
+        assert_eq!(err(Method::Get, "/search", ""), "Missing `q` parameter");
+        let refused = "That command can't be sent over HTTP";
+        let batch = r#"{"Batch": [["Next", "Stop"], false]}"#;
+        assert_eq!(err(Method::Post, "/command", batch), refused);
+        let subscribe = r#""Subscribe""#;
+        assert_eq!(err(Method::Post, "/command", subscribe), refused);
+        assert_eq!(
+            cmd(Method::Post, "/command", r#"{"Seek": 30}"#),
+            Some(Seek(30))
+        );
+    }
+
+    #[test]
+    fn knows_loopback_addresses() {
+        assert!(loopback("127.0.0.1"));
+        assert!(loopback("::1"));
+        assert!(loopback("localhost"));
+        assert!(!loopback("0.0.0.0"));
+        assert!(!loopback("192.168.1.2"));
+    }
+}
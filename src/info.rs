@@ -0,0 +1,269 @@
+//! Detailed information about songs, albums and artists.
+
+use std::fmt;
+use sunk::song::Song;
+use sunk::{Album, Artist, Client};
+
+use error::Error;
+use player::Track;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SongInfo {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u64>,
+    pub disc: Option<u64>,
+    pub year: Option<u64>,
+    pub genre: Option<String>,
+    /// In kbps.
+    pub bitrate: Option<u64>,
+    pub suffix: Option<String>,
+    pub content_type: Option<String>,
+    pub path: Option<String>,
+    pub play_count: Option<u64>,
+    pub rating: Option<u8>,
+    pub starred: bool,
+    pub duration: u64,
+}
+
+impl SongInfo {
+    pub fn new(song: &Song) -> SongInfo {
+        SongInfo {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            track: song.track.map(|t| t as u64),
+            disc: song.disc_number.map(|d| d as u64),
+            year: song.year.map(|y| y as u64),
+            genre: song.genre.clone(),
+            bitrate: song.bitrate.map(|b| b as u64),
+            suffix: song.suffix.clone(),
+            content_type: song.content_type.clone(),
+            path: song.path.clone(),
+            play_count: song.play_count.map(|c| c as u64),
+            rating: song.user_rating,
+            starred: song.starred.is_some(),
+            duration: song.duration.map(|d| d as u64).unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Display for SongInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.title)?;
+        field(f, "Artist", &self.artist)?;
+        field(f, "Album", &self.album)?;
+        field(f, "Track", &self.track)?;
+        field(f, "Disc", &self.disc)?;
+        field(f, "Year", &self.year)?;
+        field(f, "Genre", &self.genre)?;
+        field(f, "Duration", &Some(minsec(self.duration)))?;
+        field(f, "Bitrate", &self.bitrate.map(|b| format!("{} kbps", b)))?;
+        field(f, "Suffix", &self.suffix)?;
+        field(f, "Type", &self.content_type)?;
+        field(f, "Path", &self.path)?;
+        field(f, "Plays", &self.play_count)?;
+        field(f, "Rating", &self.rating.map(|r| format!("{}/5", r)))?;
+        field(f, "Starred", &Some(yes_no(self.starred)))?;
+        write!(f, "{:>10}: {}", "ID", self.id)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlbumInfo {
+    pub id: u64,
+    pub name: String,
+    pub artist: Option<String>,
+    pub year: Option<u64>,
+    pub genre: Option<String>,
+    pub duration: u64,
+    pub starred: bool,
+    pub notes: Option<String>,
+    pub songs: Vec<Track>,
+}
+
+impl AlbumInfo {
+    pub fn new(
+        cli: &Client,
+        album: &Album,
+    ) -> ::std::result::Result<AlbumInfo, Error> {
+        let info = album.info(cli)?;
+        let songs = album.songs(cli)?;
+        Ok(AlbumInfo {
+            id: album.id,
+            name: album.name.clone(),
+            artist: album.artist.clone(),
+            year: album.year.map(|y| y as u64),
+            genre: album.genre.clone(),
+            duration: songs
+                .iter()
+                .map(|s| s.duration.map(|d| d as u64).unwrap_or(0))
+                .sum(),
+            starred: album.starred.is_some(),
+            notes: info.notes.as_ref().map(|n| strip_tags(n)),
+            songs: songs.iter().map(|s| Track::new(cli, s)).collect(),
+        })
+    }
+}
+
+impl fmt::Display for AlbumInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        field(f, "Artist", &self.artist)?;
+        field(f, "Year", &self.year)?;
+        field(f, "Genre", &self.genre)?;
+        field(f, "Duration", &Some(minsec(self.duration)))?;
+        field(f, "Starred", &Some(yes_no(self.starred)))?;
+        writeln!(f, "{:>10}: {}", "ID", self.id)?;
+        if let Some(ref notes) = self.notes {
+            writeln!(f, "\n{}", notes)?;
+        }
+        writeln!(f)?;
+        for (i, song) in self.songs.iter().enumerate() {
+            writeln!(f, "{:>3}. {} ({})", i + 1, song, minsec(song.duration))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArtistInfo {
+    pub id: u64,
+    pub name: String,
+    pub starred: bool,
+    pub biography: Option<String>,
+    pub albums: Vec<String>,
+    pub similar: Vec<String>,
+}
+
+impl ArtistInfo {
+    pub fn new(
+        cli: &Client,
+        artist: &Artist,
+    ) -> ::std::result::Result<ArtistInfo, Error> {
+        let info = artist.info(cli)?;
+        Ok(ArtistInfo {
+            id: artist.id,
+            name: artist.name.clone(),
+            starred: artist.starred.is_some(),
+            biography: info.biography.as_ref().map(|b| strip_tags(b)),
+            albums: artist
+                .albums(cli)?
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            similar: info
+                .similar_artists
+                .iter()
+                .map(|a| a.name.clone())
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Display for ArtistInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        field(f, "Starred", &Some(yes_no(self.starred)))?;
+        writeln!(f, "{:>10}: {}", "ID", self.id)?;
+        if let Some(ref bio) = self.biography {
+            writeln!(f, "\n{}", bio)?;
+        }
+        if !self.albums.is_empty() {
+            writeln!(f, "\nAlbums:")?;
+            for album in &self.albums {
+                writeln!(f, "  {}", album)?;
+            }
+        }
+        if !self.similar.is_empty() {
+            writeln!(f, "\nSimilar artists:")?;
+            for artist in &self.similar {
+                writeln!(f, "  {}", artist)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a labelled line, if there's anything to write.
+fn field<T: fmt::Display>(
+    f: &mut fmt::Formatter,
+    name: &str,
+    value: &Option<T>,
+) -> fmt::Result {
+    match *value {
+        Some(ref v) => writeln!(f, "{:>10}: {}", name, v),
+        None => Ok(()),
+    }
+}
+
+fn minsec(secs: u64) -> String { format!("{}:{:02}", secs / 60, secs % 60) }
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Biographies and notes come from Last.fm with links in them.
+fn strip_tags(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut in_tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => (),
+        }
+    }
+    out.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{strip_tags, SongInfo};
+
+    #[test]
+    fn strips_links_from_notes() {
+        assert_eq!(
+            strip_tags(
+                "Post-rock band. <a href=\"https://last.fm/x\">Read more</a> "
+            ),
+            "Post-rock band. Read more"
+        );
+    }
+
+    #[test]
+    fn song_info_leaves_out_missing_fields() {
+        let info = SongInfo {
+            id: 5,
+            title: "Hoppípolla".into(),
+            artist: Some("Sigur Rós".into()),
+            album: None,
+            track: Some(2),
+            disc: None,
+            year: None,
+            genre: None,
+            bitrate: Some(320),
+            suffix: None,
+            content_type: None,
+            path: None,
+            play_count: None,
+            rating: Some(4),
+            starred: true,
+            duration: 268,
+        };
+        assert_eq!(
+            info.to_string(),
+            "Hoppípolla\n    Artist: Sigur Rós\n     Track: 2\n  \
+             Duration: 4:28\n   Bitrate: 320 kbps\n    Rating: 4/5\n   \
+             Starred: yes\n        ID: 5"
+        );
+    }
+}
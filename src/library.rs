@@ -0,0 +1,510 @@
+//! A local copy of the library's artists, albums and songs.
+//!
+//! The index is kept in the cache directory and synced in the background.
+//! `getIndexes` with `ifModifiedSince` tells whether anything has changed
+//! since the last sync; if it has, the artists and their albums are listed
+//! again, but only albums that are new or have changed are fetched song by
+//! song. Searches, browsing and status lookups are served from the index,
+//! so they're quick and keep working while the server can't be reached.
+
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use api::Api;
+use conf::Conf;
+use error::{Error, Result};
+use player::Track;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SongEntry {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_id: Option<u64>,
+    pub artist_id: Option<u64>,
+    pub track: Option<u64>,
+    pub year: Option<u64>,
+    pub duration: u64,
+    pub cover_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlbumEntry {
+    pub id: u64,
+    pub name: String,
+    pub artist: Option<String>,
+    pub year: Option<u64>,
+    pub song_count: u64,
+    /// Song IDs in track order.
+    pub songs: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArtistEntry {
+    pub id: u64,
+    pub name: String,
+    pub albums: Vec<u64>,
+}
+
+impl fmt::Display for SongEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref artist) = self.artist {
+            write!(f, "{} - ", artist)?;
+        }
+        if let Some(ref album) = self.album {
+            write!(f, "{} ", album)?;
+            if let Some(year) = self.year {
+                write!(f, "[{}] ", year)?;
+            }
+            write!(f, "- ")?;
+        }
+        write!(f, "{}", self.title)
+    }
+}
+
+impl fmt::Display for AlbumEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref artist) = self.artist {
+            write!(f, "{} - ", artist)?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some(year) = self.year {
+            write!(f, " [{}]", year)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Index {
+    /// When the server says the library last changed, in milliseconds.
+    last_modified: u64,
+    artists: Vec<ArtistEntry>,
+    albums: HashMap<u64, AlbumEntry>,
+    songs: HashMap<u64, SongEntry>,
+}
+
+pub struct Library {
+    api: Api,
+    path: PathBuf,
+    index: RwLock<Index>,
+}
+
+impl Library {
+    /// Loads the index saved by the last sync, if there is one. With
+    /// syncing turned off the index is left empty, so nothing stale is
+    /// served from it.
+    pub fn load(cfg: &Conf) -> Library {
+        let path = cfg.cache.join("library.json");
+        let index = if cfg.library_sync > 0 {
+            File::open(&path)
+                .ok()
+                .and_then(|f| serde_json::from_reader(f).ok())
+                .unwrap_or_default()
+        } else {
+            Index::default()
+        };
+
+        Library {
+            api: Api::new(cfg),
+            path,
+            index: RwLock::new(index),
+        }
+    }
+
+    /// Whether there's anything to serve from the index yet.
+    pub fn is_empty(&self) -> bool {
+        self.index.read().unwrap().songs.is_empty()
+    }
+
+    pub fn song(&self, id: u64) -> Option<SongEntry> {
+        self.index.read().unwrap().songs.get(&id).cloned()
+    }
+
+    pub fn track(&self, song: &SongEntry) -> Track {
+        Track {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            duration: song.duration,
+            cover_url: song
+                .cover_id
+                .as_ref()
+                .map(|c| self.api.url("getCoverArt", &[("id", c.clone())])),
+            cover_path: None,
+            location: None,
+        }
+    }
+
+    pub fn artists(&self) -> Vec<(u64, String)> {
+        self.index
+            .read()
+            .unwrap()
+            .artists
+            .iter()
+            .map(|a| (a.id, a.name.clone()))
+            .collect()
+    }
+
+    pub fn albums(&self, artist: u64) -> Option<Vec<(u64, String)>> {
+        let index = self.index.read().unwrap();
+        let artist = index.artists.iter().find(|a| a.id == artist)?;
+        Some(
+            artist
+                .albums
+                .iter()
+                .filter_map(|id| index.albums.get(id))
+                .map(|a| (a.id, a.to_string()))
+                .collect(),
+        )
+    }
+
+    pub fn album_songs(&self, album: u64) -> Option<Vec<SongEntry>> {
+        let index = self.index.read().unwrap();
+        let album = index.albums.get(&album)?;
+        Some(
+            album
+                .songs
+                .iter()
+                .filter_map(|id| index.songs.get(id))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Songs whose artist, album and title between them contain every word
+    /// of the query, ignoring case and punctuation. The best matches come
+    /// first: songs with the query as their title, then songs with its
+    /// words in their title, then songs with its words anywhere.
+    pub fn search_songs(&self, query: &str, n: usize) -> Vec<SongEntry> {
+        let words = words(query);
+        let index = self.index.read().unwrap();
+        let mut found = index
+            .songs
+            .values()
+            .filter_map(|s| Some((rank(s, &words)?, s)))
+            .collect::<Vec<_>>();
+        found.sort_by(|&(ra, a), &(rb, b)| {
+            (ra, &a.artist, &a.album, a.track)
+                .cmp(&(rb, &b.artist, &b.album, b.track))
+        });
+        found.into_iter().take(n).map(|(_, s)| s.clone()).collect()
+    }
+
+    pub fn search_albums(&self, query: &str, n: usize) -> Vec<AlbumEntry> {
+        let words = words(query);
+        let index = self.index.read().unwrap();
+        let mut found = index
+            .albums
+            .values()
+            .filter(|a| {
+                let text = normalise(&a.to_string());
+                words.iter().all(|w| text.contains(w.as_str()))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| (&a.artist, a.year).cmp(&(&b.artist, b.year)));
+        found.truncate(n);
+        found
+    }
+
+    pub fn search_artists(&self, query: &str, n: usize) -> Vec<String> {
+        let words = words(query);
+        self.index
+            .read()
+            .unwrap()
+            .artists
+            .iter()
+            .filter(|a| {
+                let text = normalise(&a.name);
+                words.iter().all(|w| text.contains(w.as_str()))
+            })
+            .take(n)
+            .map(|a| a.name.clone())
+            .collect()
+    }
+
+    /// Brings the index up to date with the server.
+    pub fn sync(&self) -> Result {
+        let last_modified = self.index.read().unwrap().last_modified;
+        let res = self.api.call(
+            "getIndexes",
+            &[("ifModifiedSince", last_modified.to_string())],
+        )?;
+        let modified = res["indexes"]["lastModified"].as_u64().unwrap_or(0);
+        if modified != 0 && modified <= last_modified && !self.is_empty() {
+            debug!("library unchanged since {}", last_modified);
+            return Ok(())
+        }
+
+        info!("syncing library");
+        let mut next = Index {
+            last_modified: modified,
+            ..Index::default()
+        };
+        let res = self.api.call("getArtists", &[])?;
+        for artist in items(&res["artists"]["index"]) {
+            for a in items(&artist["artist"]) {
+                let id = match id(&a["id"]) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let albums = self.sync_artist(id, &mut next)?;
+                next.artists.push(ArtistEntry {
+                    id,
+                    name: a["name"].as_str().unwrap_or("").to_string(),
+                    albums,
+                });
+            }
+        }
+
+        info!(
+            "library has {} artists, {} albums and {} songs",
+            next.artists.len(),
+            next.albums.len(),
+            next.songs.len()
+        );
+        if let Err(e) = File::create(&self.path)
+            .map_err(Error::from)
+            .and_then(|f| Ok(serde_json::to_writer(f, &next)?))
+        {
+            warn!("unable to save library: {}", e);
+        }
+        *self.index.write().unwrap() = next;
+        Ok(())
+    }
+
+    /// Lists an artist's albums into `next`, reusing the songs of albums
+    /// that haven't changed.
+    fn sync_artist(
+        &self,
+        artist: u64,
+        next: &mut Index,
+    ) -> ::std::result::Result<Vec<u64>, Error> {
+        let res = self.api.call("getArtist", &[("id", artist.to_string())])?;
+        let mut ids = Vec::new();
+        for a in items(&res["artist"]["album"]) {
+            let id = match id(&a["id"]) {
+                Some(id) => id,
+                None => continue,
+            };
+            let name = a["name"].as_str().unwrap_or("").to_string();
+            let song_count = a["songCount"].as_u64().unwrap_or(0);
+
+            let known = {
+                let index = self.index.read().unwrap();
+                index
+                    .albums
+                    .get(&id)
+                    .filter(|old| {
+                        old.name == name && old.song_count == song_count
+                    })
+                    .map(|old| {
+                        let songs = old.songs
+                            .iter()
+                            .filter_map(|s| index.songs.get(s))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        (old.clone(), songs)
+                    })
+            };
+            let (album, songs) = match known {
+                Some(k) => k,
+                None => self.fetch_album(id)?,
+            };
+
+            for song in songs {
+                next.songs.insert(song.id, song);
+            }
+            next.albums.insert(id, album);
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+
+    fn fetch_album(
+        &self,
+        id: u64,
+    ) -> ::std::result::Result<(AlbumEntry, Vec<SongEntry>), Error> {
+        debug!("fetching album {}", id);
+        let res = self.api.call("getAlbum", &[("id", id.to_string())])?;
+        let a = &res["album"];
+        let songs = items(&a["song"])
+            .iter()
+            .filter_map(|s| {
+                Some(SongEntry {
+                    id: id(&s["id"])?,
+                    title: s["title"].as_str()?.to_string(),
+                    artist: s["artist"].as_str().map(String::from),
+                    album: s["album"].as_str().map(String::from),
+                    album_id: id(&s["albumId"]),
+                    artist_id: id(&s["artistId"]),
+                    track: s["track"].as_u64(),
+                    year: s["year"].as_u64(),
+                    duration: s["duration"].as_u64().unwrap_or(0),
+                    cover_id: s["coverArt"].as_str().map(String::from),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let album = AlbumEntry {
+            id,
+            name: a["name"].as_str().unwrap_or("").to_string(),
+            artist: a["artist"].as_str().map(String::from),
+            year: a["year"].as_u64(),
+            song_count: a["songCount"].as_u64().unwrap_or(0),
+            songs: songs.iter().map(|s| s.id).collect(),
+        };
+        Ok((album, songs))
+    }
+}
+
+/// Keeps the library in sync in the background, every `interval` seconds.
+pub fn spawn(library: Arc<Library>, interval: u64) {
+    thread::spawn(move || loop {
+        if let Err(e) = library.sync() {
+            warn!("unable to sync library: {}", e);
+        }
+        thread::sleep(Duration::from_secs(interval));
+    });
+}
+
+/// The elements of a JSON list. Servers send a lone element as an object
+/// rather than a list of one.
+pub fn items(v: &Value) -> Vec<Value> {
+    match *v {
+        Value::Array(ref a) => a.clone(),
+        Value::Null => Vec::new(),
+        ref other => vec![other.clone()],
+    }
+}
+
+/// IDs are strings in JSON responses, but numbers everywhere else.
+fn id(v: &Value) -> Option<u64> {
+    v.as_u64().or_else(|| v.as_str()?.parse().ok())
+}
+
+fn words(query: &str) -> Vec<String> {
+    query.split_whitespace().map(normalise).filter(|w| !w.is_empty()).collect()
+}
+
+/// How well a song matches the words of a query, lowest first, or `None` if
+/// it doesn't.
+fn rank(song: &SongEntry, words: &[String]) -> Option<u8> {
+    let title = normalise(&song.title);
+    let text = normalise(&format!(
+        "{} {} {}",
+        song.artist.as_ref().map_or("", |a| a),
+        song.album.as_ref().map_or("", |a| a),
+        song.title
+    ));
+    if !words.iter().all(|w| text.contains(w.as_str())) {
+        return None
+    }
+
+    let whole = |text: &str| {
+        words.iter().all(|w| text.split(' ').any(|t| t == w))
+    };
+    Some(if title == words.join(" ") {
+        0
+    } else if whole(&title) {
+        1
+    } else if whole(&text) {
+        2
+    } else {
+        3
+    })
+}
+
+/// Lower-cases, strips punctuation and collapses spacing, so that none of
+/// them get in the way of matching.
+pub fn normalise(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .flat_map(|c| c.to_lowercase())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::RwLock;
+
+    use super::*;
+
+    fn song(id: u64, artist: &str, album: &str, title: &str) -> SongEntry {
+        SongEntry {
+            id,
+            title: title.into(),
+            artist: Some(artist.into()),
+            album: Some(album.into()),
+            album_id: None,
+            artist_id: None,
+            track: Some(id),
+            year: None,
+            duration: 180,
+            cover_id: None,
+        }
+    }
+
+    fn library(songs: Vec<SongEntry>) -> Library {
+        Library {
+            api: Api::new(&Conf::default()),
+            path: PathBuf::from("/nonexistent/library.json"),
+            index: RwLock::new(Index {
+                songs: songs.into_iter().map(|s| (s.id, s)).collect(),
+                ..Index::default()
+            }),
+        }
+    }
+
+    fn ids(songs: Vec<SongEntry>) -> Vec<u64> {
+        songs.iter().map(|s| s.id).collect()
+    }
+
+    #[test]
+    fn normalise_ignores_case_punctuation_and_spacing() {
+        assert_eq!(normalise("  Don't  Stop\tMe Now! "), "dont stop me now");
+        assert_eq!(normalise("AC/DC"), "acdc");
+        assert_eq!(normalise("Sigur Rós"), "sigur rós");
+        assert_eq!(normalise("..."), "");
+    }
+
+    #[test]
+    fn search_ranks_title_matches_first() {
+        let lib = library(vec![
+            song(1, "Love", "Forever Changes", "Alone Again Or"),
+            song(2, "The Beatles", "Love", "Help!"),
+            song(3, "Queen", "Jazz", "Don't Stop Me Now"),
+            song(4, "Joy Division", "Closer", "Love Will Tear Us Apart"),
+            song(5, "Nirvana", "Nevermind", "Lovebuzz"),
+            song(6, "Whitney Houston", "Whitney", "Love"),
+        ]);
+
+        assert_eq!(ids(lib.search_songs("love", 10)), vec![6, 4, 1, 2, 5]);
+        assert_eq!(ids(lib.search_songs("love", 2)), vec![6, 4]);
+        assert_eq!(ids(lib.search_songs("dont stop", 10)), vec![3]);
+        assert_eq!(ids(lib.search_songs("queen help", 10)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn rank_needs_every_word() {
+        let s = song(1, "Queen", "Jazz", "Don't Stop Me Now");
+        assert_eq!(rank(&s, &words("don't stop me now")), Some(0));
+        assert_eq!(rank(&s, &words("stop now")), Some(1));
+        assert_eq!(rank(&s, &words("queen now")), Some(2));
+        assert_eq!(rank(&s, &words("que sto")), Some(3));
+        assert_eq!(rank(&s, &words("queen later")), None);
+    }
+}
@@ -0,0 +1,250 @@
+//! Fetching and caching lyrics.
+//!
+//! Servers implementing OpenSubsonic's `getLyricsBySongId` can give lyrics
+//! synced to the song, so that's tried first. Anything else falls back to the
+//! plain `getLyrics`, which matches by artist and title.
+
+use serde_json;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use api::Api;
+use conf::Conf;
+use error::Error;
+use player::Track;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Lyrics {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    /// Whether every line has a start time.
+    pub synced: bool,
+    /// Empty if no lyrics were found.
+    pub lines: Vec<Line>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Line {
+    /// Milliseconds into the song.
+    pub start: Option<u64>,
+    pub text: String,
+}
+
+impl fmt::Display for Lyrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets lyrics from the Subsonic server, keeping them in the cache directory.
+pub struct Fetcher {
+    api: Api,
+    dir: PathBuf,
+}
+
+impl Fetcher {
+    pub fn new(cfg: &Conf) -> Fetcher {
+        let dir = cfg.cache.join("lyrics");
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("unable to create {}: {}", dir.display(), e);
+        }
+
+        Fetcher {
+            api: Api::new(cfg),
+            dir,
+        }
+    }
+
+    pub fn get(&self, song: &Track) -> ::std::result::Result<Lyrics, Error> {
+        let path = self.dir.join(format!("{}.json", song.id));
+        if let Some(lyrics) = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+        {
+            return Ok(lyrics)
+        }
+
+        let lines = match self.by_id(song.id) {
+            Ok(Some(lines)) => lines,
+            Ok(None) => self.by_name(song)?,
+            Err(e) => {
+                debug!("getLyricsBySongId failed, trying getLyrics: {}", e);
+                self.by_name(song)?
+            }
+        };
+        let synced =
+            !lines.is_empty() && lines.iter().all(|l| l.start.is_some());
+        let lyrics = Lyrics {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            synced,
+            lines,
+        };
+
+        // Don't cache a miss; the server might have them later.
+        if !lyrics.lines.is_empty() {
+            if let Err(e) = File::create(&path)
+                .map_err(Error::from)
+                .and_then(|f| Ok(serde_json::to_writer(f, &lyrics)?))
+            {
+                warn!("unable to cache lyrics: {}", e);
+            }
+        }
+        Ok(lyrics)
+    }
+
+    /// Asks for structured lyrics, preferring synced ones. `None` means the
+    /// server had none.
+    fn by_id(
+        &self,
+        id: u64,
+    ) -> ::std::result::Result<Option<Vec<Line>>, Error> {
+        let params = [("id", id.to_string())];
+        let res = self.api.call("getLyricsBySongId", &params)?;
+        let list = match res["lyricsList"]["structuredLyrics"].as_array() {
+            Some(l) if !l.is_empty() => l.clone(),
+            _ => return Ok(None),
+        };
+        let best = list.iter()
+            .find(|l| l["synced"].as_bool() == Some(true))
+            .unwrap_or(&list[0]);
+        let synced = best["synced"].as_bool() == Some(true);
+
+        Ok(Some(
+            best["line"]
+                .as_array()
+                .map(|lines| {
+                    lines
+                        .iter()
+                        .map(|l| Line {
+                            start: l["start"].as_u64().filter(|_| synced),
+                            text: l["value"].as_str().unwrap_or("").to_string(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ))
+    }
+
+    fn by_name(
+        &self,
+        song: &Track,
+    ) -> ::std::result::Result<Vec<Line>, Error> {
+        let mut params = vec![("title", song.title.clone())];
+        if let Some(ref artist) = song.artist {
+            params.push(("artist", artist.clone()));
+        }
+        let res = self.api.call("getLyrics", &params)?;
+        Ok(res["lyrics"]["value"]
+            .as_str()
+            .unwrap_or("")
+            .lines()
+            .map(|l| Line {
+                start: None,
+                text: l.trim_end().to_string(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::Fetcher;
+    use conf::Conf;
+    use player::Track;
+    use testhttp::serve;
+
+    const SYNCED: &str = r#"{"subsonic-response": {"status": "ok",
+        "lyricsList": {"structuredLyrics": [
+            {"synced": false, "line": [{"value": "Plain"}]},
+            {"synced": true, "line": [
+                {"start": 0, "value": "First"},
+                {"start": 2500, "value": "Second"}
+            ]}
+        ]}}}"#;
+    const UNSUPPORTED: &str = r#"{"subsonic-response": {"status": "failed",
+        "error": {"code": 70, "message": "Not found"}}}"#;
+    const PLAIN: &str = r#"{"subsonic-response": {"status": "ok",
+        "lyrics": {"value": "One  \nTwo"}}}"#;
+    const NONE: &str = r#"{"subsonic-response": {"status": "ok",
+        "lyricsList": {"structuredLyrics": []}, "lyrics": {}}}"#;
+
+    fn track() -> Track {
+        Track {
+            id: 8,
+            title: "Windowlicker".into(),
+            artist: Some("Aphex Twin".into()),
+            album: None,
+            duration: 367,
+            cover_url: None,
+            cover_path: None,
+            location: None,
+        }
+    }
+
+    fn fetcher(name: &str, url: String) -> (Fetcher, PathBuf) {
+        let cache = env::temp_dir()
+            .join(format!("subs-test-lyrics-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&cache);
+        let cfg = Conf {
+            cache: cache.clone(),
+            url,
+            ..Conf::default()
+        };
+        (Fetcher::new(&cfg), cache)
+    }
+
+    #[test]
+    fn prefers_synced_lyrics_and_caches_them() {
+        let (url, requests) = serve(vec![(200, SYNCED)]);
+        let (fetcher, cache) = fetcher("synced", url);
+
+        let lyrics = fetcher.get(&track()).unwrap();
+        assert!(lyrics.synced);
+        assert_eq!(lyrics.lines[1].start, Some(2500));
+        assert_eq!(lyrics.to_string(), "First\nSecond\n");
+        let (head, _) = requests.recv().unwrap();
+        assert!(head.starts_with("GET /rest/getLyricsBySongId?"));
+
+        // The server only answers once, so this has to come from the cache.
+        assert_eq!(fetcher.get(&track()).unwrap().lines.len(), 2);
+        assert!(cache.join("lyrics/8.json").exists());
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_plain_lyrics() {
+        let (url, requests) = serve(vec![(200, UNSUPPORTED), (200, PLAIN)]);
+        let (fetcher, cache) = fetcher("plain", url);
+
+        let lyrics = fetcher.get(&track()).unwrap();
+        assert!(!lyrics.synced);
+        assert_eq!(lyrics.to_string(), "One\nTwo\n");
+        requests.recv().unwrap();
+        let (head, _) = requests.recv().unwrap();
+        assert!(head.starts_with("GET /rest/getLyrics?"));
+        assert!(head.contains("title=Windowlicker&artist=Aphex%20Twin"));
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn does_not_cache_a_miss() {
+        let (url, _) = serve(vec![(200, NONE), (200, NONE), (200, SYNCED)]);
+        let (fetcher, cache) = fetcher("miss", url);
+
+        assert!(fetcher.get(&track()).unwrap().lines.is_empty());
+        assert!(fetcher.get(&track()).unwrap().synced);
+        fs::remove_dir_all(&cache).unwrap();
+    }
+}
@@ -3,29 +3,61 @@ extern crate config;
 extern crate dirs;
 extern crate crossbeam_channel;
 extern crate ctrlc;
+extern crate dbus;
 #[macro_use]
 extern crate failure;
 extern crate fern;
 extern crate gstreamer as gst;
 #[macro_use]
 extern crate log;
+extern crate md5;
+extern crate quick_xml;
+extern crate rand;
+extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate sunk;
+extern crate termion;
+extern crate tiny_http;
 extern crate unix_socket;
 
+mod api;
+mod cache;
 mod cli;
 mod error;
 mod subcmd;
 mod conf;
+mod cover;
 mod daemon;
+mod download;
+mod event;
+mod hooks;
+mod http;
+mod info;
+mod library;
+mod lyrics;
+mod mpd;
+mod mpris;
+mod notify;
+mod playlist_file;
+mod quality;
 mod queue;
+mod radio;
+mod remote;
+mod scrobble;
+mod statusbar;
 mod player;
+mod tui;
+#[cfg(test)]
+mod testbus;
+#[cfg(test)]
+mod testhttp;
 
 use structopt::StructOpt;
 
@@ -37,39 +69,73 @@ fn main() {
     }
 
     use cli::AppCommand::*;
+    // Completions are generated when packaging, where there's no config.
+    if let Completions { shell } = app.cmd {
+        cli::App::clap().gen_completions_to(
+            "subs",
+            shell,
+            &mut ::std::io::stdout(),
+        );
+        return
+    }
+
+    let mut cfg = match conf::Conf::new() {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("{}", err);
+            ::std::process::exit(1);
+        }
+    };
+    if app.host.is_some() {
+        cfg.host = app.host;
+    }
+
     if let Err(err) = match app.cmd {
-        Add { query } => subcmd::add(collapse(query)),
-        AddNext { query } => subcmd::addnext(collapse(query)),
-        Clear => subcmd::clear(),
-        Load { name } => subcmd::load(name),
-        Next => subcmd::next(),
-        Pause => subcmd::pause(),
-        Play => subcmd::play(),
-        Prev => subcmd::prev(),
-        Random { number } => subcmd::random(number),
-        Search { .. } => subcmd::search(app.cmd),
-        Status => subcmd::status(),
-        Toggle => subcmd::toggle(),
+        Add { query } => subcmd::add(&cfg, query),
+        AddNext { query } => subcmd::addnext(&cfg, query),
+        Clear => subcmd::clear(&cfg),
+        Load { name } => subcmd::load(&cfg, name),
+        Next => subcmd::next(&cfg),
+        Pause => subcmd::pause(&cfg),
+        Play => subcmd::play(&cfg),
+        Prev => subcmd::prev(&cfg),
+        Random { number } => subcmd::random(&cfg, number),
+        Search { .. } => subcmd::search(&cfg, app.cmd),
+        Status => subcmd::status(&cfg),
+        Statusbar { format, interval } => {
+            statusbar::run(&cfg, format, interval)
+        }
+        Tui => tui::run(cfg),
+        Batch { stop_on_error } => subcmd::batch(&cfg, stop_on_error),
+        Star { .. } | Unstar { .. } | Rate { .. } => {
+            subcmd::annotate(&cfg, app.cmd)
+        }
+        Toggle => subcmd::toggle(&cfg),
+        Info { .. } => subcmd::info(&cfg, app.cmd),
+        Lyrics { follow } => subcmd::lyrics(&cfg, follow),
+        Cover { out } => subcmd::cover(&cfg, out),
+        Update { wait } => subcmd::update(&cfg, wait),
+        Playlist { cmd } => subcmd::playlist(&cfg, cmd),
+        Cache { cmd } => subcmd::cache(&cfg, cmd),
+        Radio { cmd } => subcmd::radio(&cfg, cmd),
+        Quality { setting, format } => subcmd::quality(&cfg, setting, format),
+        Download { .. } => subcmd::download(&cfg, app.cmd),
+        Save { .. } => subcmd::save(&cfg, app.cmd),
+        Export { file, urls } => subcmd::export(&cfg, file, urls),
+        Import { file } => subcmd::import(&cfg, file),
         List { cmd } => {
             use cli::ListCommand::*;
             match cmd {
-                Playlist => subcmd::ls_playlists(),
-                Artist { number } => subcmd::ls_artists(number),
+                Playlist => subcmd::ls_playlists(&cfg),
+                Artist { number } => subcmd::ls_artists(&cfg, number),
+                Starred { add } => subcmd::ls_starred(&cfg, add),
             }
         }
-        Completions { shell } => {
-            cli::App::clap().gen_completions_to(
-                "subs",
-                shell,
-                &mut ::std::io::stdout(),
-            );
-            Ok(())
-        }
         Daemon { cmd } => {
             use cli::DaemonCommand::*;
             match cmd {
-                Start => daemon::cmd_start(),
-                Stop => daemon::cmd_stop(),
+                Start => daemon::cmd_start(cfg),
+                Stop => daemon::cmd_stop(&cfg),
                 Restart => daemon::cmd_restart(),
             }
         }
@@ -100,10 +166,10 @@ fn init_logging(v: u64) -> Result<(), ::log::SetLoggerError> {
     base.chain(std::io::stdout()).apply()
 }
 
+/// Joins words split up by the shell back into a single query.
 pub fn collapse<T>(v: Vec<T>) -> String
 where
     T: Into<String>,
 {
-    v.into_iter()
-        .fold(String::new(), |a, s| a + " " + &s.into())
+    v.into_iter().map(Into::into).collect::<Vec<String>>().join(" ")
 }
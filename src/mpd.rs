@@ -0,0 +1,676 @@
+//! Enough of the MPD protocol for MPD clients to drive the daemon.
+//!
+//! Each client connection gets its own thread. Commands are translated into
+//! daemon commands and sent over the daemon's own socket, so they're handled
+//! in order with every other client.
+
+use crossbeam_channel::{unbounded, Receiver};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use daemon::{self, Command, Reply};
+use error::Result;
+use event::{Bus, Event};
+use player::{State, Track};
+
+const GREETING: &str = "OK MPD 0.19.0\n";
+
+/// Commands understood by the server, as reported by `commands`.
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "find",
+    "idle",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "previous",
+    "search",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "status",
+    "stop",
+    "tagtypes",
+];
+
+const ACK_ERROR_ARG: u8 = 2;
+const ACK_ERROR_UNKNOWN: u8 = 5;
+const ACK_ERROR_NO_EXIST: u8 = 50;
+const ACK_ERROR_SYSTEM: u8 = 52;
+
+/// Number of results returned for `search` and `find`.
+const SEARCH_SIZE: usize = 100;
+
+/// Starts listening for MPD clients on `addr` in the background.
+pub fn spawn(addr: &str, socket: &Path, bus: Bus) -> Result {
+    let listener = TcpListener::bind(addr)?;
+    info!("MPD server listening on {}", addr);
+
+    let socket = socket.to_path_buf();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    let socket = socket.clone();
+                    let bus = bus.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = Session::new(s, socket).run(&bus) {
+                            debug!("MPD client disconnected: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("unable to accept MPD client: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+enum Input {
+    Line(String),
+    Event(Event),
+    Closed,
+}
+
+struct Ack {
+    code: u8,
+    msg: String,
+}
+
+impl Ack {
+    fn new<S: Into<String>>(code: u8, msg: S) -> Ack {
+        Ack {
+            code,
+            msg: msg.into(),
+        }
+    }
+}
+
+struct Session {
+    stream: TcpStream,
+    socket: PathBuf,
+    /// Bumped whenever the queue changes, so clients know to refetch it.
+    playlist_version: u32,
+    /// Subsystems that changed since the client last went idle.
+    changed: Vec<&'static str>,
+    /// Subsystems the client is idling on, if it's idle.
+    idle: Option<Vec<String>>,
+}
+
+impl Session {
+    fn new(stream: TcpStream, socket: PathBuf) -> Session {
+        Session {
+            stream,
+            socket,
+            playlist_version: 0,
+            changed: Vec::new(),
+            idle: None,
+        }
+    }
+
+    fn run(&mut self, bus: &Bus) -> Result {
+        self.stream.write_all(GREETING.as_bytes())?;
+        let input = self.listen(bus)?;
+
+        // Commands between `command_list_begin` and `command_list_end`, and
+        // whether each should be acknowledged with `list_OK`.
+        let mut list: Option<(Vec<Vec<String>>, bool)> = None;
+
+        loop {
+            let line = match input.recv() {
+                Ok(Input::Line(l)) => l,
+                Ok(Input::Event(ev)) => {
+                    self.changed(&ev);
+                    self.flush_idle()?;
+                    continue
+                }
+                Ok(Input::Closed) | Err(_) => return Ok(()),
+            };
+            debug!("MPD client sent {:?}", line);
+
+            let args = match tokenize(&line) {
+                Ok(args) => args,
+                Err(ack) => {
+                    self.write_ack(&ack, 0, "")?;
+                    continue
+                }
+            };
+            let name = match args.get(0) {
+                Some(name) => name.clone(),
+                None => continue,
+            };
+
+            if self.idle.is_some() {
+                // Anything other than `noidle` is a protocol error while
+                // idle; MPD drops the client.
+                if name != "noidle" {
+                    return Ok(())
+                }
+                self.idle = None;
+                self.stream.write_all(b"OK\n")?;
+                continue
+            }
+
+            match name.as_str() {
+                "close" => return Ok(()),
+                "noidle" => (),
+                "idle" => {
+                    self.idle = Some(args[1..].to_vec());
+                    self.flush_idle()?;
+                }
+                "command_list_begin" => list = Some((Vec::new(), false)),
+                "command_list_ok_begin" => list = Some((Vec::new(), true)),
+                "command_list_end" => match list.take() {
+                    Some((cmds, list_ok)) => self.execute(&cmds, list_ok)?,
+                    None => self.write_ack(
+                        &Ack::new(ACK_ERROR_ARG, "not in command list"),
+                        0,
+                        &name,
+                    )?,
+                },
+                _ => match list {
+                    Some((ref mut cmds, _)) => cmds.push(args),
+                    None => self.execute(&[args], false)?,
+                },
+            }
+        }
+    }
+
+    /// Funnels lines from the client and events from the player into a
+    /// single channel.
+    fn listen(&self, bus: &Bus) -> io::Result<Receiver<Input>> {
+        let (send, recv) = unbounded();
+
+        let reader = BufReader::new(self.stream.try_clone()?);
+        let lines = send.clone();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                match line {
+                    Ok(l) => if lines.send(Input::Line(l)).is_err() {
+                        return
+                    },
+                    Err(_) => break,
+                }
+            }
+            let _ = lines.send(Input::Closed);
+        });
+
+        let events = bus.subscribe();
+        thread::spawn(move || {
+            while let Ok(ev) = events.recv() {
+                if send.send(Input::Event(ev)).is_err() {
+                    break
+                }
+            }
+        });
+
+        Ok(recv)
+    }
+
+    fn changed(&mut self, ev: &Event) {
+        let subsystem = match *ev {
+            Event::TrackStart(_)
+            | Event::StreamStart
+            | Event::Tags
+            | Event::TrackEnd(_)
+            | Event::QueueEmpty
+            | Event::Pause
+            | Event::Resume
+            | Event::Seek(_) => "player",
+            Event::Queue => {
+                self.playlist_version += 1;
+                "playlist"
+            }
+            Event::Volume(_) => "mixer",
+            Event::Error(_) => return,
+        };
+
+        if !self.changed.contains(&subsystem) {
+            self.changed.push(subsystem);
+        }
+    }
+
+    /// Tells an idle client about any subsystems it's waiting on that have
+    /// changed.
+    fn flush_idle(&mut self) -> io::Result<()> {
+        let wanted = match self.idle {
+            Some(ref wanted) => wanted.clone(),
+            None => return Ok(()),
+        };
+
+        let (report, keep): (Vec<&'static str>, Vec<&'static str>) =
+            self.changed.iter().cloned().partition(|s| {
+                wanted.is_empty() || wanted.iter().any(|w| w.as_str() == *s)
+            });
+        if report.is_empty() {
+            return Ok(())
+        }
+
+        let mut out = String::new();
+        for subsystem in report {
+            writeln!(out, "changed: {}", subsystem).unwrap();
+        }
+        out.push_str("OK\n");
+
+        self.changed = keep;
+        self.idle = None;
+        self.stream.write_all(out.as_bytes())
+    }
+
+    /// Runs a list of commands as a single daemon batch, stopping at the
+    /// first one that fails.
+    fn execute(&mut self, cmds: &[Vec<String>], list_ok: bool) -> Result {
+        let mut translated = Vec::with_capacity(cmds.len());
+        let mut failed = None;
+        for (i, args) in cmds.iter().enumerate() {
+            match translate(args) {
+                Ok(cmd) => translated.push(cmd),
+                Err(ack) => {
+                    failed = Some((i, ack));
+                    break
+                }
+            }
+        }
+
+        let batch = translated.iter().filter_map(|c| c.clone()).collect();
+        let mut replies = match daemon::send_recv_to(
+            &self.socket,
+            Command::Batch(batch, true),
+        ) {
+            Ok(Reply::Batch(r)) => r.into_iter(),
+            Ok(_) => unreachable!(),
+            Err(e) => {
+                let ack = Ack::new(ACK_ERROR_SYSTEM, e.to_string());
+                return Ok(self.write_ack(&ack, 0, &cmds[0][0])?)
+            }
+        };
+
+        let mut out = String::new();
+        for (i, cmd) in translated.iter().enumerate() {
+            let args = &cmds[i];
+            let reply = match *cmd {
+                Some(_) => replies.next().unwrap_or(Reply::None),
+                None => Reply::None,
+            };
+
+            if let Err(ack) = self.format(args, reply, &mut out) {
+                failed = Some((i, ack));
+                break
+            }
+            if list_ok {
+                out.push_str("list_OK\n");
+            }
+        }
+
+        self.stream.write_all(out.as_bytes())?;
+        match failed {
+            Some((i, ack)) => self.write_ack(&ack, i, &cmds[i][0])?,
+            None => self.stream.write_all(b"OK\n")?,
+        }
+
+        Ok(())
+    }
+
+    /// Writes the response to a single command.
+    fn format(
+        &self,
+        args: &[String],
+        reply: Reply,
+        out: &mut String,
+    ) -> ::std::result::Result<(), Ack> {
+        if let Reply::Error(e) = reply {
+            return Err(Ack::new(ACK_ERROR_SYSTEM, e))
+        }
+
+        match args[0].as_str() {
+            "status" => if let Reply::Status(st) = reply {
+                writeln!(out, "volume: {}", st.volume).unwrap();
+                out.push_str("repeat: 0\nrandom: 0\nsingle: 0\nconsume: 0\n");
+                writeln!(out, "playlist: {}", self.playlist_version).unwrap();
+                writeln!(out, "playlistlength: {}", st.len).unwrap();
+                writeln!(
+                    out,
+                    "state: {}",
+                    match st.state {
+                        State::Playing => "play",
+                        State::Paused => "pause",
+                        State::Stopped => "stop",
+                    }
+                ).unwrap();
+                if let Some(track) = st.track {
+                    writeln!(out, "song: {}", st.position).unwrap();
+                    writeln!(out, "songid: {}", st.position).unwrap();
+                    writeln!(out, "time: {}:{}", st.elapsed, track.duration)
+                        .unwrap();
+                    writeln!(out, "elapsed: {}.000", st.elapsed).unwrap();
+                    writeln!(out, "duration: {}.000", track.duration)
+                        .unwrap();
+                }
+            },
+            "currentsong" => if let Reply::Status(st) = reply {
+                if let Some(track) = st.track {
+                    write_track(out, &track, Some(st.position));
+                }
+            },
+            "playlistinfo" | "playlistid" => {
+                if let Reply::Songs(songs) = reply {
+                    for (i, track) in songs.iter().enumerate() {
+                        write_track(out, track, Some(i));
+                    }
+                }
+            }
+            "search" | "find" => if let Reply::Songs(songs) = reply {
+                for track in &songs {
+                    write_track(out, track, None);
+                }
+            },
+            "add" | "addid" => {
+                if let Reply::Other(ref r) = reply {
+                    if r.starts_with("Nothing") {
+                        return Err(Ack::new(ACK_ERROR_NO_EXIST, r.as_str()))
+                    }
+                }
+                if let Reply::Batch(ref replies) = reply {
+                    if let Some(&Reply::Status(ref st)) = replies.last() {
+                        let id = st.len.saturating_sub(1);
+                        writeln!(out, "Id: {}", id).unwrap();
+                    }
+                }
+            }
+            "commands" => for cmd in COMMANDS {
+                writeln!(out, "command: {}", cmd).unwrap();
+            },
+            "tagtypes" => for tag in &["Artist", "Album", "Title"] {
+                writeln!(out, "tagtype: {}", tag).unwrap();
+            },
+            "outputs" => out.push_str(
+                "outputid: 0\noutputname: subs\noutputenabled: 1\n",
+            ),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn write_ack(
+        &mut self,
+        ack: &Ack,
+        index: usize,
+        cmd: &str,
+    ) -> io::Result<()> {
+        let line =
+            format!("ACK [{}@{}] {{{}}} {}\n", ack.code, index, cmd, ack.msg);
+        self.stream.write_all(line.as_bytes())
+    }
+}
+
+/// Works out the daemon command for an MPD command. Commands that only need
+/// a local answer translate to `None`.
+fn translate(args: &[String]) -> ::std::result::Result<Option<Command>, Ack> {
+    fn arg(args: &[String], n: usize) -> ::std::result::Result<&str, Ack> {
+        args.get(n)
+            .map(|s| s.as_str())
+            .ok_or_else(|| Ack::new(ACK_ERROR_ARG, "missing argument"))
+    }
+
+    fn time(s: &str) -> ::std::result::Result<u64, Ack> {
+        s.parse::<f64>()
+            .ok()
+            .filter(|t| *t >= 0.)
+            .map(|t| t as u64)
+            .ok_or_else(|| Ack::new(ACK_ERROR_ARG, "invalid time"))
+    }
+
+    /// A queue position, which also serves as the song's ID since a song
+    /// can be in the queue more than once.
+    fn pos(s: &str) -> ::std::result::Result<usize, Ack> {
+        s.parse()
+            .map_err(|_| Ack::new(ACK_ERROR_ARG, "invalid song position"))
+    }
+
+    use daemon::Command::*;
+    Ok(Some(match args[0].as_str() {
+        "status" | "currentsong" => StatusReq,
+        "playlistinfo" | "playlistid" => QueueReq,
+        "play" => match args.get(1) {
+            Some(p) => PlayAt(pos(p)?),
+            None => Play,
+        },
+        "playid" => PlayAt(pos(arg(args, 1)?)?),
+        // There's no stopped state short of emptying the queue, so stopping
+        // pauses where it is.
+        "stop" => Pause,
+        "next" => Next,
+        "previous" => Prev,
+        "clear" => Clear,
+        "pause" => match args.get(1).map(|s| s.as_str()) {
+            Some("1") => Pause,
+            Some("0") => Play,
+            Some(_) => return Err(Ack::new(ACK_ERROR_ARG, "expected 0 or 1")),
+            None => Toggle,
+        },
+        "add" => {
+            let uri = arg(args, 1)?;
+            match uri.parse() {
+                Ok(id) => Add(id),
+                Err(_) => AddSearch(uri.into()),
+            }
+        }
+        // The status afterwards gives the new entry's position, to send
+        // back as its ID.
+        "addid" => match arg(args, 1)?.parse() {
+            Ok(id) => Batch(vec![Add(id), StatusReq], true),
+            Err(_) => {
+                return Err(Ack::new(ACK_ERROR_NO_EXIST, "no such song"))
+            }
+        },
+        "search" | "find" => {
+            // Arguments come in tag/value pairs; Subsonic only does
+            // free-text search, so the values are searched together.
+            let query = args[1..]
+                .chunks(2)
+                .filter_map(|pair| pair.get(1))
+                .cloned()
+                .collect::<Vec<_>>();
+            if query.is_empty() {
+                return Err(Ack::new(ACK_ERROR_ARG, "missing search query"))
+            }
+            SearchSongs(query.join(" "), SEARCH_SIZE)
+        }
+        "setvol" => match arg(args, 1)?.parse::<u8>() {
+            Ok(v) if v <= 100 => SetVolume(v),
+            _ => return Err(Ack::new(ACK_ERROR_ARG, "invalid volume")),
+        },
+        "seek" | "seekid" => Seek(time(arg(args, 2)?)?),
+        "seekcur" => {
+            let t = arg(args, 1)?;
+            if t.starts_with('+') || t.starts_with('-') {
+                return Err(Ack::new(
+                    ACK_ERROR_ARG,
+                    "relative seeking is not supported",
+                ))
+            }
+            Seek(time(t)?)
+        }
+        "ping" | "commands" | "notcommands" | "tagtypes" | "outputs" => {
+            return Ok(None)
+        }
+        cmd => {
+            return Err(Ack::new(
+                ACK_ERROR_UNKNOWN,
+                format!("unknown command \"{}\"", cmd),
+            ))
+        }
+    }))
+}
+
+fn write_track(out: &mut String, track: &Track, pos: Option<usize>) {
+    writeln!(out, "file: {}", track.id).unwrap();
+    writeln!(out, "Title: {}", track.title).unwrap();
+    if let Some(ref artist) = track.artist {
+        writeln!(out, "Artist: {}", artist).unwrap();
+    }
+    if let Some(ref album) = track.album {
+        writeln!(out, "Album: {}", album).unwrap();
+    }
+    writeln!(out, "Time: {}", track.duration).unwrap();
+    writeln!(out, "duration: {}.000", track.duration).unwrap();
+    if let Some(pos) = pos {
+        writeln!(out, "Pos: {}", pos).unwrap();
+        writeln!(out, "Id: {}", pos).unwrap();
+    }
+}
+
+/// Splits a command line into its arguments, handling double quotes and
+/// backslash escapes.
+fn tokenize(line: &str) -> ::std::result::Result<Vec<String>, Ack> {
+    let mut args = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue
+        }
+
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => arg.push(c),
+                        None => break,
+                    },
+                    Some(c) => arg.push(c),
+                    None => {
+                        let msg = "missing closing quote";
+                        return Err(Ack::new(ACK_ERROR_ARG, msg))
+                    }
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use daemon::Command::*;
+
+    fn cmd(line: &str) -> Option<Command> {
+        translate(&tokenize(line).ok().unwrap()).ok().unwrap()
+    }
+
+    /// The error for a line, as it's written back to the client.
+    fn ack(line: &str) -> String {
+        match translate(&tokenize(line).ok().unwrap()) {
+            Ok(cmd) => panic!("expected an error, got {:?}", cmd),
+            Err(ack) => format!("ACK {} {}", ack.code, ack.msg),
+        }
+    }
+
+    #[test]
+    fn tokenizes_quotes_and_escapes() {
+        let args = tokenize(r#"  find artist "Guns \"N\" Roses"  album x "#);
+        assert_eq!(
+            args.ok().unwrap(),
+            vec!["find", "artist", "Guns \"N\" Roses", "album", "x"]
+        );
+        assert!(tokenize(r#"add "unclosed"#).is_err());
+        assert!(tokenize("   ").ok().unwrap().is_empty());
+    }
+
+    #[test]
+    fn translates_commands() {
+        assert_eq!(cmd("pause"), Some(Toggle));
+        assert_eq!(cmd("pause 1"), Some(Pause));
+        assert_eq!(cmd("pause 0"), Some(Play));
+        assert_eq!(cmd("add 42"), Some(Add(42)));
+        assert_eq!(
+            cmd("add \"The Wall\""),
+            Some(AddSearch("The Wall".into()))
+        );
+        assert_eq!(
+            cmd("search artist Pink title Time"),
+            Some(SearchSongs("Pink Time".into(), 100))
+        );
+        assert_eq!(cmd("seekcur 61.5"), Some(Seek(61)));
+        assert_eq!(cmd("play"), Some(Play));
+        assert_eq!(cmd("play 3"), Some(PlayAt(3)));
+        assert_eq!(cmd("playid 0"), Some(PlayAt(0)));
+        assert_eq!(
+            cmd("addid 42"),
+            Some(Batch(vec![Add(42), StatusReq], true))
+        );
+        assert_eq!(cmd("ping"), None);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(ack("setvol 101"), "ACK 2 invalid volume");
+        assert_eq!(
+            ack("seekcur +5"),
+            "ACK 2 relative seeking is not supported"
+        );
+        assert_eq!(ack("seek 0"), "ACK 2 missing argument");
+        assert_eq!(ack("addid abc"), "ACK 50 no such song");
+        assert_eq!(ack("play next"), "ACK 2 invalid song position");
+        assert_eq!(ack("playid"), "ACK 2 missing argument");
+        assert_eq!(ack("search"), "ACK 2 missing search query");
+        assert_eq!(ack("crossfade 3"), "ACK 5 unknown command \"crossfade\"");
+    }
+
+    #[test]
+    fn writes_tracks() {
+        let track = Track {
+            id: 9,
+            title: "Time".into(),
+            artist: Some("Pink Floyd".into()),
+            album: None,
+            duration: 413,
+            cover_url: None,
+            cover_path: None,
+            location: None,
+        };
+        let mut out = String::new();
+        write_track(&mut out, &track, Some(2));
+        assert_eq!(
+            out,
+            "file: 9\nTitle: Time\nArtist: Pink Floyd\nTime: 413\n\
+             duration: 413.000\nPos: 2\nId: 2\n"
+        );
+    }
+}
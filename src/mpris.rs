@@ -0,0 +1,421 @@
+//! An MPRIS2 service on the D-Bus session bus, so media keys, desktop
+//! widgets and `playerctl` can control the daemon.
+//!
+//! The session bus is found through `DBUS_SESSION_BUS_ADDRESS`, so pointing
+//! that at a private `dbus-daemon` keeps the service off the desktop's bus.
+//!
+//! Cover art is only offered once it's in the daemon's cache, as a `file://`
+//! URL; the server's own cover URLs carry the user's credentials.
+
+use crossbeam_channel::Receiver;
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::tree::{Access, Factory, MTFn, Method, MethodErr};
+use dbus::{BusType, Connection, Message, NameFlag, SignalArgs};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use daemon::{self, Command, Reply};
+use event::{Bus, Event};
+use player::{self, State, Status};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.subs";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_IFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_IFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+type Metadata = HashMap<String, Variant<Box<RefArg>>>;
+
+/// Starts the MPRIS service in the background.
+pub fn spawn(socket: &Path, bus: Bus) {
+    let remote = Arc::new(Remote {
+        socket: socket.to_path_buf(),
+    });
+    let events = bus.subscribe();
+
+    thread::spawn(move || {
+        match Connection::get_private(BusType::Session) {
+            Ok(conn) => serve(&conn, &remote, &events),
+            Err(e) => error!("unable to connect to session bus: {}", e),
+        }
+    });
+}
+
+/// Offers the service on the connection, sending signals as the player's
+/// events arrive.
+fn serve(conn: &Connection, remote: &Arc<Remote>, events: &Receiver<Event>) {
+    if let Err(e) =
+        conn.register_name(BUS_NAME, NameFlag::ReplaceExisting as u32)
+    {
+        return error!("unable to register {}: {}", BUS_NAME, e)
+    }
+
+    let tree = tree(remote);
+    if let Err(e) = tree.set_registered(conn, true) {
+        return error!("unable to register MPRIS object: {}", e)
+    }
+    conn.add_handler(tree);
+    info!("MPRIS service registered as {}", BUS_NAME);
+
+    loop {
+        for _ in conn.incoming(250) {}
+
+        while let Ok(ev) = events.try_recv() {
+            if let Some(msg) = signal(remote, &ev) {
+                if conn.send(msg).is_err() {
+                    error!("unable to send MPRIS signal for {:?}", ev);
+                }
+            }
+        }
+    }
+}
+
+/// Talks to the daemon over its socket.
+struct Remote {
+    socket: PathBuf,
+}
+
+impl Remote {
+    fn send(&self, cmd: Command) -> Result<Reply, MethodErr> {
+        daemon::send_recv_to(&self.socket, cmd)
+            .map_err(|e| MethodErr::failed(&e))
+    }
+
+    fn status(&self) -> Result<Status, MethodErr> {
+        match self.send(Command::StatusReq)? {
+            Reply::Status(st) => Ok(st),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn tree(remote: &Arc<Remote>) -> ::dbus::tree::Tree<MTFn<()>, ()> {
+    let f = Factory::new_fn::<()>();
+
+    let root = f.interface(ROOT_IFACE, ())
+        .add_m(f.method("Raise", (), |m| Ok(vec![m.msg.method_return()])))
+        .add_m(f.method("Quit", (), |m| Ok(vec![m.msg.method_return()])))
+        .add_p(constant(&f, "CanQuit", false))
+        .add_p(constant(&f, "CanRaise", false))
+        .add_p(constant(&f, "HasTrackList", false))
+        .add_p(constant(&f, "Identity", "subs"))
+        .add_p(constant(&f, "SupportedUriSchemes", Vec::<String>::new()))
+        .add_p(constant(&f, "SupportedMimeTypes", Vec::<String>::new()));
+
+    let r = remote.clone();
+    let seek = f.method("Seek", (), move |m| {
+        let offset: i64 = m.msg.read1()?;
+        let st = r.status()?;
+        if let Some(track) = st.track {
+            let to = st.elapsed as i64 + offset / 1_000_000;
+            // Seeking past the end skips to the next song.
+            r.send(if to < 0 {
+                Command::Seek(0)
+            } else if to as u64 > track.duration {
+                Command::Next
+            } else {
+                Command::Seek(to as u64)
+            })?;
+        }
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<i64, _>("Offset");
+
+    let r = remote.clone();
+    let set_position = f.method("SetPosition", (), move |m| {
+        let (_, pos): (::dbus::Path, i64) = m.msg.read2()?;
+        if pos >= 0 {
+            r.send(Command::Seek(pos as u64 / 1_000_000))?;
+        }
+        Ok(vec![m.msg.method_return()])
+    }).inarg::<::dbus::Path, _>("TrackId")
+        .inarg::<i64, _>("Position");
+
+    let open_uri = f.method("OpenUri", (), |_| {
+        Err(MethodErr::failed(&"opening URIs is not supported"))
+    }).inarg::<&str, _>("Uri");
+
+    let r = remote.clone();
+    let status_prop = f.property::<&str, _>("PlaybackStatus", ())
+        .on_get(move |i, _| {
+            i.append(playback_status(&r.status()?));
+            Ok(())
+        });
+
+    let r = remote.clone();
+    let metadata_prop =
+        f.property::<Metadata, _>("Metadata", ()).on_get(move |i, _| {
+            i.append(metadata(&r.status()?));
+            Ok(())
+        });
+
+    let r = remote.clone();
+    let position = f.property::<i64, _>("Position", ()).on_get(move |i, _| {
+        i.append(r.status()?.elapsed as i64 * 1_000_000);
+        Ok(())
+    });
+
+    let (r, w) = (remote.clone(), remote.clone());
+    let volume = f.property::<f64, _>("Volume", ())
+        .access(Access::ReadWrite)
+        .on_get(move |i, _| {
+            i.append(f64::from(r.status()?.volume) / 100.);
+            Ok(())
+        })
+        .on_set(move |i, _| {
+            let vol: f64 = i.read()?;
+            let vol = (vol.max(0.).min(1.) * 100.).round() as u8;
+            w.send(Command::SetVolume(vol))?;
+            Ok(())
+        });
+
+    let player = f.interface(PLAYER_IFACE, ())
+        .add_m(command(&f, remote, "Next", Command::Next))
+        .add_m(command(&f, remote, "Previous", Command::Prev))
+        .add_m(command(&f, remote, "Pause", Command::Pause))
+        .add_m(command(&f, remote, "PlayPause", Command::Toggle))
+        .add_m(command(&f, remote, "Stop", Command::Pause))
+        .add_m(command(&f, remote, "Play", Command::Play))
+        .add_m(seek)
+        .add_m(set_position)
+        .add_m(open_uri)
+        .add_p(status_prop)
+        .add_p(metadata_prop)
+        .add_p(position)
+        .add_p(volume)
+        .add_p(constant(&f, "Rate", 1f64))
+        .add_p(constant(&f, "MinimumRate", 1f64))
+        .add_p(constant(&f, "MaximumRate", 1f64))
+        .add_p(constant(&f, "CanGoNext", true))
+        .add_p(constant(&f, "CanGoPrevious", true))
+        .add_p(constant(&f, "CanPlay", true))
+        .add_p(constant(&f, "CanPause", true))
+        .add_p(constant(&f, "CanSeek", true))
+        .add_p(constant(&f, "CanControl", true));
+
+    f.tree(()).add(
+        f.object_path(OBJECT_PATH, ())
+            .introspectable()
+            .add(root)
+            .add(player),
+    )
+}
+
+/// A method that sends a single command to the daemon.
+fn command(
+    f: &Factory<MTFn<()>, ()>,
+    remote: &Arc<Remote>,
+    name: &'static str,
+    cmd: Command,
+) -> Method<MTFn<()>, ()> {
+    let remote = remote.clone();
+    f.method(name, (), move |m| {
+        remote.send(cmd.clone())?;
+        Ok(vec![m.msg.method_return()])
+    })
+}
+
+/// A read-only property that never changes.
+fn constant<T>(
+    f: &Factory<MTFn<()>, ()>,
+    name: &'static str,
+    value: T,
+) -> ::dbus::tree::Property<MTFn<()>, ()>
+where
+    T: ::dbus::arg::Arg + ::dbus::arg::Append + Clone + 'static,
+{
+    f.property::<T, _>(name, ()).on_get(move |i, _| {
+        i.append(value.clone());
+        Ok(())
+    })
+}
+
+/// The message to send to the bus for a player event, if it changes
+/// anything MPRIS clients can see.
+fn signal(remote: &Remote, ev: &Event) -> Option<Message> {
+    let path = ::dbus::Path::from(OBJECT_PATH);
+
+    if let Event::Seek(to) = *ev {
+        return Message::new_signal(path, PLAYER_IFACE, "Seeked")
+            .ok()
+            .map(|m| m.append1(to as i64 * 1_000_000))
+    }
+
+    let st = match remote.status() {
+        Ok(st) => st,
+        Err(_) => return None,
+    };
+
+    let mut changed: Metadata = HashMap::new();
+    match *ev {
+        Event::TrackStart(_) | Event::StreamStart | Event::Tags => {
+            changed.insert("Metadata".into(), variant(metadata(&st)));
+            changed.insert(
+                "PlaybackStatus".into(),
+                variant(playback_status(&st).to_string()),
+            );
+        }
+        Event::Pause | Event::Resume => {
+            changed.insert(
+                "PlaybackStatus".into(),
+                variant(playback_status(&st).to_string()),
+            );
+        }
+        Event::Volume(vol) => {
+            changed.insert("Volume".into(), variant(f64::from(vol) / 100.));
+        }
+        _ => return None,
+    }
+
+    let props = PropertiesPropertiesChanged {
+        interface_name: PLAYER_IFACE.into(),
+        changed_properties: changed,
+        invalidated_properties: Vec::new(),
+    };
+    Some(props.to_emit_message(&path))
+}
+
+fn playback_status(st: &Status) -> &'static str {
+    match st.state {
+        State::Playing => "Playing",
+        State::Paused => "Paused",
+        State::Stopped => "Stopped",
+    }
+}
+
+fn metadata(st: &Status) -> Metadata {
+    let mut meta: Metadata = HashMap::new();
+    let track = match st.track {
+        Some(ref t) => t,
+        None => return meta,
+    };
+
+    macro_rules! insert {
+        ($key:expr, $val:expr) => {
+            meta.insert($key.into(), variant($val));
+        };
+    }
+
+    let trackid = format!("/org/subs/track/{}", track.id);
+    insert!("mpris:trackid", ::dbus::Path::from(trackid));
+    insert!("mpris:length", track.duration as i64 * 1_000_000);
+    insert!("xesam:title", track.title.clone());
+    if let Some(ref artist) = track.artist {
+        insert!("xesam:artist", vec![artist.clone()]);
+    }
+    if let Some(ref album) = track.album {
+        insert!("xesam:album", album.clone());
+    }
+    if let Some(ref path) = track.cover_path {
+        insert!("mpris:artUrl", player::file_uri(path));
+    }
+
+    meta
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<RefArg>> {
+    Variant(Box::new(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::arg::{RefArg, Variant};
+    use dbus::stdintf::org_freedesktop_dbus::Properties;
+    use serde_json;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use unix_socket::UnixListener;
+
+    use daemon::Reply;
+    use event::Bus;
+    use player::{State, Status, Track};
+    use testbus::PrivateBus;
+
+    use super::{serve, Remote, BUS_NAME, OBJECT_PATH, PLAYER_IFACE};
+
+    fn status() -> Status {
+        Status {
+            state: State::Paused,
+            position: 0,
+            len: 1,
+            elapsed: 10,
+            elapsed_ms: 10_000,
+            volume: 50,
+            track: Some(Track {
+                id: 7,
+                title: "Bad".into(),
+                artist: Some("Michael Jackson".into()),
+                album: Some("Bad".into()),
+                duration: 247,
+                cover_url: Some("http://server/rest/getCoverArt?t=x".into()),
+                cover_path: Some("/tmp/covers/7.jpg".into()),
+                location: None,
+            }),
+        }
+    }
+
+    /// Answers every request on a socket with the same status, standing in
+    /// for the daemon.
+    fn fake_daemon() -> PathBuf {
+        let socket =
+            env::temp_dir().join(format!("subs-mpris-{}", process::id()));
+        let _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || {
+            let reply = serde_json::to_string(&Reply::Status(status()));
+            let reply = reply.unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut req = String::new();
+                stream.read_to_string(&mut req).unwrap();
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        socket
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon; run with --ignored"]
+    fn serves_status_and_metadata() {
+        let bus = PrivateBus::start();
+
+        let remote = Arc::new(Remote {
+            socket: fake_daemon(),
+        });
+        let events = Bus::new().subscribe();
+        let service = bus.connect();
+        thread::spawn(move || serve(&service, &remote, &events));
+
+        let client = bus.connect();
+        let player = client.with_path(BUS_NAME, OBJECT_PATH, 5000);
+        // The service registers itself on its own thread.
+        let mut status = player.get::<String>(PLAYER_IFACE, "PlaybackStatus");
+        for _ in 0..50 {
+            if status.is_ok() {
+                break
+            }
+            thread::sleep(Duration::from_millis(100));
+            status = player.get(PLAYER_IFACE, "PlaybackStatus");
+        }
+        assert_eq!(status.unwrap(), "Paused");
+
+        let meta: HashMap<String, Variant<Box<RefArg>>> =
+            player.get(PLAYER_IFACE, "Metadata").unwrap();
+        assert_eq!(meta["xesam:title"].0.as_str(), Some("Bad"));
+        assert_eq!(meta["xesam:album"].0.as_str(), Some("Bad"));
+        assert_eq!(meta["mpris:length"].0.as_i64(), Some(247_000_000));
+        // The cached cover, never the server's URL with its credentials.
+        assert_eq!(
+            meta["mpris:artUrl"].0.as_str(),
+            Some("file:///tmp/covers/7.jpg")
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Desktop notifications when a new song starts.
+//!
+//! Each notification replaces the last one instead of stacking up. Like the
+//! MPRIS service, they go to the bus in `DBUS_SESSION_BUS_ADDRESS`, so a
+//! private `dbus-daemon` can stand in for the desktop's.
+
+use dbus::arg::Variant;
+use dbus::{BusType, Connection, Message};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use sunk::song::Song;
+use sunk::Client;
+
+use conf::Conf;
+use cover::Covers;
+use error::Error;
+use event::{Bus, Event};
+use player::Track;
+
+const DEST: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+/// How long to wait for the notification server, in milliseconds.
+const TIMEOUT: i32 = 2000;
+
+/// Starts notifying in the background, if notifications are turned on.
+pub fn spawn(
+    cfg: &Conf,
+    client: Arc<Mutex<Client>>,
+    covers: Arc<Covers>,
+    bus: &Bus,
+) {
+    if !cfg.notify {
+        return
+    }
+
+    let mut notifier = Notifier {
+        summary: cfg.notify_summary.clone(),
+        body: cfg.notify_body.clone(),
+        last: 0,
+    };
+    let events = bus.subscribe();
+
+    thread::spawn(move || {
+        let conn = match Connection::get_private(BusType::Session) {
+            Ok(c) => c,
+            Err(e) => return error!("unable to connect to session bus: {}", e),
+        };
+
+        while let Ok(ev) = events.recv() {
+            if let Event::TrackStart(id) = ev {
+                let res = {
+                    let cli = &*client.lock().unwrap();
+                    Song::get(cli, id).map_err(Error::from).and_then(|song| {
+                        let cover = covers.fetch(cli, &song)?;
+                        Ok((Track::new(cli, &song), cover))
+                    })
+                };
+                let sent = res.and_then(|(track, cover)| {
+                    let icon = cover
+                        .map(|p| p.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    notifier.notify(&conn, &track, &icon)
+                });
+                if let Err(e) = sent {
+                    warn!("unable to send notification for {}: {}", id, e);
+                }
+            }
+        }
+    });
+}
+
+struct Notifier {
+    summary: String,
+    body: String,
+    /// ID of the last notification, so the next one can replace it.
+    last: u32,
+}
+
+impl Notifier {
+    fn notify(
+        &mut self,
+        conn: &Connection,
+        track: &Track,
+        icon: &str,
+    ) -> ::std::result::Result<(), Error> {
+        let summary = fill(&self.summary, track, false);
+        let body = fill(&self.body, track, true);
+        let hints: HashMap<&str, Variant<&str>> = HashMap::new();
+
+        let msg = Message::new_method_call(DEST, PATH, DEST, "Notify")
+            .map_err(Error::Service)?
+            .append3("subs", self.last, icon)
+            .append3(summary, body, Vec::<&str>::new())
+            .append2(hints, -1i32);
+        let reply = conn.send_with_reply_and_block(msg, TIMEOUT)
+            .map_err(|e| Error::Service(e.to_string()))?;
+        self.last = reply
+            .get1()
+            .ok_or("Notification server didn't return an ID")?;
+        Ok(())
+    }
+}
+
+/// Fills in `{title}`, `{artist}`, `{album}` and `{duration}`. The body may
+/// be shown as markup, so values going there are escaped.
+fn fill(template: &str, track: &Track, markup: bool) -> String {
+    let esc = |s: &str| {
+        if markup {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        } else {
+            s.to_string()
+        }
+    };
+    let duration = track.duration;
+
+    template
+        .replace("{title}", &esc(&track.title))
+        .replace("{artist}", &esc(track.artist.as_ref().map_or("", |a| a)))
+        .replace("{album}", &esc(track.album.as_ref().map_or("", |a| a)))
+        .replace(
+            "{duration}",
+            &format!("{}:{:02}", duration / 60, duration % 60),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::{MessageType, NameFlag};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use player::Track;
+    use testbus::PrivateBus;
+
+    use super::{fill, Notifier, DEST};
+
+    fn track() -> Track {
+        Track {
+            id: 1,
+            title: "Bad".into(),
+            artist: Some("Simon & Garfunkel".into()),
+            album: None,
+            duration: 247,
+            cover_url: None,
+            cover_path: None,
+            location: None,
+        }
+    }
+
+    #[test]
+    fn fill_escapes_only_the_body() {
+        let t = track();
+        assert_eq!(
+            fill("{artist} ({duration})", &t, false),
+            "Simon & Garfunkel (4:07)"
+        );
+        assert_eq!(fill("{artist}", &t, true), "Simon &amp; Garfunkel");
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon; run with --ignored"]
+    fn replaces_the_last_notification() {
+        let bus = PrivateBus::start();
+
+        // Stands in for the desktop's notification server, handing out IDs
+        // from 42 and passing on the ID each notification replaces.
+        let server = bus.connect();
+        server
+            .register_name(DEST, NameFlag::ReplaceExisting as u32)
+            .unwrap();
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            let mut id = 41u32;
+            loop {
+                for msg in server.incoming(100) {
+                    if msg.msg_type() != MessageType::MethodCall {
+                        continue
+                    }
+                    let (_, replaces) = msg.read2::<&str, u32>().unwrap();
+                    id += 1;
+                    let _ = send.send(replaces);
+                    let _ = server.send(msg.method_return().append1(id));
+                }
+            }
+        });
+
+        let conn = bus.connect();
+        let mut notifier = Notifier {
+            summary: "{title}".into(),
+            body: "{artist}".into(),
+            last: 0,
+        };
+        notifier.notify(&conn, &track(), "").unwrap();
+        assert_eq!(recv.recv().unwrap(), 0);
+        assert_eq!(notifier.last, 42);
+
+        notifier.notify(&conn, &track(), "").unwrap();
+        assert_eq!(recv.recv().unwrap(), 42);
+        assert_eq!(notifier.last, 43);
+    }
+}
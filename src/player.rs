@@ -1,24 +1,181 @@
 use crossbeam_channel::*;
 use gst;
 use gst::prelude::*;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use sunk::{Client, Streamable};
+use sunk::{Client, Media};
 use sunk::song::Song;
 
+use cache::AudioCache;
+use cover::Covers;
 use daemon::Command;
 use error::Error;
-use queue::Queue;
+use event::{Bus, Event};
+use library::Library;
+use quality::Preferences;
+use queue::{Queue, Source};
+
+/// How often to check whether a stream with no set length has ended, in
+/// seconds.
+const POLL: u64 = 1;
+/// How long to wait for a file or URL to start before asking how long it
+/// is, in seconds.
+const PROBE_TIMEOUT: u64 = 5;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum State {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// The parts of a song the daemon's clients care about.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Track {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: u64,
+    pub cover_url: Option<String>,
+    /// Where the cover is in the daemon's cache, once it's downloaded.
+    pub cover_path: Option<String>,
+    /// The file or URL, for entries that aren't songs on the server.
+    pub location: Option<String>,
+}
+
+impl Track {
+    pub fn new(cli: &Client, song: &Song) -> Track {
+        Track {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            duration: song.duration.map(|d| d as u64).unwrap_or(0),
+            cover_url: if song.has_cover_art() {
+                song.cover_art_url(cli, None).ok()
+            } else {
+                None
+            },
+            cover_path: None,
+            location: None,
+        }
+    }
+
+    /// A file, URL or radio station, named by its tags if they've been
+    /// read. Stations' tags usually hold whatever they're playing, so the
+    /// station's name goes in place of the album.
+    pub fn local(source: &Source, tags: &Tags, duration: u64) -> Track {
+        let album = match *source {
+            Source::Radio { ref name, .. } => Some(name.clone()),
+            _ => tags.album.clone(),
+        };
+        Track {
+            id: 0,
+            title: tags.title.clone().unwrap_or_else(|| source.name()),
+            artist: tags.artist.clone(),
+            album,
+            duration,
+            cover_url: None,
+            cover_path: None,
+            location: source.location().map(String::from),
+        }
+    }
+}
+
+/// Details read from the tags in a file or stream.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref artist) = self.artist {
+            write!(f, "{} - ", artist)?;
+        }
+        write!(f, "{}", self.title)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Status {
+    pub state: State,
+    /// Position of the current song in the queue.
+    pub position: usize,
+    pub len: usize,
+    /// Seconds into the current song.
+    pub elapsed: u64,
+    /// Milliseconds into the current song, for anything that needs to keep
+    /// closer time than `elapsed`.
+    pub elapsed_ms: u64,
+    pub volume: u8,
+    pub track: Option<Track>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn secs_to_minsec(secs: u64) -> String {
+            format!("{}:{:02}", secs / 60, secs % 60)
+        }
+
+        let track = match self.track {
+            Some(ref t) => t,
+            None => return write!(f, "nothing to display"),
+        };
+
+        write!(
+            f,
+            "{track}\n[{stat}]  #{n}/{size}  {prog}/{dur} ({per})",
+            track = track,
+            stat = match self.state {
+                State::Playing => "playing",
+                _ => "paused",
+            },
+            n = self.position + 1,
+            size = self.len,
+            prog = secs_to_minsec(self.elapsed),
+            dur = secs_to_minsec(track.duration),
+            per = if self.elapsed > 0 && track.duration > 0 {
+                format!(
+                    "{:.0}%",
+                    (self.elapsed as f32 / track.duration as f32) * 100.
+                )
+            } else {
+                "0%".into()
+            },
+        )
+    }
+}
 
 pub struct Player {
     daemon_recv: Receiver<Command>,
     daemon_send: Sender<Command>,
     client: Arc<Mutex<Client>>,
+    bus: Bus,
+    covers: Arc<Covers>,
+    library: Arc<Library>,
+    cache: Arc<AudioCache>,
+    prefs: Arc<Preferences>,
     queue: Queue,
+    /// Tags of the current entry, if it's a file or URL.
+    tags: Tags,
     pipe: Option<gst::Element>,
     playing: bool,
+    volume: u8,
     song_dur: u64,
     song_rem: u64,
+    /// Whether the current entry is a stream with no set length, which ends
+    /// when the stream does rather than after `song_dur`.
+    open_ended: bool,
+    /// Set by `Next` and `Prev` to move on without waiting for the current
+    /// entry to finish.
+    skip: bool,
+    /// Set once the queue runs out, leaving nothing to wait for.
+    ran_out: bool,
 }
 
 impl Player {
@@ -26,16 +183,31 @@ impl Player {
         daemon_recv: Receiver<Command>,
         daemon_send: Sender<Command>,
         client: Arc<Mutex<Client>>,
+        bus: Bus,
+        covers: Arc<Covers>,
+        library: Arc<Library>,
+        cache: Arc<AudioCache>,
+        prefs: Arc<Preferences>,
     ) -> Player {
         Player {
             daemon_recv,
             daemon_send,
             client,
+            bus,
+            covers,
+            library,
+            cache,
+            prefs,
             queue: Queue::new(),
+            tags: Tags::default(),
             pipe: None,
             playing: false,
+            volume: 100,
             song_dur: 0,
             song_rem: 0,
+            open_ended: false,
+            skip: false,
+            ran_out: false,
         }
     }
 
@@ -43,29 +215,90 @@ impl Player {
         match cmd {
             Command::Add(song) => {
                 debug!("adding song {}", song);
-                self.queue.append(song as usize);
+                self.queue.append(Source::Song(song));
+                self.bus.publish(Event::Queue);
+                self.prefetch();
             }
             Command::AddMany(ns) => {
                 debug!("adding random songs: {:?}", ns);
-                self.queue.extend(ns.iter().map(|i| *i as usize));
+                self.queue.extend(ns.into_iter().map(Source::Song));
+                self.bus.publish(Event::Queue);
+                self.prefetch();
             }
             Command::AddNext(song) => {
                 debug!("adding song {} next", song);
-                self.queue.insert_next(song as usize);
+                self.queue.insert_next(Source::Song(song));
+                self.bus.publish(Event::Queue);
+                self.prefetch();
+            }
+            Command::AddSource(source, next) => {
+                debug!("adding {}", source);
+                if next {
+                    self.queue.insert_next(source);
+                } else {
+                    self.queue.append(source);
+                }
+                self.bus.publish(Event::Queue);
+            }
+            Command::PlayNow(source) => {
+                debug!("playing {} now", source);
+                if self.pipe.is_some() {
+                    if let Some(id) =
+                        self.queue.current().and_then(|s| s.song())
+                    {
+                        self.bus.publish(Event::TrackEnd(id));
+                    }
+                    self.queue.insert_next(source.clone());
+                    self.queue.next();
+                } else {
+                    self.queue.insert_current(source.clone());
+                }
+                self.bus.publish(Event::Queue);
+                self.playing = self.start(&source);
+            }
+            Command::PlayAt(pos) => {
+                debug!("playing queue position {}", pos);
+                if pos >= self.queue.len() {
+                    return "continue"
+                }
+                if self.pipe.is_some() {
+                    if let Some(id) =
+                        self.queue.current().and_then(|s| s.song())
+                    {
+                        self.bus.publish(Event::TrackEnd(id));
+                    }
+                }
+                if let Some(source) = self.queue.jump(pos) {
+                    self.playing = self.start(&source);
+                }
             }
             Command::Clear => {
                 debug!("emptying queue");
+                if let Some(pipe) = self.pipe.take() {
+                    log(pipe.set_state(gst::State::Null));
+                    if let Some(id) =
+                        self.queue.current().and_then(|s| s.song())
+                    {
+                        self.bus.publish(Event::TrackEnd(id));
+                    }
+                }
                 self.queue.clear();
+                self.playing = false;
+                self.open_ended = false;
+                self.song_rem = 0;
+                self.bus.publish(Event::Queue);
+                self.bus.publish(Event::QueueEmpty);
             }
             Command::Next => {
                 debug!("skipping");
-                // self.queue.next();
                 self.song_rem = 0;
+                self.skip = true;
             }
             Command::Prev => {
                 debug!("rewinding");
                 self.queue.prev2();
                 self.song_rem = 0;
+                self.skip = true;
             }
             Command::Play => {
                 debug!("playing");
@@ -75,6 +308,7 @@ impl Player {
 
                 if let Some(ref pipe) = self.pipe {
                     log(pipe.set_state(gst::State::Playing));
+                    self.bus.publish(Event::Resume);
                 } else {
                     self.song_rem = 0;
                 }
@@ -89,8 +323,9 @@ impl Player {
 
                 if let Some(ref pipe) = self.pipe {
                     log(pipe.set_state(gst::State::Paused));
-                    self.song_rem = self.song_dur - secs(pipe);
+                    self.song_rem = self.song_dur.saturating_sub(secs(pipe));
                     info!("song duration left: {}", self.song_rem);
+                    self.bus.publish(Event::Pause);
                 } else {
                     self.song_rem = 0;
                 }
@@ -109,19 +344,64 @@ impl Player {
                             State::Paused => pipe.set_state(State::Playing),
                             _ => gst::StateChangeReturn::Success,
                         });
-                        self.song_rem = self.song_dur - secs(pipe);
+                        self.song_rem =
+                            self.song_dur.saturating_sub(secs(pipe));
                         self.playing = !self.playing;
                         info!("song duration left: {}", self.song_rem);
+                        self.bus.publish(if self.playing {
+                            Event::Resume
+                        } else {
+                            Event::Pause
+                        });
                     }
                 } else {
                     self.song_rem = 0;
                 }
             }
+            Command::Seek(to) => {
+                debug!("seeking to {}", to);
+                if self.open_ended {
+                    self.bus.publish(Event::Error(
+                        "Unable to seek in a stream".into(),
+                    ));
+                    return "continue"
+                }
+                if let Some(ref pipe) = self.pipe {
+                    let to = ::std::cmp::min(to, self.song_dur);
+                    if let Err(e) = pipe.seek_simple(
+                        gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                        gst::ClockTime::from_seconds(to),
+                    ) {
+                        error!("unable to seek: {}", e);
+                        self.bus.publish(Event::Error(format!(
+                            "Unable to seek: {}",
+                            e
+                        )));
+                        return "continue"
+                    }
+                    self.song_rem = self.song_dur - to;
+                    self.bus.publish(Event::Seek(to));
+                }
+            }
+            Command::SetVolume(vol) => {
+                debug!("setting volume to {}", vol);
+                self.volume = ::std::cmp::min(vol, 100);
+                if let Some(ref pipe) = self.pipe {
+                    set_volume(pipe, self.volume);
+                }
+                self.bus.publish(Event::Volume(self.volume));
+            }
             Command::StatusReq => {
                 debug!("sending status");
+                self.read_tags();
                 let status = self.status();
                 self.daemon_send.send(Command::Status(status)).unwrap();
             }
+            Command::QueueReq => {
+                debug!("sending queue");
+                let songs = self.queue.songs().to_vec();
+                self.daemon_send.send(Command::Queue(songs)).unwrap();
+            }
             Command::Stop => {
                 debug!("stopping");
                 return "break"
@@ -136,8 +416,14 @@ impl Player {
 
         'main: loop {
             if self.playing {
-                match self.daemon_recv
-                    .recv_timeout(Duration::from_secs(self.song_rem))
+                let wait = if self.skip {
+                    0
+                } else if self.open_ended {
+                    POLL
+                } else {
+                    self.remaining()
+                };
+                match self.daemon_recv.recv_timeout(Duration::from_secs(wait))
                 {
                     Ok(cmd) => match self.run_cmd(cmd) {
                         "break" => break 'main,
@@ -145,38 +431,38 @@ impl Player {
                         _ => (),
                     },
                     Err(_) => {
-                        info!("trying to play next song");
-                        if let Some(n) = {
-                            if self.pipe.is_some() {
-                                self.queue.next()
-                            } else {
-                                self.queue.current()
+                        let skip = self.skip;
+                        self.skip = false;
+                        if self.open_ended && !skip && !self.stream_ended() {
+                            continue 'main
+                        }
+                        if self.pipe.is_some() {
+                            if let Some(id) =
+                                self.queue.current().and_then(|s| s.song())
+                            {
+                                self.bus.publish(Event::TrackEnd(id));
                             }
-                        } {
+                        }
+                        info!("trying to play next song");
+                        let mut next = if self.pipe.is_some() {
+                            self.queue.next()
+                        } else {
+                            self.queue.current()
+                        };
+                        // Entries that can't be played are skipped.
+                        let mut started = false;
+                        while let Some(n) = next {
                             info!("playing next song: {}", n);
-                            if let Some(ref pipe) = self.pipe {
-                                log(pipe.set_state(gst::State::Null));
+                            if self.start(&n) {
+                                started = true;
+                                break
                             }
-                            let cli = &*self.client
-                                .lock()
-                                .expect("unable to lock client");
-                            let song = Song::get(cli, n as u64).unwrap();
-
-                            let url = song.stream_url(cli).unwrap();
-                            let pipe = gst::parse_launch(&format!(
-                                "playbin uri={}",
-                                url
-                            )).expect("unable to start pipe");
-                            log(pipe.set_state(gst::State::Playing));
-                            self.pipe = Some(pipe);
-                            self.song_dur = match song.duration {
-                                Some(d) => d as u64,
-                                None => song.size / 192 / 124,
-                            };
-                            self.song_rem = self.song_dur;
-                            info!("song duration left: {}", self.song_dur);
-                        } else {
+                            next = self.queue.next();
+                        }
+                        if !started {
                             warn!("queue is empty, what happened?");
+                            self.bus.publish(Event::QueueEmpty);
+                            self.ran_out = true;
                             self.song_rem = 9999;
                         }
                     }
@@ -191,59 +477,269 @@ impl Player {
         }
     }
 
-    fn status(&self) -> String {
-        fn secs_to_minsec(secs: u64) -> String {
-            format!("{}:{:02}", secs / 60, secs % 60)
+    /// Starts playing a queue entry from the beginning, letting everyone
+    /// know and returning `false` if it can't be.
+    fn start(&mut self, source: &Source) -> bool {
+        if let Some(pipe) = self.pipe.take() {
+            log(pipe.set_state(gst::State::Null));
         }
+        self.tags = Tags::default();
 
-        if let Some(song) = self.queue.current() {
-            let (status, prog) = if let Some(ref pipe) = self.pipe {
-                let state = pipe.get_state(gst::CLOCK_TIME_NONE).1;
-                let status = if self.playing { "playing" } else { "paused" };
-                let prog = pipe.query_position::<gst::ClockTime>()
-                    .expect("error getting clock time")
-                    .seconds()
-                    .expect("unable to cast clock to seconds");
+        let (uri, duration) = match *source {
+            // Cached songs are played without asking the server anything,
+            // taking their length from the index or the file itself.
+            Source::Song(id) => match self.cache.path(id) {
+                Some(path) => {
+                    info!("playing from the cache");
+                    let duration = self
+                        .library
+                        .song(id)
+                        .map(|s| s.duration)
+                        .filter(|&d| d > 0);
+                    (file_uri(&path.to_string_lossy()), duration)
+                }
+                None => match self.song_duration(id) {
+                    Ok(duration) => (self.prefs.stream_url(id), duration),
+                    Err(e) => {
+                        error!("unable to get song {}: {}", id, e);
+                        self.bus.publish(Event::Error(format!(
+                            "Unable to play song {}: {}",
+                            id, e
+                        )));
+                        return false
+                    }
+                },
+            },
+            Source::File(ref path) => (file_uri(path), None),
+            Source::Url(ref url) | Source::Radio { ref url, .. } => {
+                (url.clone(), None)
+            }
+        };
 
-                (status, prog)
-            } else {
-                ("paused", 0)
+        let pipe = match playbin(&uri) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("unable to play {}: {}", source, e);
+                self.bus.publish(Event::Error(format!(
+                    "Unable to play {}: {}",
+                    source.name(),
+                    e
+                )));
+                return false
+            }
+        };
+        set_volume(&pipe, self.volume);
+        if pipe.set_state(gst::State::Playing)
+            == gst::StateChangeReturn::Failure
+        {
+            error!("unable to play {}", source);
+            log(pipe.set_state(gst::State::Null));
+            self.bus.publish(Event::Error(format!(
+                "Unable to play {}",
+                source.name()
+            )));
+            return false
+        }
+        let duration = duration.or_else(|| probe_duration(&pipe));
+        self.pipe = Some(pipe);
+
+        self.bus.publish(match *source {
+            Source::Song(id) => Event::TrackStart(id),
+            _ => Event::StreamStart,
+        });
+        self.prefetch();
+        self.open_ended = duration.is_none();
+        self.ran_out = false;
+        self.song_dur = duration.unwrap_or(0);
+        self.song_rem = self.song_dur;
+        info!("song duration left: {}", self.song_dur);
+        true
+    }
+
+    /// Seconds until the current song ends. It's worked out from where the
+    /// pipe has got to each time, since any command that comes in restarts
+    /// the wait.
+    fn remaining(&self) -> u64 {
+        match self.pipe {
+            Some(ref pipe) if !self.ran_out => {
+                self.song_dur.saturating_sub(secs(pipe))
+            }
+            _ => self.song_rem,
+        }
+    }
+
+    /// How long a song is, from the index if it's there. `None` leaves it to
+    /// gstreamer to find out.
+    fn song_duration(
+        &self,
+        id: u64,
+    ) -> ::std::result::Result<Option<u64>, Error> {
+        if let Some(entry) = self.library.song(id) {
+            if entry.duration > 0 {
+                return Ok(Some(entry.duration))
+            }
+        }
+        let cli = &*self.client.lock().expect("unable to lock client");
+        let song = Song::get(cli, id)?;
+        Ok(song.duration.map(|d| d as u64))
+    }
+
+    /// Has the cache download the songs coming up next.
+    fn prefetch(&self) {
+        let ids = self.queue
+            .upcoming(self.cache.prefetch_count())
+            .iter()
+            .filter_map(|s| s.song())
+            .collect::<Vec<_>>();
+        self.cache.prefetch(&ids);
+    }
+
+    /// Whether an open-ended stream has finished or failed, letting
+    /// everyone know if its tags have changed in the meantime.
+    fn stream_ended(&mut self) -> bool {
+        let before = self.tags.clone();
+        self.read_tags();
+        if self.tags != before {
+            self.bus.publish(Event::Tags);
+        }
+
+        let bus = match self.pipe.as_ref().and_then(|p| p.get_bus()) {
+            Some(b) => b,
+            None => return true,
+        };
+        let types = [gst::MessageType::Eos, gst::MessageType::Error];
+        match bus.pop_filtered(&types) {
+            Some(msg) => {
+                if let gst::MessageView::Error(e) = msg.view() {
+                    error!("stream failed: {}", e.get_error());
+                    self.bus.publish(Event::Error(format!(
+                        "Stream failed: {}",
+                        e.get_error()
+                    )));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Picks up any tags the pipe has come across since the last look.
+    fn read_tags(&mut self) {
+        let bus = match self.pipe.as_ref().and_then(|p| p.get_bus()) {
+            Some(b) => b,
+            None => return,
+        };
+        while let Some(msg) = bus.pop_filtered(&[gst::MessageType::Tag]) {
+            if let gst::MessageView::Tag(tag) = msg.view() {
+                let tags = tag.get_tags();
+                if let Some(t) = tags.get::<gst::tags::Title>() {
+                    self.tags.title = t.get().map(String::from);
+                }
+                if let Some(a) = tags.get::<gst::tags::Artist>() {
+                    self.tags.artist = a.get().map(String::from);
+                }
+                if let Some(a) = tags.get::<gst::tags::Album>() {
+                    self.tags.album = a.get().map(String::from);
+                }
+            }
+        }
+    }
+
+    fn status(&self) -> Status {
+        let (state, elapsed_ms) = match self.pipe {
+            Some(ref pipe) if self.playing => (State::Playing, millis(pipe)),
+            Some(ref pipe) => (State::Paused, millis(pipe)),
+            None => (State::Stopped, 0),
+        };
+
+        let track = self.queue.current().map(|source| {
+            let song = match source {
+                Source::Song(id) => id,
+                _ => return Track::local(&source, &self.tags, self.song_dur),
             };
+            if let Some(entry) = self.library.song(song) {
+                let mut track = self.library.track(&entry);
+                track.cover_path = entry
+                    .cover_id
+                    .and_then(|c| self.covers.cached_id(&c))
+                    .map(|p| p.to_string_lossy().into_owned());
+                return track
+            }
 
             let cli = &*self.client.lock().expect("unable to lock client");
-            let curr_song = Song::get(cli, song as u64).unwrap();
-            let dur = curr_song.duration.unwrap();
+            let song = match Song::get(cli, song) {
+                Ok(s) => s,
+                // Offline and not in the index yet; all that's known is
+                // the ID.
+                Err(e) => {
+                    warn!("unable to get song {}: {}", song, e);
+                    let tags = Tags::default();
+                    return Track {
+                        id: song,
+                        ..Track::local(&source, &tags, self.song_dur)
+                    }
+                }
+            };
+            let mut track = Track::new(cli, &song);
+            track.cover_path = self.covers
+                .cached(&song)
+                .map(|p| p.to_string_lossy().into_owned());
+            track
+        });
 
-            format!(
-                "{art}{title}\n[{stat}]  #{n}/{size}  {prog}/{dur} ({per})",
-                art = if let Some(a) = curr_song.artist {
-                    a + " - "
-                } else {
-                    "".to_string()
-                },
-                title = curr_song.title,
-                stat = status,
-                n = self.queue.position() + 1,
-                size = self.queue.len(),
-                prog = secs_to_minsec(prog),
-                dur = secs_to_minsec(dur),
-                per = if prog > 0 {
-                    format!("{:.0}%", (prog as f32 / dur as f32) * 100.)
-                } else {
-                    "0%".into()
-                },
-            )
-        } else {
-            "nothing to display".into()
+        Status {
+            state,
+            position: self.queue.position(),
+            len: self.queue.len(),
+            elapsed: elapsed_ms / 1000,
+            elapsed_ms,
+            volume: self.volume,
+            track,
         }
     }
 }
 
+/// Streams that are still buffering have no position yet, which counts as
+/// the start.
 fn secs(pipe: &gst::Element) -> u64 {
     pipe.query_position::<gst::ClockTime>()
-        .expect("error getting clock time")
-        .seconds()
-        .expect("unable to cast clock to seconds")
+        .and_then(|t| t.seconds())
+        .unwrap_or(0)
+}
+
+fn millis(pipe: &gst::Element) -> u64 {
+    pipe.query_position::<gst::ClockTime>()
+        .and_then(|t| t.mseconds())
+        .unwrap_or(0)
+}
+
+/// A pipe to play the URI. It's set as a property rather than parsed as part
+/// of a pipeline description, so quotes and spaces in it can't break out.
+fn playbin(uri: &str) -> ::std::result::Result<gst::Element, Error> {
+    let pipe = gst::ElementFactory::make("playbin", None)
+        .ok_or(Error::Other("unable to create playbin"))?;
+    pipe.set_property("uri", &uri)
+        .map_err(|_| Error::Other("unable to set the URI"))?;
+    Ok(pipe)
+}
+
+/// Waits for the pipe to start, then asks how long it'll play for. Streams
+/// with no end give `None`.
+fn probe_duration(pipe: &gst::Element) -> Option<u64> {
+    pipe.get_state(gst::ClockTime::from_seconds(PROBE_TIMEOUT));
+    pipe.query_duration::<gst::ClockTime>()?.seconds()
+}
+
+/// A `file://` URI for a path, escaping anything that isn't allowed in one.
+pub fn file_uri(path: &str) -> String {
+    let path = path.bytes()
+        .map(|b| match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.'
+            | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect::<String>();
+    format!("file://{}", path)
 }
 
 fn log(s: gst::StateChangeReturn) {
@@ -251,3 +747,115 @@ fn log(s: gst::StateChangeReturn) {
         error!("unable to change state: {:?}", s)
     }
 }
+
+fn set_volume(pipe: &gst::Element, vol: u8) {
+    if let Err(e) = pipe.set_property("volume", &(f64::from(vol) / 100.)) {
+        error!("unable to set volume: {}", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use sunk::Client;
+
+    use super::Player;
+    use cache::AudioCache;
+    use conf::Conf;
+    use cover::Covers;
+    use daemon::Command;
+    use event::Bus;
+    use library::Library;
+    use quality::Preferences;
+    use queue::Source;
+
+    /// Writes `secs` seconds of silence as a WAV file.
+    fn silence(path: &Path, secs: u32) -> String {
+        let rate = 8000u32;
+        let len = rate * 2 * secs;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&le32(36 + len));
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&le32(16));
+        // PCM, mono, 16 bits.
+        wav.extend_from_slice(&[1, 0, 1, 0]);
+        wav.extend_from_slice(&le32(rate));
+        wav.extend_from_slice(&le32(rate * 2));
+        wav.extend_from_slice(&[2, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&le32(len));
+        wav.resize(44 + len as usize, 0);
+        fs::write(path, wav).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn le32(n: u32) -> [u8; 4] {
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("subs-test-player-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn moves_on_while_status_is_polled() {
+        let dir = temp_dir("advance");
+        let first = silence(&dir.join("first.wav"), 2);
+        let second = silence(&dir.join("second.wav"), 2);
+        let cfg = Conf {
+            cache: dir.clone(),
+            ..Conf::default()
+        };
+
+        let (send, player_recv) = unbounded();
+        let (player_send, recv) = unbounded();
+        let client = Client::new("http://127.0.0.1:1", "u", "p").unwrap();
+        let prefs = Arc::new(Preferences::new(&cfg));
+        let mut player = Player::new(
+            player_recv,
+            player_send,
+            Arc::new(Mutex::new(client)),
+            Bus::new(),
+            Arc::new(Covers::new(&cfg)),
+            Arc::new(Library::load(&cfg)),
+            Arc::new(AudioCache::new(&cfg, prefs.clone())),
+            prefs,
+        );
+        thread::spawn(move || player.run());
+
+        send.send(Command::AddSource(Source::File(first), false))
+            .unwrap();
+        send.send(Command::AddSource(Source::File(second), false))
+            .unwrap();
+        send.send(Command::Play).unwrap();
+
+        // Asking for the status restarts the player's wait, so this is
+        // what a status bar or MPD client does to it.
+        let start = Instant::now();
+        let mut position = 0;
+        while position == 0 && start.elapsed() < Duration::from_secs(8) {
+            thread::sleep(Duration::from_millis(100));
+            send.send(Command::StatusReq).unwrap();
+            position = match recv.recv().unwrap() {
+                Command::Status(st) => st.position,
+                _ => unreachable!(),
+            };
+        }
+        assert_eq!(position, 1);
+
+        send.send(Command::Stop).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+//! Reading and writing playlists as M3U and XSPF files.
+
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::Reader;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use error::{Error, Result};
+
+/// Prefix for locations that refer to a song on the Subsonic server by ID.
+pub const ID_SCHEME: &str = "subsonic:";
+
+/// A single entry in a playlist file.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Entry {
+    pub location: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Length in seconds.
+    pub duration: Option<u64>,
+}
+
+impl Entry {
+    /// A short description of the entry, for reporting it to the user.
+    pub fn describe(&self) -> String {
+        match (&self.artist, &self.title, &self.location) {
+            (&Some(ref a), &Some(ref t), _) => format!("{} - {}", a, t),
+            (_, &Some(ref t), _) => t.clone(),
+            (_, _, &Some(ref l)) => l.clone(),
+            _ => "(empty entry)".into(),
+        }
+    }
+
+    /// The Subsonic song ID the location refers to, either directly or as the
+    /// `id` parameter of a stream URL.
+    pub fn id(&self) -> Option<u64> {
+        let location = self.location.as_ref()?;
+        if location.starts_with(ID_SCHEME) {
+            return location[ID_SCHEME.len()..].parse().ok()
+        }
+        if !location.contains("/rest/stream") {
+            return None
+        }
+        let query = location.splitn(2, '?').nth(1)?;
+        query
+            .split('&')
+            .filter_map(|p| {
+                let mut kv = p.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some("id"), Some(v)) => v.parse().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// Something to search the library with when there's no ID to go on.
+    pub fn query(&self) -> Option<String> {
+        match (&self.artist, &self.title) {
+            (&Some(ref a), &Some(ref t)) => return Some(format!("{} {}", a, t)),
+            (_, &Some(ref t)) => return Some(t.clone()),
+            _ => (),
+        }
+        // Fall back to the file name, minus any leading track number.
+        let stem = Path::new(self.location.as_ref()?).file_stem()?.to_str()?;
+        let name = stem.trim_start_matches(|c: char| {
+            c.is_numeric() || c == ' ' || c == '-' || c == '.' || c == '_'
+        });
+        if name.is_empty() {
+            None
+        } else {
+            Some(name.replace('_', " "))
+        }
+    }
+}
+
+enum Format {
+    M3u,
+    Xspf,
+}
+
+fn format(path: &Path) -> ::std::result::Result<Format, Error> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("m3u") | Some("m3u8") => Ok(Format::M3u),
+        Some("xspf") => Ok(Format::Xspf),
+        _ => Err("Playlist files must end in .m3u, .m3u8 or .xspf".into()),
+    }
+}
+
+pub fn read(path: &Path) -> ::std::result::Result<Vec<Entry>, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    match format(path)? {
+        Format::M3u => Ok(read_m3u(&contents)),
+        Format::Xspf => read_xspf(&contents),
+    }
+}
+
+pub fn write(path: &Path, entries: &[Entry]) -> Result {
+    let out = match format(path)? {
+        Format::M3u => write_m3u(entries),
+        Format::Xspf => write_xspf(entries),
+    };
+    File::create(path)?.write_all(out.as_bytes())?;
+    Ok(())
+}
+
+fn read_m3u(contents: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut next = Entry::default();
+
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.starts_with("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let mut info = line["#EXTINF:".len()..].splitn(2, ',');
+            next.duration = info
+                .next()
+                .and_then(|d| d.trim().parse::<i64>().ok())
+                .filter(|d| *d > 0)
+                .map(|d| d as u64);
+            if let Some(name) = info.next() {
+                let mut parts = name.splitn(2, " - ");
+                match (parts.next(), parts.next()) {
+                    (Some(a), Some(t)) => {
+                        next.artist = Some(a.trim().into());
+                        next.title = Some(t.trim().into());
+                    }
+                    (Some(t), None) if !t.trim().is_empty() => {
+                        next.title = Some(t.trim().into())
+                    }
+                    _ => (),
+                }
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            next.location = Some(line.into());
+            entries.push(next);
+            next = Entry::default();
+        }
+    }
+
+    entries
+}
+
+fn write_m3u(entries: &[Entry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        let name = match (&entry.artist, &entry.title) {
+            (&Some(ref a), &Some(ref t)) => format!("{} - {}", a, t),
+            (_, &Some(ref t)) => t.clone(),
+            _ => String::new(),
+        };
+        writeln!(
+            out,
+            "#EXTINF:{},{}",
+            entry.duration.map(|d| d as i64).unwrap_or(-1),
+            name
+        ).unwrap();
+        if let Some(ref location) = entry.location {
+            writeln!(out, "{}", location).unwrap();
+        }
+    }
+    out
+}
+
+fn read_xspf(contents: &str) -> ::std::result::Result<Vec<Entry>, Error> {
+    let mut reader = Reader::from_str(contents);
+    reader.trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut track: Option<Entry> = None;
+    let mut tag = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(XmlEvent::Start(ref e)) => {
+                tag = e.local_name().to_vec();
+                if tag == b"track" {
+                    track = Some(Entry::default());
+                }
+            }
+            Ok(XmlEvent::End(ref e)) => {
+                if e.local_name() == b"track" {
+                    entries.extend(track.take());
+                }
+                tag.clear();
+            }
+            Ok(XmlEvent::Text(ref e)) => if let Some(ref mut t) = track {
+                let text = e.unescape_and_decode(&reader)
+                    .map_err(|_| Error::Other("Invalid text in XSPF file"))?;
+                match tag.as_slice() {
+                    // Prefer a Subsonic ID over whatever else the location is.
+                    b"location" => if t.id().is_none() {
+                        t.location = Some(text)
+                    },
+                    b"identifier" if text.starts_with(ID_SCHEME) => {
+                        t.location = Some(text)
+                    }
+                    b"title" => t.title = Some(text),
+                    b"creator" => t.artist = Some(text),
+                    b"album" => t.album = Some(text),
+                    b"duration" => {
+                        t.duration = text.parse().ok().map(|ms: u64| ms / 1000)
+                    }
+                    _ => (),
+                }
+            },
+            Ok(XmlEvent::Eof) => break,
+            Err(_) => return Err("Unable to parse XSPF file".into()),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(entries)
+}
+
+fn write_xspf(entries: &[Entry]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  \
+         <trackList>\n",
+    );
+
+    for entry in entries {
+        out.push_str("    <track>\n");
+        if let Some(ref location) = entry.location {
+            if location.starts_with(ID_SCHEME) {
+                field(&mut out, "identifier", location);
+            } else {
+                field(&mut out, "location", location);
+            }
+        }
+        if let Some(ref title) = entry.title {
+            field(&mut out, "title", title);
+        }
+        if let Some(ref artist) = entry.artist {
+            field(&mut out, "creator", artist);
+        }
+        if let Some(ref album) = entry.album {
+            field(&mut out, "album", album);
+        }
+        if let Some(duration) = entry.duration {
+            field(&mut out, "duration", &(duration * 1000).to_string());
+        }
+        out.push_str("    </track>\n");
+    }
+
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+fn field(out: &mut String, name: &str, value: &str) {
+    writeln!(out, "      <{0}>{1}</{0}>", name, escape(value)).unwrap();
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry {
+                location: Some("subsonic:42".into()),
+                title: Some("Fish & <Chips>".into()),
+                artist: Some("Cake".into()),
+                album: None,
+                duration: Some(200),
+            },
+            Entry {
+                location: Some("/music/03 - Nobody_Home.ogg".into()),
+                title: None,
+                artist: None,
+                album: None,
+                duration: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn m3u_round_trip() {
+        assert_eq!(read_m3u(&write_m3u(&entries())), entries());
+    }
+
+    #[test]
+    fn xspf_round_trip() {
+        let mut with_album = entries();
+        with_album[0].album = Some("Fashion Nugget".into());
+        let out = write_xspf(&with_album);
+        assert!(out.contains("<identifier>subsonic:42</identifier>"));
+        assert!(out.contains("Fish &amp; &lt;Chips&gt;"));
+        assert_eq!(read_xspf(&out).unwrap(), with_album);
+    }
+
+    #[test]
+    fn finds_ids_and_queries() {
+        let stream = Entry {
+            location: Some(
+                "https://music.example/rest/stream?u=me&id=7&c=subs".into(),
+            ),
+            ..Entry::default()
+        };
+        assert_eq!(entries()[0].id(), Some(42));
+        assert_eq!(stream.id(), Some(7));
+        assert_eq!(entries()[1].id(), None);
+
+        assert_eq!(entries()[0].query(), Some("Cake Fish & <Chips>".into()));
+        assert_eq!(entries()[1].query(), Some("Nobody Home".into()));
+    }
+
+    #[test]
+    fn format_needs_a_known_extension() {
+        assert!(format(Path::new("a.m3u8")).is_ok());
+        assert!(format(Path::new("a.XSPF")).is_err());
+        assert!(format(Path::new("a.pls")).is_err());
+    }
+}
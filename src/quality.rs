@@ -0,0 +1,246 @@
+//! How songs are streamed: as the original files, or transcoded by the
+//! server to a lower bitrate or another format.
+//!
+//! `Conf::max_bit_rate` and `Conf::format` apply normally. When the network
+//! is metered, `Conf::metered_bit_rate` and `Conf::metered_format` apply
+//! instead, if either is set. Whether the network is metered comes from
+//! `Conf::metered` if it's set, or from NetworkManager otherwise. `subs
+//! quality` overrides both until the daemon stops.
+
+use dbus::stdintf::org_freedesktop_dbus::Properties;
+use dbus::{BusType, Connection};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use api::Api;
+use conf::Conf;
+
+const NM_DEST: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+/// How long to wait for NetworkManager, in milliseconds.
+const TIMEOUT: i32 = 1000;
+/// How long to trust NetworkManager's answer for, in seconds. Every stream
+/// URL needs it, so asking each time would mean a new bus connection per
+/// song.
+const METERED_TTL: u64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Quality {
+    /// In kbps.
+    pub max_bit_rate: Option<u32>,
+    pub format: Option<String>,
+}
+
+impl Quality {
+    pub fn from_conf(cfg: &Conf) -> Quality {
+        Quality {
+            max_bit_rate: cfg.max_bit_rate,
+            format: cfg.format.clone(),
+        }
+    }
+
+    fn metered(cfg: &Conf) -> Option<Quality> {
+        if cfg.metered_bit_rate.is_none() && cfg.metered_format.is_none() {
+            return None
+        }
+        Some(Quality {
+            max_bit_rate: cfg.metered_bit_rate,
+            format: cfg.metered_format.clone(),
+        })
+    }
+
+    /// Parameters for a `stream` request.
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        if let Some(rate) = self.max_bit_rate {
+            params.push(("maxBitRate", rate.to_string()));
+        }
+        if let Some(ref format) = self.format {
+            params.push(("format", format.clone()));
+        }
+        params
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.max_bit_rate, &self.format) {
+            (None, &None) => write!(f, "original"),
+            (Some(rate), &None) => write!(f, "up to {} kbps", rate),
+            (None, &Some(ref format)) => write!(f, "{}", format),
+            (Some(rate), &Some(ref format)) => {
+                write!(f, "{} up to {} kbps", format, rate)
+            }
+        }
+    }
+}
+
+pub struct Preferences {
+    api: Api,
+    normal: Quality,
+    metered: Option<Quality>,
+    /// Whether the network is metered, if it's set rather than detected.
+    metered_conf: Option<bool>,
+    /// The last answer from NetworkManager, and when it was given.
+    detected: Mutex<Option<(Instant, bool)>>,
+    /// Set with `subs quality`, until the daemon stops.
+    session: Mutex<Option<Quality>>,
+}
+
+impl Preferences {
+    pub fn new(cfg: &Conf) -> Preferences {
+        Preferences {
+            api: Api::new(cfg),
+            normal: Quality::from_conf(cfg),
+            metered: Quality::metered(cfg),
+            metered_conf: cfg.metered,
+            detected: Mutex::new(None),
+            session: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, quality: Option<Quality>) {
+        *self.session.lock().unwrap() = quality;
+    }
+
+    /// The quality to stream at, and whether it was set with `subs quality`.
+    pub fn current(&self) -> (Quality, bool) {
+        if let Some(ref q) = *self.session.lock().unwrap() {
+            return (q.clone(), true)
+        }
+        match self.metered {
+            Some(ref q) if self.is_metered() => (q.clone(), false),
+            _ => (self.normal.clone(), false),
+        }
+    }
+
+    pub fn is_metered(&self) -> bool {
+        if let Some(metered) = self.metered_conf {
+            return metered
+        }
+        self.detect_metered(Instant::now(), network_metered)
+    }
+
+    fn detect_metered<F>(&self, now: Instant, detect: F) -> bool
+    where
+        F: FnOnce() -> Option<bool>,
+    {
+        let mut detected = self.detected.lock().unwrap();
+        match *detected {
+            Some((at, metered))
+                if now.duration_since(at) < Duration::from_secs(METERED_TTL) =>
+            {
+                metered
+            }
+            _ => {
+                let metered = detect().unwrap_or(false);
+                *detected = Some((now, metered));
+                metered
+            }
+        }
+    }
+
+    pub fn stream_url(&self, id: u64) -> String {
+        self.stream_url_at(id, &self.current().0)
+    }
+
+    pub fn stream_url_at(&self, id: u64, quality: &Quality) -> String {
+        let mut params = vec![("id", id.to_string())];
+        params.extend(quality.params());
+        self.api.url("stream", &params)
+    }
+}
+
+/// Asks NetworkManager whether the primary connection is metered, counting
+/// its guesses. `None` if it can't be reached.
+fn network_metered() -> Option<bool> {
+    let conn = match Connection::get_private(BusType::System) {
+        Ok(c) => c,
+        Err(e) => {
+            debug!("unable to connect to system bus: {}", e);
+            return None
+        }
+    };
+    let nm = conn.with_path(NM_DEST, NM_PATH, TIMEOUT);
+    match nm.get::<u32>(NM_DEST, "Metered") {
+        // NM_METERED_YES and NM_METERED_GUESS_YES.
+        Ok(m) => Some(m == 1 || m == 3),
+        Err(e) => {
+            debug!("unable to ask NetworkManager about metering: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Preferences, Quality, METERED_TTL};
+    use conf::Conf;
+
+    fn conf(metered: bool) -> Conf {
+        Conf {
+            max_bit_rate: Some(320),
+            metered: Some(metered),
+            metered_bit_rate: Some(96),
+            metered_format: Some("opus".into()),
+            ..Conf::default()
+        }
+    }
+
+    #[test]
+    fn describes_and_parameterises() {
+        let q = Quality {
+            max_bit_rate: Some(128),
+            format: Some("mp3".into()),
+        };
+        assert_eq!(q.to_string(), "mp3 up to 128 kbps");
+        let params = q.params();
+        assert_eq!(params[0], ("maxBitRate", "128".to_string()));
+        assert_eq!(params[1], ("format", "mp3".to_string()));
+        assert_eq!(Quality::default().to_string(), "original");
+        assert!(Quality::default().params().is_empty());
+    }
+
+    #[test]
+    fn metered_networks_use_their_own_quality() {
+        let normal = Preferences::new(&conf(false));
+        assert_eq!(normal.current().0.max_bit_rate, Some(320));
+
+        let metered = Preferences::new(&conf(true));
+        assert_eq!(metered.current().0.to_string(), "opus up to 96 kbps");
+        assert!(metered.stream_url(3).contains("&maxBitRate=96&format=opus"));
+
+        // Without a metered quality, metering changes nothing.
+        let unset = Preferences::new(&Conf {
+            metered_bit_rate: None,
+            metered_format: None,
+            ..conf(true)
+        });
+        assert_eq!(unset.current(), (Quality::from_conf(&conf(true)), false));
+    }
+
+    #[test]
+    fn session_quality_wins_until_cleared() {
+        let prefs = Preferences::new(&conf(true));
+        prefs.set(Some(Quality::default()));
+        assert_eq!(prefs.current(), (Quality::default(), true));
+        prefs.set(None);
+        assert_eq!(prefs.current().0.max_bit_rate, Some(96));
+    }
+
+    #[test]
+    fn remembers_whether_the_network_is_metered_for_a_while() {
+        let prefs = Preferences::new(&Conf::default());
+        let start = Instant::now();
+        assert!(prefs.detect_metered(start, || Some(true)));
+
+        let soon = start + Duration::from_secs(METERED_TTL - 1);
+        assert!(prefs.detect_metered(soon, || panic!("asked again")));
+
+        let later = start + Duration::from_secs(METERED_TTL);
+        assert!(!prefs.detect_metered(later, || None));
+    }
+}
@@ -1,6 +1,77 @@
+use std::fmt;
+use std::path::Path;
+
+/// Something that can be queued: a song from the server, a local file or
+/// URL played as it is, or one of the server's radio stations.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Source {
+    Song(u64),
+    File(String),
+    Url(String),
+    Radio { name: String, url: String },
+}
+
+impl Source {
+    /// A file or URL given on the command line, if that's what it is.
+    /// Files are made absolute, since the daemon runs somewhere else.
+    pub fn from_arg(arg: &str) -> Option<Source> {
+        if arg.starts_with("http://") || arg.starts_with("https://") {
+            return Some(Source::Url(arg.to_string()))
+        }
+        let path = Path::new(arg);
+        if path.is_file() {
+            let path = path.canonicalize().ok()?;
+            return Some(Source::File(path.to_string_lossy().into_owned()))
+        }
+        None
+    }
+
+    pub fn song(&self) -> Option<u64> {
+        match *self {
+            Source::Song(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// A name to show until the file's tags have been read.
+    pub fn name(&self) -> String {
+        match *self {
+            Source::Song(id) => format!("song {}", id),
+            Source::File(ref path) => Path::new(path)
+                .file_name()
+                .map(|f| f.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.clone()),
+            Source::Url(ref url) => url.clone(),
+            Source::Radio { ref name, .. } => name.clone(),
+        }
+    }
+
+    /// Where it's played from, for anything that isn't a song.
+    pub fn location(&self) -> Option<&str> {
+        match *self {
+            Source::Song(_) => None,
+            Source::File(ref path) => Some(path),
+            Source::Url(ref url) | Source::Radio { ref url, .. } => Some(url),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Song(id) => write!(f, "song {}", id),
+            Source::File(ref path) => write!(f, "{}", path),
+            Source::Url(ref url) => write!(f, "{}", url),
+            Source::Radio { ref name, ref url } => {
+                write!(f, "{} <{}>", name, url)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Queue {
-    songs: Vec<usize>,
+    songs: Vec<Source>,
     position: usize,
 }
 
@@ -16,13 +87,32 @@ impl Queue {
 
     pub fn len(&self) -> usize { self.songs.len() }
 
+    pub fn songs(&self) -> &[Source] { &self.songs }
+
+    /// Up to `n` songs after the current one.
+    pub fn upcoming(&self, n: usize) -> &[Source] {
+        let start = ::std::cmp::min(self.position + 1, self.len());
+        let end = ::std::cmp::min(start + n, self.len());
+        &self.songs[start..end]
+    }
+
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 
-    pub fn append(&mut self, song: usize) { self.songs.push(song); }
+    pub fn append(&mut self, song: Source) { self.songs.push(song); }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+        self.position = 0;
+    }
 
-    pub fn clear(&mut self) { self.songs.clear() }
+    /// Puts a song in place of the current one, moving it and everything
+    /// after it along.
+    pub fn insert_current(&mut self, song: Source) {
+        let at = ::std::cmp::min(self.position, self.len());
+        self.songs.insert(at, song);
+    }
 
-    pub fn insert_next(&mut self, song: usize) {
+    pub fn insert_next(&mut self, song: Source) {
         if self.position == self.len() {
             self.songs.push(song)
         } else {
@@ -30,8 +120,15 @@ impl Queue {
         }
     }
 
-    pub fn current(&self) -> Option<usize> {
-        self.songs.get(self.position).map(|i| *i)
+    pub fn current(&self) -> Option<Source> {
+        self.songs.get(self.position).cloned()
+    }
+
+    /// Moves to the entry at `pos`, if there is one.
+    pub fn jump(&mut self, pos: usize) -> Option<Source> {
+        let song = self.songs.get(pos).cloned()?;
+        self.position = pos;
+        Some(song)
     }
 
     pub fn has_next(&self) -> bool {
@@ -40,38 +137,125 @@ impl Queue {
             || (self.len() - 2 >= self.position)
     }
 
-    pub fn next(&mut self) -> Option<usize> {
+    pub fn next(&mut self) -> Option<Source> {
         if !self.is_empty() && self.position <= self.len() - 1 {
             self.position += 1;
-            Some(self.songs[self.position])
+            self.songs.get(self.position).cloned()
         } else {
             None
         }
     }
 
-    pub fn prev(&mut self) -> Option<usize> {
+    pub fn prev(&mut self) -> Option<Source> {
         if !self.is_empty() {
             if self.position == 0 {
             } else {
                 self.position -= 1;
             }
-            Some(self.songs[self.position])
+            Some(self.songs[self.position].clone())
         } else {
             None
         }
     }
 
-    pub fn prev2(&mut self) -> Option<usize> {
+    pub fn prev2(&mut self) -> Option<Source> {
         self.prev();
         self.prev()
     }
 }
 
-impl ::std::iter::Extend<usize> for Queue {
+impl ::std::iter::Extend<Source> for Queue {
     fn extend<T>(&mut self, iter: T)
     where
-        T: IntoIterator<Item = usize>,
+        T: IntoIterator<Item = Source>,
     {
         self.songs.extend(iter);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+
+    use super::{Queue, Source};
+
+    fn queue(n: u64) -> Queue {
+        let mut q = Queue::new();
+        q.extend((1..n + 1).map(Source::Song));
+        q
+    }
+
+    #[test]
+    fn sources_from_args() {
+        let url = "https://radio.example/stream.ogg";
+        assert_eq!(Source::from_arg(url), Some(Source::Url(url.into())));
+        assert_eq!(Source::from_arg("/no/such/file.flac"), None);
+
+        let dir = env::temp_dir().join("subs-test-queue");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("a.ogg")).unwrap();
+        let file = Source::from_arg(dir.join("a.ogg").to_str().unwrap());
+        let path = dir.canonicalize().unwrap().join("a.ogg");
+        assert_eq!(file, Some(Source::File(path.to_string_lossy().into())));
+        assert_eq!(file.unwrap().name(), "a.ogg");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_and_locations() {
+        let radio = Source::Radio {
+            name: "KEXP".into(),
+            url: "http://kexp.example/live".into(),
+        };
+        assert_eq!(radio.name(), "KEXP");
+        assert_eq!(radio.location(), Some("http://kexp.example/live"));
+        assert_eq!(radio.to_string(), "KEXP <http://kexp.example/live>");
+        assert_eq!(Source::Song(3).location(), None);
+        assert_eq!(Source::Song(3).song(), Some(3));
+        assert_eq!(radio.song(), None);
+    }
+
+    #[test]
+    fn upcoming_stops_at_the_end() {
+        let mut q = queue(4);
+        assert_eq!(q.upcoming(2), &[Source::Song(2), Source::Song(3)]);
+        q.next();
+        q.next();
+        assert_eq!(q.upcoming(2), &[Source::Song(4)]);
+        q.next();
+        q.next();
+        assert!(q.upcoming(2).is_empty());
+    }
+
+    #[test]
+    fn insert_current_replaces_what_plays() {
+        let mut q = queue(3);
+        q.next();
+        q.insert_current(Source::Url("http://a.example/".into()));
+        assert_eq!(q.current(), Some(Source::Url("http://a.example/".into())));
+        assert_eq!(q.upcoming(1), &[Source::Song(2)]);
+        assert_eq!(q.len(), 4);
+    }
+
+    #[test]
+    fn jumps_only_within_the_queue() {
+        let mut q = queue(3);
+        assert_eq!(q.jump(2), Some(Source::Song(3)));
+        assert_eq!(q.position(), 2);
+        assert_eq!(q.jump(3), None);
+        assert_eq!(q.position(), 2);
+    }
+
+    #[test]
+    fn clear_starts_over() {
+        let mut q = queue(3);
+        q.next();
+        q.next();
+        q.clear();
+        assert!(q.is_empty());
+        assert_eq!(q.position(), 0);
+        q.append(Source::Song(9));
+        assert_eq!(q.current(), Some(Source::Song(9)));
+    }
+}
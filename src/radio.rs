@@ -0,0 +1,185 @@
+//! Internet radio stations saved on the server.
+
+use std::fmt;
+
+use api::Api;
+use error::Error;
+use library::items;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Station {
+    pub id: String,
+    pub name: String,
+    pub stream_url: String,
+    pub home_page: Option<String>,
+}
+
+impl fmt::Display for Station {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} <{}>", self.name, self.stream_url)
+    }
+}
+
+pub fn list(api: &Api) -> ::std::result::Result<Vec<Station>, Error> {
+    let res = api.call("getInternetRadioStations", &[])?;
+    Ok(items(&res["internetRadioStations"]["internetRadioStation"])
+        .iter()
+        .filter_map(|s| {
+            Some(Station {
+                id: s["id"]
+                    .as_str()
+                    .map(String::from)
+                    .or_else(|| s["id"].as_u64().map(|i| i.to_string()))?,
+                name: s["name"].as_str()?.to_string(),
+                stream_url: s["streamUrl"].as_str()?.to_string(),
+                home_page: s["homePageUrl"].as_str().map(String::from),
+            })
+        })
+        .collect())
+}
+
+/// Finds a station by name, ignoring case, preferring an exact match to one
+/// that only contains the name.
+pub fn find(api: &Api, name: &str) -> ::std::result::Result<Station, Error> {
+    let wanted = name.trim().to_lowercase();
+    let stations = list(api)?;
+    let exact = stations.iter().position(|s| s.name.to_lowercase() == wanted);
+    let partial = || {
+        stations
+            .iter()
+            .position(|s| s.name.to_lowercase().contains(&wanted))
+    };
+    match exact.or_else(partial) {
+        Some(i) => Ok(stations[i].clone()),
+        None => Err(not_found(name)),
+    }
+}
+
+/// Finds a station by its whole name, ignoring case. Used where a wrong
+/// guess can't be undone.
+pub fn find_exact(
+    api: &Api,
+    name: &str,
+) -> ::std::result::Result<Station, Error> {
+    let wanted = name.trim().to_lowercase();
+    list(api)?
+        .into_iter()
+        .find(|s| s.name.to_lowercase() == wanted)
+        .ok_or_else(|| not_found(name))
+}
+
+fn not_found(name: &str) -> Error {
+    Error::Response(format!("No station called \"{}\".", name))
+}
+
+pub fn create(
+    api: &Api,
+    name: &str,
+    stream_url: &str,
+    home_page: Option<&str>,
+) -> ::std::result::Result<(), Error> {
+    let mut params = vec![
+        ("name", name.to_string()),
+        ("streamUrl", stream_url.to_string()),
+    ];
+    if let Some(home_page) = home_page {
+        params.push(("homepageUrl", home_page.to_string()));
+    }
+    api.call("createInternetRadioStation", &params)
+        .map_err(admin)?;
+    Ok(())
+}
+
+pub fn delete(api: &Api, id: &str) -> ::std::result::Result<(), Error> {
+    api.call("deleteInternetRadioStation", &[("id", id.to_string())])
+        .map_err(admin)?;
+    Ok(())
+}
+
+/// Changing stations needs the radio role, which the server reports as an
+/// authorisation failure.
+fn admin(err: Error) -> Error {
+    match err {
+        Error::Service(ref msg) if msg.to_lowercase().contains("not auth") => {
+            Error::Response(
+                "Only users allowed to manage radio stations can do that; \
+                 ask your server's admin to give you that permission."
+                    .into(),
+            )
+        }
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{admin, create, find, find_exact, list};
+    use api::Api;
+    use conf::Conf;
+    use error::Error;
+    use testhttp::serve;
+
+    const STATIONS: &str = r#"{"subsonic-response": {"status": "ok",
+        "internetRadioStations": {"internetRadioStation": [
+            {"id": "1", "name": "Radio Paradise Mellow",
+             "streamUrl": "http://rp.example/mellow"},
+            {"id": 2, "name": "Radio Paradise",
+             "streamUrl": "http://rp.example/main",
+             "homePageUrl": "https://rp.example"},
+            {"id": "3", "name": "Missing a stream"}
+        ]}}}"#;
+
+    fn api(responses: Vec<(u16, &'static str)>) -> Api {
+        Api::new(&Conf {
+            url: serve(responses).0,
+            ..Conf::default()
+        })
+    }
+
+    #[test]
+    fn lists_complete_stations() {
+        let stations = list(&api(vec![(200, STATIONS)])).unwrap();
+        assert_eq!(stations.len(), 2);
+        assert_eq!(stations[1].id, "2");
+        assert_eq!(
+            stations[1].home_page,
+            Some("https://rp.example".to_string())
+        );
+        assert_eq!(
+            stations[0].to_string(),
+            "Radio Paradise Mellow <http://rp.example/mellow>"
+        );
+    }
+
+    #[test]
+    fn finds_exact_names_first() {
+        let api = api(vec![(200, STATIONS), (200, STATIONS), (200, STATIONS)]);
+        assert_eq!(find(&api, " radio paradise ").unwrap().id, "2");
+        assert_eq!(find(&api, "mellow").unwrap().id, "1");
+        assert_eq!(
+            find(&api, "KEXP").unwrap_err().to_string(),
+            "Error from daemon: No station called \"KEXP\"."
+        );
+    }
+
+    #[test]
+    fn exact_lookup_ignores_partial_names() {
+        let api = api(vec![(200, STATIONS), (200, STATIONS)]);
+        assert_eq!(find_exact(&api, "Radio paradise ").unwrap().id, "2");
+        assert!(find_exact(&api, "mellow").is_err());
+    }
+
+    #[test]
+    fn explains_missing_permissions() {
+        let denied = r#"{"subsonic-response": {"status": "failed",
+            "error": {"code": 50, "message": "User is not authorized"}}}"#;
+        let err = create(&api(vec![(200, denied)]), "KEXP", "http://k", None);
+        match err {
+            Err(Error::Response(msg)) => assert!(msg.contains("radio")),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let other = Error::Service("Server is down".into());
+        assert_eq!(admin(other).to_string(), "Server is down");
+    }
+}
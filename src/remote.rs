@@ -0,0 +1,231 @@
+//! Remote control of the daemon over TCP.
+//!
+//! A connection starts with the daemon sending a random nonce on its own
+//! line. The client answers with the SHA-256 digest of the nonce followed by
+//! the shared secret, in hex and on its own line, and then continues exactly
+//! as it would over the Unix socket.
+
+use rand::{self, Rng};
+use serde_json;
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use daemon::{self, Command, Reply};
+use error::{Error, Result};
+
+/// How long a client has to authenticate and send its command.
+const TIMEOUT: u64 = 10;
+
+/// Starts listening for remote clients on `addr` in the background.
+pub fn spawn(addr: &str, secret: Option<String>, socket: &Path) -> Result {
+    let secret = match secret {
+        Some(ref s) if !s.is_empty() => s.clone(),
+        _ => {
+            return Err(
+                "A secret is required to listen for remote clients".into(),
+            )
+        }
+    };
+
+    let listener = TcpListener::bind(addr)?;
+    info!("listening for remote clients on {}", addr);
+
+    let socket = socket.to_path_buf();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    let socket = socket.clone();
+                    let secret = secret.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(s, &secret, &socket) {
+                            error!("remote client failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("unable to accept remote client: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Connects and authenticates to a daemon on another machine.
+pub fn connect(
+    host: &str,
+    secret: Option<&str>,
+) -> ::std::result::Result<TcpStream, Error> {
+    let secret = match secret {
+        Some(s) => s,
+        None => {
+            return Err("`SUBS_SECRET` must be set to use a remote host".into())
+        }
+    };
+
+    let mut stream = TcpStream::connect(host)?;
+    let nonce = {
+        let mut reader = BufReader::new(&stream);
+        let mut nonce = String::new();
+        reader.read_line(&mut nonce)?;
+        nonce.trim().to_string()
+    };
+    debug!("authenticating to {}", host);
+    stream.write_all(format!("{}\n", digest(&nonce, secret)).as_bytes())?;
+
+    Ok(stream)
+}
+
+fn serve(mut stream: TcpStream, secret: &str, socket: &Path) -> Result {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+
+    let nonce = nonce();
+    stream.write_all(format!("{}\n", nonce).as_bytes())?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut answer = String::new();
+    reader.read_line(&mut answer)?;
+    if !eq(answer.trim().as_bytes(), digest(&nonce, secret).as_bytes()) {
+        warn!("remote client {} failed to authenticate", peer);
+        let reply = Reply::Error("Authentication failed".into());
+        stream.write_all(serde_json::to_string(&reply)?.as_bytes())?;
+        return Ok(())
+    }
+
+    let mut buf = String::new();
+    reader.read_to_string(&mut buf)?;
+    let cmd = serde_json::from_str::<Command>(&buf)?;
+    debug!("remote client {} sent {:?}", peer, cmd);
+
+    // Subscriptions stay open, so pass events along until either side
+    // hangs up.
+    if let Command::Subscribe = cmd {
+        stream.set_read_timeout(None)?;
+        let mut events = daemon::subscribe_to(socket)?;
+        io::copy(&mut events, &mut stream)?;
+        return Ok(())
+    }
+
+    let reply = match daemon::send_recv_to(socket, cmd) {
+        Ok(r) => r,
+        Err(Error::Response(e)) => Reply::Error(e),
+        Err(e) => Reply::Error(e.to_string()),
+    };
+    // Fire-and-forget clients will have hung up already.
+    let _ = stream.write_all(serde_json::to_string(&reply)?.as_bytes());
+
+    Ok(())
+}
+
+fn nonce() -> String {
+    let mut rng = rand::thread_rng();
+    format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>())
+}
+
+fn digest(nonce: &str, secret: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(nonce.as_bytes());
+    hasher.input(secret.as_bytes());
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compares without bailing out early, so the time taken doesn't leak how
+/// much of the answer was right.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::process;
+    use std::thread;
+
+    use super::{connect, digest, eq, serve};
+    use daemon::{Command, Reply};
+
+    /// Answers every request on a socket with the request itself, standing
+    /// in for the daemon.
+    fn fake_daemon() -> PathBuf {
+        let socket =
+            env::temp_dir().join(format!("subs-remote-{}", process::id()));
+        let _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut req = String::new();
+                stream.read_to_string(&mut req).unwrap();
+                let reply = serde_json::to_string(&Reply::Other(req));
+                stream.write_all(reply.unwrap().as_bytes()).unwrap();
+            }
+        });
+        socket
+    }
+
+    /// Sends `cmd`, if given, to a daemon listening remotely on `addr`.
+    fn ask(addr: &str, secret: &str, cmd: Option<Command>) -> Reply {
+        let mut stream = connect(addr, Some(secret)).unwrap();
+        if let Some(cmd) = cmd {
+            let json = serde_json::to_string(&cmd).unwrap();
+            stream.write_all(json.as_bytes()).unwrap();
+        }
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        serde_json::from_str(&reply).unwrap()
+    }
+
+    #[test]
+    fn digest_is_sha256_of_nonce_and_secret() {
+        assert_eq!(
+            digest("a", "bc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn eq_compares_whole_slices() {
+        assert!(eq(b"abc", b"abc"));
+        assert!(!eq(b"abc", b"abd"));
+        assert!(!eq(b"abc", b"ab"));
+        assert!(eq(b"", b""));
+    }
+
+    #[test]
+    fn forwards_only_authenticated_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let socket = fake_daemon();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                serve(stream.unwrap(), "hunter2", &socket).unwrap();
+            }
+        });
+
+        match ask(&addr, "hunter2", Some(Command::StatusReq)) {
+            Reply::Other(req) => assert_eq!(req, "\"StatusReq\""),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        match ask(&addr, "hunter3", None) {
+            Reply::Error(e) => assert_eq!(e, "Authentication failed"),
+            r => panic!("unexpected reply {:?}", r),
+        }
+    }
+}
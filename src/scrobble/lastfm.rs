@@ -0,0 +1,221 @@
+use md5;
+use reqwest;
+use std::collections::BTreeMap;
+
+use conf::Conf;
+use error::{Error, Result};
+use super::{Backend, Listen, Rejection};
+
+/// Last.fm accepts at most this many scrobbles per request.
+const BATCH_SIZE: usize = 50;
+/// Error codes for problems that might clear up: authentication failed,
+/// invalid session key, invalid API key, service offline, temporarily
+/// unavailable, suspended API key and rate limit exceeded.
+const RETRY_ERRORS: &[u32] = &[4, 9, 10, 11, 16, 26, 29];
+
+/// Reports plays to Last.fm, using a session key the user has already
+/// authorised.
+pub struct LastFm {
+    http: reqwest::Client,
+    url: String,
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    error: Option<u32>,
+    message: Option<String>,
+}
+
+impl LastFm {
+    pub fn from_conf(cfg: &Conf) -> Option<LastFm> {
+        Some(LastFm {
+            http: reqwest::Client::new(),
+            url: cfg.lastfm_url.clone(),
+            api_key: cfg.lastfm_api_key.clone()?,
+            api_secret: cfg.lastfm_api_secret.clone()?,
+            session_key: cfg.lastfm_session_key.clone()?,
+        })
+    }
+
+    /// Calls a signed API method.
+    fn call(
+        &self,
+        method: &str,
+        mut params: BTreeMap<String, String>,
+    ) -> ::std::result::Result<(), Rejection> {
+        params.insert("method".into(), method.into());
+        params.insert("api_key".into(), self.api_key.clone());
+        params.insert("sk".into(), self.session_key.clone());
+
+        // The signature is the MD5 of every parameter, sorted by name,
+        // followed by the API secret.
+        let mut sig = params.iter().fold(String::new(), |s, (k, v)| s + k + v);
+        sig.push_str(&self.api_secret);
+        params.insert("api_sig".into(), format!("{:x}", md5::compute(sig)));
+        params.insert("format".into(), "json".into());
+
+        let mut res = self.http.post(&self.url).form(&params).send()?;
+        let body: Response = res.json()?;
+        match body.error {
+            Some(code) => {
+                let err = Error::Service(format!(
+                    "Last.fm error {}: {}",
+                    code,
+                    body.message.unwrap_or_default()
+                ));
+                Err(if RETRY_ERRORS.contains(&code) {
+                    Rejection::Retry(err)
+                } else {
+                    Rejection::Permanent(err)
+                })
+            }
+            None if !res.status().is_success() => {
+                Err(Rejection::Retry(Error::Service(format!(
+                    "Last.fm responded with {}",
+                    res.status()
+                ))))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Backend for LastFm {
+    fn name(&self) -> &'static str { "lastfm" }
+
+    fn now_playing(&self, listen: &Listen) -> Result {
+        let mut params = BTreeMap::new();
+        match listen.artist {
+            Some(ref artist) => params.insert("artist".into(), artist.clone()),
+            None => return Ok(()),
+        };
+        params.insert("track".into(), listen.title.clone());
+        if let Some(ref album) = listen.album {
+            params.insert("album".into(), album.clone());
+        }
+        if listen.duration > 0 {
+            params.insert("duration".into(), listen.duration.to_string());
+        }
+
+        Ok(self.call("track.updateNowPlaying", params)?)
+    }
+
+    fn submit(
+        &self,
+        listens: &[Listen],
+    ) -> ::std::result::Result<(), Rejection> {
+        // Last.fm needs an artist for every scrobble.
+        let mut params = BTreeMap::new();
+        let listens = listens.iter().filter(|l| l.artist.is_some());
+        for (i, listen) in listens.enumerate() {
+            let mut add = |k: &str, v: String| {
+                params.insert(format!("{}[{}]", k, i), v);
+            };
+            add("artist", listen.artist.clone().unwrap_or_default());
+            add("track", listen.title.clone());
+            add("timestamp", listen.started.to_string());
+            if let Some(ref album) = listen.album {
+                add("album", album.clone());
+            }
+            if let Some(track) = listen.track {
+                add("trackNumber", track.to_string());
+            }
+            if listen.duration > 0 {
+                add("duration", listen.duration.to_string());
+            }
+        }
+
+        if params.is_empty() {
+            return Ok(())
+        }
+        self.call("track.scrobble", params)
+    }
+
+    fn batch_size(&self) -> usize { BATCH_SIZE }
+}
+
+#[cfg(test)]
+mod tests {
+    use md5;
+    use reqwest;
+    use std::collections::BTreeMap;
+
+    use super::super::tests::{listen, temp_dir};
+    use super::super::Queue;
+    use super::LastFm;
+    use testhttp::serve;
+
+    /// Reads a form body back into its parameters.
+    fn form(body: &str) -> BTreeMap<String, String> {
+        body.split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                Some((decode(kv.next()?), decode(kv.next()?)))
+            })
+            .collect()
+    }
+
+    fn decode(s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'+' => out.push(b' '),
+                b'%' => {
+                    let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]);
+                    out.push(u8::from_str_radix(hex.unwrap(), 16).unwrap());
+                    i += 2;
+                }
+                b => out.push(b),
+            }
+            i += 1;
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn signs_requests_and_keeps_failed_scrobbles() {
+        let (url, requests) = serve(vec![
+            (200, r#"{"error":11,"message":"Service Offline"}"#),
+            (200, r#"{"scrobbles":{}}"#),
+        ]);
+        let lastfm = LastFm {
+            http: reqwest::Client::new(),
+            url,
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            session_key: "session".into(),
+        };
+        let dir = temp_dir("lastfm");
+        let mut queue = Queue::load(Box::new(lastfm), &dir);
+
+        queue.submit(listen());
+        assert_eq!(queue.pending.len(), 1);
+        assert!(dir.join("lastfm.json").exists());
+
+        let first = form(&requests.recv().unwrap().1);
+        assert_eq!(first["method"], "track.scrobble");
+        assert_eq!(first["api_key"], "key");
+        assert_eq!(first["sk"], "session");
+        assert_eq!(first["artist[0]"], "Michael Jackson");
+        assert_eq!(first["track[0]"], "Bad");
+        assert_eq!(first["timestamp[0]"], "1500000000");
+        let mut sig = first
+            .iter()
+            .filter(|&(k, _)| k != "api_sig" && k != "format")
+            .fold(String::new(), |s, (k, v)| s + k + v);
+        sig.push_str("secret");
+        assert_eq!(first["api_sig"], format!("{:x}", md5::compute(sig)));
+
+        // Once Last.fm is back, the same scrobble goes through.
+        queue.flush();
+        assert!(queue.pending.is_empty());
+        let second = form(&requests.recv().unwrap().1);
+        assert_eq!(second["track[0]"], "Bad");
+        assert_eq!(second["timestamp[0]"], "1500000000");
+    }
+}
@@ -0,0 +1,179 @@
+use reqwest;
+use reqwest::header::Authorization;
+
+use conf::Conf;
+use error::{Error, Result};
+use super::{Backend, Listen, Rejection};
+
+/// ListenBrainz accepts at most this many listens per request.
+const BATCH_SIZE: usize = 100;
+
+/// Reports plays to ListenBrainz using a user token.
+pub struct ListenBrainz {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Submission<'a> {
+    listen_type: &'static str,
+    payload: Vec<Payload<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listened_at: Option<u64>,
+    track_metadata: Metadata<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct Metadata<'a> {
+    artist_name: &'a str,
+    track_name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_name: Option<&'a str>,
+    additional_info: AdditionalInfo,
+}
+
+#[derive(Debug, Serialize)]
+struct AdditionalInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tracknumber: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+    media_player: &'static str,
+}
+
+impl ListenBrainz {
+    pub fn from_conf(cfg: &Conf) -> Option<ListenBrainz> {
+        Some(ListenBrainz {
+            http: reqwest::Client::new(),
+            url: format!(
+                "{}/1/submit-listens",
+                cfg.listenbrainz_url.trim_end_matches('/')
+            ),
+            token: cfg.listenbrainz_token.clone()?,
+        })
+    }
+
+    fn send(
+        &self,
+        listen_type: &'static str,
+        listens: &[Listen],
+        timestamps: bool,
+    ) -> ::std::result::Result<(), Rejection> {
+        // ListenBrainz needs an artist for every listen.
+        let payload = listens
+            .iter()
+            .filter_map(|l| {
+                let artist = l.artist.as_ref()?;
+                Some(Payload {
+                    listened_at: if timestamps {
+                        Some(l.started)
+                    } else {
+                        None
+                    },
+                    track_metadata: Metadata {
+                        artist_name: artist,
+                        track_name: &l.title,
+                        release_name: l.album.as_ref().map(|a| a.as_str()),
+                        additional_info: AdditionalInfo {
+                            tracknumber: l.track,
+                            duration: if l.duration > 0 {
+                                Some(l.duration)
+                            } else {
+                                None
+                            },
+                            media_player: "subs",
+                        },
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if payload.is_empty() {
+            return Ok(())
+        }
+
+        let res = self.http
+            .post(&self.url)
+            .header(Authorization(format!("Token {}", self.token)))
+            .json(&Submission {
+                listen_type,
+                payload,
+            })
+            .send()?;
+
+        let status = res.status();
+        if status.is_success() {
+            return Ok(())
+        }
+        let err =
+            Error::Service(format!("ListenBrainz responded with {}", status));
+        // Anything but a bad listen might be sorted out later, whether it's
+        // the server, the token or the rate limit.
+        match status.as_u16() {
+            400 => Err(Rejection::Permanent(err)),
+            _ => Err(Rejection::Retry(err)),
+        }
+    }
+}
+
+impl Backend for ListenBrainz {
+    fn name(&self) -> &'static str { "listenbrainz" }
+
+    fn now_playing(&self, listen: &Listen) -> Result {
+        Ok(self.send("playing_now", &[listen.clone()], false)?)
+    }
+
+    fn submit(
+        &self,
+        listens: &[Listen],
+    ) -> ::std::result::Result<(), Rejection> {
+        let listen_type = if listens.len() == 1 { "single" } else { "import" };
+        self.send(listen_type, listens, true)
+    }
+
+    fn batch_size(&self) -> usize { BATCH_SIZE }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest;
+    use serde_json::{self, Value};
+
+    use super::super::tests::{listen, temp_dir};
+    use super::super::Queue;
+    use super::ListenBrainz;
+    use testhttp::serve;
+
+    #[test]
+    fn sends_token_and_keeps_failed_listens() {
+        let (url, requests) = serve(vec![(503, "{}"), (200, "{}")]);
+        let lb = ListenBrainz {
+            http: reqwest::Client::new(),
+            url,
+            token: "token".into(),
+        };
+        let dir = temp_dir("listenbrainz");
+        let mut queue = Queue::load(Box::new(lb), &dir);
+
+        queue.submit(listen());
+        assert_eq!(queue.pending.len(), 1);
+
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.contains("Authorization: Token token"));
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["listen_type"], "single");
+        let listen = &body["payload"][0];
+        assert_eq!(listen["listened_at"], 1_500_000_000);
+        assert_eq!(listen["track_metadata"]["artist_name"], "Michael Jackson");
+        assert_eq!(listen["track_metadata"]["track_name"], "Bad");
+
+        queue.flush();
+        assert!(queue.pending.is_empty());
+        requests.recv().unwrap();
+    }
+}
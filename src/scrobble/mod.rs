@@ -0,0 +1,752 @@
+//! Reports plays to the Subsonic server and other scrobbling services.
+//!
+//! A song is reported as now playing when it starts, and submitted once half
+//! of it (or four minutes, whichever comes first) has been played. Time spent
+//! paused doesn't count, and songs shorter than 30 seconds are never
+//! submitted.
+//!
+//! Each backend keeps its own queue of submissions that haven't gone
+//! through yet, saved in the cache directory so they survive restarts. The
+//! queue is retried whenever the backend next responds. Submissions a
+//! backend will never accept, such as songs since deleted from the server,
+//! are dropped so they don't hold up the rest.
+//!
+//! Song details come from the library index if it's there, or the server
+//! otherwise. Songs played while neither has them, such as cached songs
+//! played offline, are held by ID along with how long they were played for,
+//! and filled in once the server can be reached again.
+
+mod lastfm;
+mod listenbrainz;
+mod subsonic;
+
+use crossbeam_channel::RecvTimeoutError;
+use reqwest;
+use serde_json;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sunk::song::Song;
+use sunk::Client;
+
+use conf::Conf;
+use error::{Error, Result};
+use event::{Bus, Event};
+use library::{Library, SongEntry};
+
+use self::lastfm::LastFm;
+use self::listenbrainz::ListenBrainz;
+use self::subsonic::Subsonic;
+
+/// Songs shorter than this, in seconds, aren't submitted.
+const MIN_DURATION: u64 = 30;
+/// Songs are submitted after this many seconds even if that's less than half.
+const MAX_THRESHOLD: u64 = 240;
+/// Most submissions to hold on to while a backend is unreachable.
+const MAX_PENDING: usize = 500;
+
+/// Somewhere plays are reported to.
+pub trait Backend: Send {
+    /// Short name for the backend, used to name its saved queue.
+    fn name(&self) -> &'static str;
+
+    fn now_playing(&self, listen: &Listen) -> Result;
+
+    /// Submits finished listens, oldest first. Either all of them go through
+    /// or none do.
+    fn submit(
+        &self,
+        listens: &[Listen],
+    ) -> ::std::result::Result<(), Rejection>;
+
+    /// Most listens that can be submitted at once.
+    fn batch_size(&self) -> usize { 1 }
+}
+
+/// Why a backend didn't take a submission.
+#[derive(Debug)]
+pub enum Rejection {
+    /// It might go through later, as when the service can't be reached.
+    Retry(Error),
+    /// It never will, so there's no point keeping it.
+    Permanent(Error),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::Retry(ref e) | Rejection::Permanent(ref e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// Not getting an answer is worth another try.
+impl From<reqwest::Error> for Rejection {
+    fn from(err: reqwest::Error) -> Rejection { Rejection::Retry(err.into()) }
+}
+
+impl From<Rejection> for Error {
+    fn from(r: Rejection) -> Error {
+        match r {
+            Rejection::Retry(e) | Rejection::Permanent(e) => e,
+        }
+    }
+}
+
+/// A song that has been listened to.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Listen {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u64>,
+    pub duration: u64,
+    /// When the song started, in seconds since the epoch.
+    pub started: u64,
+    /// Seconds played, for listens held until their details were known.
+    #[serde(default)]
+    pub played: Option<u64>,
+}
+
+impl Listen {
+    fn new(song: &Song) -> Listen {
+        Listen {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            track: song.track.map(|t| t as u64),
+            duration: song.duration.map(|d| d as u64).unwrap_or(0),
+            started: now(),
+            played: None,
+        }
+    }
+
+    fn from_entry(song: &SongEntry) -> Listen {
+        Listen {
+            id: song.id,
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            track: song.track,
+            duration: song.duration,
+            started: now(),
+            played: None,
+        }
+    }
+
+    /// A listen known only by its ID, to be filled in later.
+    fn unresolved(id: u64) -> Listen {
+        Listen {
+            id,
+            title: String::new(),
+            artist: None,
+            album: None,
+            track: None,
+            duration: 0,
+            started: now(),
+            played: None,
+        }
+    }
+
+    fn is_resolved(&self) -> bool { !self.title.is_empty() }
+
+    /// Takes the details of another listen of the same song, keeping when
+    /// this one started and how long it was played.
+    fn fill(&mut self, other: Listen) {
+        *self = Listen {
+            started: self.started,
+            played: self.played,
+            ..other
+        };
+    }
+
+    /// Whether enough of the song was played for it to be submitted, for
+    /// listens whose play time was recorded.
+    fn counts(&self) -> bool {
+        match (self.played, threshold(self.duration)) {
+            (None, _) => true,
+            (Some(played), Some(t)) => played >= t,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the epoch")
+        .as_secs()
+}
+
+/// How many seconds of a song need to be played before it's submitted, if
+/// it's long enough to be submitted at all.
+fn threshold(duration: u64) -> Option<u64> {
+    if duration < MIN_DURATION {
+        None
+    } else {
+        Some(::std::cmp::min(duration / 2, MAX_THRESHOLD))
+    }
+}
+
+/// Starts reporting plays in the background to each configured backend.
+pub fn spawn(
+    cfg: &Conf,
+    client: Arc<Mutex<Client>>,
+    library: Arc<Library>,
+    bus: &Bus,
+) {
+    let mut backends: Vec<Box<Backend>> = Vec::new();
+    if cfg.scrobble {
+        backends.push(Box::new(Subsonic::new(client.clone())));
+    }
+    if let Some(lastfm) = LastFm::from_conf(cfg) {
+        backends.push(Box::new(lastfm));
+    }
+    if let Some(lb) = ListenBrainz::from_conf(cfg) {
+        backends.push(Box::new(lb));
+    }
+
+    if backends.is_empty() {
+        return
+    }
+
+    let dir = cfg.cache.join("scrobbles");
+    if let Err(e) = fs::create_dir_all(&dir) {
+        warn!("unable to create {}: {}", dir.display(), e);
+    }
+    let queues = backends
+        .into_iter()
+        .map(|b| Queue::load(b, &dir))
+        .collect();
+
+    let held = dir.join("unresolved.json");
+    let events = bus.subscribe();
+    thread::spawn(move || {
+        let mut scrobbler = Scrobbler::new(client, library, queues, held);
+        loop {
+            let ev = match scrobbler.until_threshold() {
+                Some(t) => events.recv_timeout(t),
+                None => events
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match ev {
+                Ok(ev) => scrobbler.handle(ev),
+                Err(RecvTimeoutError::Timeout) => scrobbler.submit(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+/// A backend along with the listens it hasn't accepted yet.
+struct Queue {
+    backend: Box<Backend>,
+    pending: VecDeque<Listen>,
+    path: PathBuf,
+}
+
+impl Queue {
+    fn load(backend: Box<Backend>, dir: &Path) -> Queue {
+        let path = dir.join(format!("{}.json", backend.name()));
+        let pending = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+            .unwrap_or_default();
+
+        Queue {
+            backend,
+            pending,
+            path,
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.try_save() {
+            warn!("unable to save {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn try_save(&self) -> Result {
+        let file = File::create(&self.path)?;
+        serde_json::to_writer(file, &self.pending)?;
+        Ok(())
+    }
+
+    fn now_playing(&mut self, listen: &Listen) {
+        match self.backend.now_playing(listen) {
+            Ok(_) => if !self.pending.is_empty() {
+                self.flush()
+            },
+            Err(e) => warn!(
+                "unable to send now playing to {}: {}",
+                self.backend.name(),
+                e
+            ),
+        }
+    }
+
+    fn submit(&mut self, listen: Listen) {
+        self.pending.push_back(listen);
+        while self.pending.len() > MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        // Set once a batch is turned down for good, to find the listens at
+        // fault by sending the rest one at a time.
+        let mut single = false;
+        while !self.pending.is_empty() {
+            let size = if single { 1 } else { self.backend.batch_size() };
+            let n = ::std::cmp::min(size, self.pending.len());
+            let batch =
+                self.pending.iter().take(n).cloned().collect::<Vec<_>>();
+            match self.backend.submit(&batch) {
+                Ok(()) => (),
+                Err(Rejection::Retry(e)) => {
+                    warn!(
+                        "unable to scrobble to {}, keeping {} plays for \
+                         later: {}",
+                        self.backend.name(),
+                        self.pending.len(),
+                        e
+                    );
+                    break
+                }
+                Err(Rejection::Permanent(ref e)) if n > 1 => {
+                    debug!("{} rejected a batch: {}", self.backend.name(), e);
+                    single = true;
+                    continue
+                }
+                Err(Rejection::Permanent(e)) => warn!(
+                    "{} rejected {}, dropping it: {}",
+                    self.backend.name(),
+                    batch[0].title,
+                    e
+                ),
+            }
+            self.pending.drain(..n);
+        }
+        self.save();
+    }
+}
+
+struct Playing {
+    listen: Listen,
+    /// How much needs to be played before the song is submitted, if it's
+    /// long enough to be submitted at all.
+    threshold: Option<Duration>,
+    /// Time played before the last pause.
+    played: Duration,
+    /// When playback last resumed, if it's playing.
+    resumed: Option<Instant>,
+}
+
+impl Playing {
+    fn played(&self) -> Duration {
+        self.played + self.resumed.map(|r| r.elapsed()).unwrap_or_default()
+    }
+}
+
+struct Scrobbler {
+    client: Arc<Mutex<Client>>,
+    library: Arc<Library>,
+    queues: Vec<Queue>,
+    current: Option<Playing>,
+    /// Listens waiting for their details, oldest first.
+    held: VecDeque<Listen>,
+    held_path: PathBuf,
+}
+
+impl Scrobbler {
+    fn new(
+        client: Arc<Mutex<Client>>,
+        library: Arc<Library>,
+        queues: Vec<Queue>,
+        held_path: PathBuf,
+    ) -> Scrobbler {
+        let held = File::open(&held_path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+            .unwrap_or_default();
+        Scrobbler {
+            client,
+            library,
+            queues,
+            current: None,
+            held,
+            held_path,
+        }
+    }
+
+    /// How long until the current song should be submitted, if it's playing
+    /// and hasn't been yet.
+    fn until_threshold(&self) -> Option<Duration> {
+        let playing = self.current.as_ref()?;
+        let threshold = playing.threshold?;
+        playing.resumed?;
+
+        let played = playing.played();
+        Some(if played >= threshold {
+            Duration::from_secs(0)
+        } else {
+            threshold - played
+        })
+    }
+
+    fn handle(&mut self, ev: Event) {
+        match ev {
+            Event::TrackStart(id) => {
+                let listen = self.lookup(id);
+                if listen.is_some() && !self.held.is_empty() {
+                    self.resolve();
+                }
+                self.start(listen.unwrap_or_else(|| Listen::unresolved(id)));
+            }
+            // Listens without details are held once they're over, since
+            // only then is it known how much was played.
+            Event::TrackEnd(id) => {
+                let held = match self.current {
+                    Some(ref p) => p.listen.id == id && !p.listen.is_resolved(),
+                    None => false,
+                };
+                if held {
+                    let playing = self.current.take().unwrap();
+                    let played = playing.played().as_secs();
+                    if played >= MIN_DURATION {
+                        debug!("holding {} until it can be looked up", id);
+                        let mut listen = playing.listen;
+                        listen.played = Some(played);
+                        self.held.push_back(listen);
+                        while self.held.len() > MAX_PENDING {
+                            self.held.pop_front();
+                        }
+                        self.save_held();
+                    }
+                }
+            }
+            // Only songs on the server are scrobbled.
+            Event::StreamStart => self.current = None,
+            Event::Pause => if let Some(ref mut playing) = self.current {
+                playing.played = playing.played();
+                playing.resumed = None;
+            },
+            Event::Resume => if let Some(ref mut playing) = self.current {
+                if playing.resumed.is_none() {
+                    playing.resumed = Some(Instant::now());
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// The song's details, from the index or else the server.
+    fn lookup(&self, id: u64) -> Option<Listen> {
+        if let Some(entry) = self.library.song(id) {
+            return Some(Listen::from_entry(&entry))
+        }
+        let cli = &*self.client.lock().unwrap();
+        match Song::get(cli, id) {
+            Ok(song) => Some(Listen::new(&song)),
+            Err(e) => {
+                warn!("unable to get song {} to scrobble: {}", id, e);
+                None
+            }
+        }
+    }
+
+    /// Fills in held listens, submitting the ones that were played for long
+    /// enough, until one can't be looked up.
+    fn resolve(&mut self) {
+        while let Some(mut listen) = self.held.front().cloned() {
+            match self.lookup(listen.id) {
+                Some(details) => listen.fill(details),
+                None => break,
+            }
+            self.held.pop_front();
+            if listen.counts() {
+                for queue in &mut self.queues {
+                    queue.submit(listen.clone());
+                }
+            }
+        }
+        self.save_held();
+    }
+
+    fn save_held(&self) {
+        if let Err(e) = self.try_save_held() {
+            warn!("unable to save {}: {}", self.held_path.display(), e);
+        }
+    }
+
+    fn try_save_held(&self) -> Result {
+        let file = File::create(&self.held_path)?;
+        serde_json::to_writer(file, &self.held)?;
+        Ok(())
+    }
+
+    fn start(&mut self, listen: Listen) {
+        let threshold = if listen.is_resolved() {
+            for queue in &mut self.queues {
+                queue.now_playing(&listen);
+            }
+            threshold(listen.duration).map(Duration::from_secs)
+        } else {
+            None
+        };
+
+        self.current = Some(Playing {
+            listen,
+            threshold,
+            played: Duration::from_secs(0),
+            resumed: Some(Instant::now()),
+        });
+    }
+
+    /// Submits the current song to every backend.
+    fn submit(&mut self) {
+        if let Some(playing) = self.current.take() {
+            debug!("scrobbling {}", playing.listen.id);
+            for queue in &mut self.queues {
+                queue.submit(playing.listen.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use sunk::Client;
+
+    use super::{threshold, Backend, Listen, Queue, Rejection, Scrobbler};
+    use conf::Conf;
+    use error::{Error, Result};
+    use event::Event;
+    use library::Library;
+
+    /// Writes down everything sent to it, turning song 13 down for good.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Backend for Recorder {
+        fn name(&self) -> &'static str { "recorder" }
+
+        fn now_playing(&self, listen: &Listen) -> Result {
+            let sent = format!("playing {}", listen.id);
+            self.0.lock().unwrap().push(sent);
+            Ok(())
+        }
+
+        fn submit(
+            &self,
+            listens: &[Listen],
+        ) -> ::std::result::Result<(), Rejection> {
+            if listens.iter().any(|l| l.id == 13) {
+                return Err(Rejection::Permanent(Error::Other("unlucky")))
+            }
+            for listen in listens {
+                let sent = format!("submit {}", listen.id);
+                self.0.lock().unwrap().push(sent);
+            }
+            Ok(())
+        }
+
+        fn batch_size(&self) -> usize { 10 }
+    }
+
+    pub fn listen() -> Listen {
+        Listen {
+            id: 1,
+            title: "Bad".into(),
+            artist: Some("Michael Jackson".into()),
+            album: Some("Bad".into()),
+            track: Some(1),
+            duration: 247,
+            started: 1_500_000_000,
+            played: None,
+        }
+    }
+
+    /// An empty directory for a test's saved queue.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("subs-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A library index holding the given songs, as `"id": {...}` pairs.
+    fn library(dir: &Path, songs: &str) -> Arc<Library> {
+        let index = format!(
+            r#"{{"last_modified": 0, "artists": [], "albums": {{}},
+                "songs": {{{}}}}}"#,
+            songs
+        );
+        fs::write(dir.join("library.json"), index).unwrap();
+        Arc::new(Library::load(&Conf {
+            cache: dir.to_path_buf(),
+            library_sync: 60,
+            ..Conf::default()
+        }))
+    }
+
+    const SONG_1: &str = r#""1": {"id": 1, "title": "Bad", "duration": 60}"#;
+
+    /// A scrobbler reporting to a `Recorder`, with songs from `library` and
+    /// a server that can't be reached.
+    fn scrobbler(
+        dir: &Path,
+        library: Arc<Library>,
+    ) -> (Scrobbler, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let client = Client::new("http://127.0.0.1:1", "u", "p").unwrap();
+        let queues = vec![Queue::load(Box::new(Recorder(log.clone())), dir)];
+        let scrobbler = Scrobbler::new(
+            Arc::new(Mutex::new(client)),
+            library,
+            queues,
+            dir.join("unresolved.json"),
+        );
+        (scrobbler, log)
+    }
+
+    #[test]
+    fn submits_once_enough_has_been_played() {
+        let dir = temp_dir("scrobbler-submit");
+        let (mut s, log) = scrobbler(&dir, library(&dir, SONG_1));
+
+        s.handle(Event::TrackStart(1));
+        let left = s.until_threshold().unwrap();
+        assert!(left <= Duration::from_secs(30));
+        assert!(left > Duration::from_secs(29));
+
+        // Time spent paused doesn't count.
+        s.handle(Event::Pause);
+        assert_eq!(s.until_threshold(), None);
+        s.handle(Event::Resume);
+        assert!(s.until_threshold().is_some());
+
+        s.submit();
+        assert_eq!(*log.lock().unwrap(), vec!["playing 1", "submit 1"]);
+        assert_eq!(s.until_threshold(), None);
+
+        // Streams aren't scrobbled.
+        s.handle(Event::TrackStart(1));
+        s.handle(Event::StreamStart);
+        assert!(s.current.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn holds_plays_until_they_can_be_looked_up() {
+        let dir = temp_dir("scrobbler-held");
+        let (mut s, log) = scrobbler(&dir, library(&dir, SONG_1));
+
+        // Song 2 isn't in the index and the server can't be reached.
+        s.handle(Event::TrackStart(2));
+        assert_eq!(s.until_threshold(), None);
+        s.current.as_mut().unwrap().played = Duration::from_secs(45);
+        s.handle(Event::TrackEnd(2));
+        assert_eq!(s.held.len(), 1);
+        assert!(log.lock().unwrap().is_empty());
+        assert!(dir.join("unresolved.json").exists());
+
+        s.library = library(
+            &dir,
+            r#""1": {"id": 1, "title": "Bad", "duration": 80},
+               "2": {"id": 2, "title": "Smooth", "duration": 80}"#,
+        );
+        s.handle(Event::TrackStart(1));
+        assert!(s.held.is_empty());
+        assert_eq!(*log.lock().unwrap(), vec!["submit 2", "playing 1"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn held_plays_turned_down_do_not_block_the_rest() {
+        let dir = temp_dir("scrobbler-held-rejected");
+        let (mut s, log) = scrobbler(&dir, library(&dir, SONG_1));
+
+        // Neither is in the index, and the server can't be reached.
+        for &id in &[13, 2] {
+            s.handle(Event::TrackStart(id));
+            s.current.as_mut().unwrap().played = Duration::from_secs(45);
+            s.handle(Event::TrackEnd(id));
+        }
+        assert_eq!(s.held.len(), 2);
+
+        s.library = library(
+            &dir,
+            r#""1": {"id": 1, "title": "Bad", "duration": 80},
+               "2": {"id": 2, "title": "Smooth", "duration": 80},
+               "13": {"id": 13, "title": "Thriller", "duration": 80}"#,
+        );
+        s.handle(Event::TrackStart(1));
+        assert!(s.held.is_empty());
+        assert!(s.queues[0].pending.is_empty());
+        assert_eq!(*log.lock().unwrap(), vec!["submit 2", "playing 1"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_listens_turned_down_for_good() {
+        let dir = temp_dir("scrobble-rejected");
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut queue = Queue::load(Box::new(Recorder(log.clone())), &dir);
+        for &id in &[12, 13, 14] {
+            queue.pending.push_back(Listen { id, ..listen() });
+        }
+
+        // The batch is turned down, so they're tried one by one.
+        queue.flush();
+        assert!(queue.pending.is_empty());
+        assert_eq!(*log.lock().unwrap(), vec!["submit 12", "submit 14"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn threshold_is_half_up_to_four_minutes() {
+        assert_eq!(threshold(20), None);
+        assert_eq!(threshold(200), Some(100));
+        assert_eq!(threshold(600), Some(240));
+    }
+
+    #[test]
+    fn held_listens_count_if_played_long_enough() {
+        let mut l = listen();
+        assert!(l.counts());
+        l.played = Some(60);
+        assert!(!l.counts());
+        l.played = Some(124);
+        assert!(l.counts());
+    }
+
+    #[test]
+    fn fill_keeps_when_and_how_long() {
+        let mut l = Listen::unresolved(1);
+        l.played = Some(100);
+        assert!(!l.is_resolved());
+        l.fill(listen());
+        assert!(l.is_resolved());
+        assert_eq!(l.title, "Bad");
+        assert_eq!(l.played, Some(100));
+        assert_ne!(l.started, listen().started);
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::{Arc, Mutex};
+use sunk::song::Song;
+use sunk::{Annotatable, ApiError, Client};
+
+use error::Result;
+use super::{Backend, Listen, Rejection};
+
+/// Reports plays to the Subsonic server, for its play counts and "recently
+/// played" lists.
+pub struct Subsonic {
+    client: Arc<Mutex<Client>>,
+}
+
+impl Subsonic {
+    pub fn new(client: Arc<Mutex<Client>>) -> Subsonic { Subsonic { client } }
+}
+
+impl Backend for Subsonic {
+    fn name(&self) -> &'static str { "subsonic" }
+
+    fn now_playing(&self, listen: &Listen) -> Result {
+        let cli = &*self.client.lock().unwrap();
+        Song::get(cli, listen.id)?.scrobble(cli, None, true)?;
+        Ok(())
+    }
+
+    fn submit(
+        &self,
+        listens: &[Listen],
+    ) -> ::std::result::Result<(), Rejection> {
+        let cli = &*self.client.lock().unwrap();
+        for listen in listens {
+            let time = listen.started * 1000;
+            Song::get(cli, listen.id)
+                .and_then(|s| s.scrobble(cli, Some(time), false))
+                .map_err(rejection)?;
+        }
+        Ok(())
+    }
+}
+
+/// Songs deleted from the server can't be scrobbled any more.
+fn rejection(err: ::sunk::Error) -> Rejection {
+    match err {
+        ::sunk::Error::Api(ApiError::NotFound(_)) => {
+            Rejection::Permanent(err.into())
+        }
+        e => Rejection::Retry(e.into()),
+    }
+}
@@ -0,0 +1,259 @@
+//! Status output for waybar, i3blocks and polybar.
+//!
+//! A line is printed whenever the player does something. While it's playing
+//! the progress ticks along every few seconds without asking the daemon.
+
+use crossbeam_channel::{self, RecvTimeoutError};
+use serde_json;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use conf::Conf;
+use daemon::{self, Command, Reply};
+use error::{Error, Result};
+use player::{State, Status};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    /// One JSON object per line, for a `custom` module with
+    /// `"return-type": "json"`.
+    Waybar,
+    /// One JSON object per line, for a block with `format=json` and
+    /// `interval=persist`.
+    I3blocks,
+    /// Plain text, for a `custom/script` module with `tail = true`.
+    Polybar,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Format, String> {
+        match s {
+            "waybar" => Ok(Format::Waybar),
+            "i3blocks" => Ok(Format::I3blocks),
+            "polybar" => Ok(Format::Polybar),
+            _ => Err(format!(
+                "Unknown format \"{}\"; expected waybar, i3blocks or polybar",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Waybar {
+    text: String,
+    tooltip: String,
+    class: &'static str,
+    alt: &'static str,
+    percentage: u64,
+}
+
+#[derive(Serialize)]
+struct I3blocks {
+    full_text: String,
+    short_text: String,
+}
+
+/// Prints the status until the daemon goes away.
+pub fn run(cfg: &Conf, format: Format, interval: u64) -> Result {
+    let events = daemon::subscribe(cfg)?;
+
+    // Events arrive on their own thread so the progress can also tick along
+    // on a timer.
+    let (send, recv) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        for ev in events {
+            if send.send(ev).is_err() {
+                break
+            }
+        }
+    });
+
+    let mut st = status(cfg)?;
+    let mut last = String::new();
+    loop {
+        let line = render(format, &st)?;
+        // Bars redraw on every line, so skip ones that say nothing new.
+        if line != last {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            writeln!(out, "{}", line)?;
+            out.flush()?;
+            last = line;
+        }
+
+        let ev = if st.state == State::Playing {
+            recv.recv_timeout(Duration::from_secs(interval))
+        } else {
+            recv.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match ev {
+            Ok(_) => st = status(cfg)?,
+            Err(RecvTimeoutError::Timeout) => tick(&mut st, interval),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err("Lost connection to the daemon".into())
+            }
+        }
+    }
+}
+
+fn status(cfg: &Conf) -> ::std::result::Result<Status, Error> {
+    match daemon::send_recv(cfg, Command::StatusReq)? {
+        Reply::Status(st) => Ok(st),
+        _ => unreachable!(),
+    }
+}
+
+/// Moves the progress along between events, stopping at the end of the
+/// song until the next one starts.
+fn tick(st: &mut Status, secs: u64) {
+    if st.state != State::Playing {
+        return
+    }
+    st.elapsed += secs;
+    if let Some(ref t) = st.track {
+        if t.duration > 0 {
+            st.elapsed = ::std::cmp::min(st.elapsed, t.duration);
+        }
+    }
+    st.elapsed_ms = st.elapsed * 1000;
+}
+
+fn render(format: Format, st: &Status) -> ::std::result::Result<String, Error> {
+    let class = match st.state {
+        State::Playing => "playing",
+        State::Paused => "paused",
+        State::Stopped => "stopped",
+    };
+    let (text, tooltip, percentage) = match st.track {
+        Some(ref t) => (
+            t.to_string(),
+            st.to_string(),
+            if t.duration > 0 {
+                st.elapsed * 100 / t.duration
+            } else {
+                0
+            },
+        ),
+        None => (String::new(), String::new(), 0),
+    };
+
+    Ok(match format {
+        Format::Waybar => serde_json::to_string(&Waybar {
+            text,
+            tooltip,
+            class,
+            alt: class,
+            percentage,
+        })?,
+        Format::I3blocks => serde_json::to_string(&I3blocks {
+            full_text: marked(st, text),
+            short_text: st.track
+                .as_ref()
+                .map(|t| t.title.clone())
+                .unwrap_or_default(),
+        })?,
+        Format::Polybar => marked(st, text),
+    })
+}
+
+/// Marks the text as paused, for bars that can't style it by class.
+fn marked(st: &Status, text: String) -> String {
+    match st.state {
+        State::Paused if !text.is_empty() => format!("[paused] {}", text),
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{self, Value};
+
+    use super::{render, tick, Format};
+    use player::{State, Status, Track};
+
+    fn status(state: State) -> Status {
+        Status {
+            state,
+            position: 0,
+            len: 1,
+            elapsed: 60,
+            elapsed_ms: 60_000,
+            volume: 50,
+            track: Some(Track {
+                id: 7,
+                title: "Teardrop".into(),
+                artist: Some("Massive Attack".into()),
+                album: Some("Mezzanine".into()),
+                duration: 240,
+                cover_url: None,
+                cover_path: None,
+                location: None,
+            }),
+        }
+    }
+
+    fn json(format: Format, st: &Status) -> Value {
+        serde_json::from_str(&render(format, st).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn waybar_has_class_and_percentage() {
+        let v = json(Format::Waybar, &status(State::Playing));
+        assert_eq!(v["text"], "Massive Attack - Teardrop");
+        assert_eq!(v["class"], "playing");
+        assert_eq!(v["alt"], "playing");
+        assert_eq!(v["percentage"], 25);
+    }
+
+    #[test]
+    fn paused_is_marked_without_classes() {
+        let st = status(State::Paused);
+        let v = json(Format::I3blocks, &st);
+        assert_eq!(v["full_text"], "[paused] Massive Attack - Teardrop");
+        assert_eq!(v["short_text"], "Teardrop");
+        assert_eq!(
+            render(Format::Polybar, &st).unwrap(),
+            "[paused] Massive Attack - Teardrop"
+        );
+    }
+
+    #[test]
+    fn nothing_playing_is_empty() {
+        let st = Status {
+            track: None,
+            ..status(State::Stopped)
+        };
+        assert_eq!(render(Format::Polybar, &st).unwrap(), "");
+        let v = json(Format::Waybar, &st);
+        assert_eq!(v["text"], "");
+        assert_eq!(v["class"], "stopped");
+        assert_eq!(v["percentage"], 0);
+    }
+
+    #[test]
+    fn ticks_only_while_playing() {
+        let mut st = status(State::Playing);
+        tick(&mut st, 5);
+        assert_eq!(st.elapsed, 65);
+        assert_eq!(st.elapsed_ms, 65_000);
+
+        tick(&mut st, 500);
+        assert_eq!(st.elapsed, 240);
+
+        let mut st = status(State::Paused);
+        tick(&mut st, 5);
+        assert_eq!(st.elapsed, 60);
+    }
+
+    #[test]
+    fn parses_format_names() {
+        assert!("waybar".parse::<Format>().is_ok());
+        assert!("polybar".parse::<Format>().is_ok());
+        assert!("lemonbar".parse::<Format>().is_err());
+    }
+}
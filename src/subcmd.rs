@@ -1,17 +1,46 @@
-use cli::AppCommand;
-use daemon::{self, Command, Reply};
+use cli::{self, AppCommand, CacheCommand, PlaylistCommand, RadioCommand};
+use conf::Conf;
+use crossbeam_channel::{self, RecvTimeoutError};
+use daemon::{self, Command, Kind, Reply};
+use download;
 use error::{Error, Result};
+use event::Event;
+use lyrics::Lyrics;
+use player::State;
+use playlist_file;
+use quality::Quality;
+use queue::Source;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
 
-pub fn clear() -> Result { daemon::send(Command::Clear) }
-pub fn next() -> Result { daemon::send(Command::Next) }
-pub fn pause() -> Result { daemon::send(Command::Pause) }
-pub fn play() -> Result { daemon::send(Command::Play) }
-pub fn prev() -> Result { daemon::send(Command::Prev) }
-pub fn random(n: usize) -> Result { daemon::send(Command::Random(n)) }
-pub fn toggle() -> Result { daemon::send(Command::Toggle) }
+/// How many times to poll for the server to report that a scan has started,
+/// a second apart, before taking it that there was nothing to scan.
+const SCAN_START_TIMEOUT: u64 = 10;
 
-pub fn add(query: String) -> Result {
-    if let Reply::Other(r) = daemon::send_recv(Command::AddSearch(query))? {
+pub fn clear(cfg: &Conf) -> Result { daemon::send(cfg, Command::Clear) }
+pub fn next(cfg: &Conf) -> Result { daemon::send(cfg, Command::Next) }
+pub fn pause(cfg: &Conf) -> Result { daemon::send(cfg, Command::Pause) }
+pub fn play(cfg: &Conf) -> Result { daemon::send(cfg, Command::Play) }
+pub fn prev(cfg: &Conf) -> Result { daemon::send(cfg, Command::Prev) }
+pub fn random(cfg: &Conf, n: usize) -> Result {
+    daemon::send(cfg, Command::Random(n))
+}
+pub fn toggle(cfg: &Conf) -> Result { daemon::send(cfg, Command::Toggle) }
+
+pub fn add(cfg: &Conf, query: Vec<String>) -> Result {
+    added(cfg, add_command(query, false))
+}
+
+pub fn addnext(cfg: &Conf, query: Vec<String>) -> Result {
+    added(cfg, add_command(query, true))
+}
+
+fn added(cfg: &Conf, cmd: Command) -> Result {
+    if let Reply::Other(r) = daemon::send_recv(cfg, cmd)? {
         if !r.starts_with("Nothing") {
             println!("{}", r);
             Ok(())
@@ -23,22 +52,239 @@ pub fn add(query: String) -> Result {
     }
 }
 
-pub fn addnext(query: String) -> Result {
-    if let Reply::Other(r) = daemon::send_recv(Command::AddNextSearch(query))? {
-        if !r.starts_with("Nothing") {
-            println!("{}", r);
-            Ok(())
-        } else {
-            Err(Error::Response(r))
+pub fn load(cfg: &Conf, name: String) -> Result {
+    match daemon::send_recv(cfg, Command::Load(name))? {
+        Reply::Other(r) => println!("{}", r),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn save(cfg: &Conf, args: AppCommand) -> Result {
+    match daemon::send_recv(cfg, command(args)?)? {
+        Reply::Other(r) => println!("{}", r),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn export(cfg: &Conf, file: String, urls: bool) -> Result {
+    match daemon::send_recv(cfg, Command::Export(urls))? {
+        Reply::Entries(entries) => {
+            playlist_file::write(Path::new(&file), &entries)?;
+            println!("Exported {} songs to {}.", entries.len(), file);
+            if urls {
+                println!(
+                    "Warning: {} contains your credentials; keep it private.",
+                    file
+                );
+            }
         }
-    } else {
-        unreachable!()
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn import(cfg: &Conf, file: String) -> Result {
+    let entries = playlist_file::read(Path::new(&file))?;
+    if entries.is_empty() {
+        return Err(Error::Response(format!("No entries found in {}.", file)))
+    }
+
+    match daemon::send_recv(cfg, Command::Import(entries))? {
+        Reply::Imported { added, unmatched } => {
+            println!("Added {} songs from {}.", added, file);
+            if !unmatched.is_empty() {
+                println!("Unable to match {}:", unmatched.len());
+                for entry in unmatched {
+                    println!("  {}", entry);
+                }
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn playlist(cfg: &Conf, cmd: PlaylistCommand) -> Result {
+    match daemon::send_recv(cfg, playlist_command(cmd))? {
+        Reply::Other(r) => println!("{}", r),
+        Reply::Songs(songs) => for (i, song) in songs.iter().enumerate() {
+            println!("{:>3}. {}", i + 1, song);
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn playlist_command(cmd: PlaylistCommand) -> Command {
+    use cli::PlaylistCommand::*;
+    match cmd {
+        Show { name } => Command::PlaylistShow(name),
+        Create { name, from_queue } => {
+            Command::PlaylistCreate(name, from_queue)
+        }
+        Add { name, query } => {
+            let query = if query.len() == 1 && query[0] == "current" {
+                None
+            } else {
+                query_or_current(query)
+            };
+            Command::PlaylistAdd(name, query)
+        }
+        Remove { name, index } => Command::PlaylistRemove(name, index),
+        Rename { name, new_name } => Command::PlaylistRename(name, new_name),
+        Delete { name } => Command::PlaylistDelete(name),
+    }
+}
+
+pub fn cache(cfg: &Conf, cmd: CacheCommand) -> Result {
+    let cmd = match cmd {
+        CacheCommand::Status => Command::CacheStatus,
+        CacheCommand::Clear => Command::CacheClear,
+        CacheCommand::Pin { query } => Command::CachePin(::collapse(query)),
+    };
+
+    match daemon::send_recv(cfg, cmd)? {
+        Reply::Other(r) => println!("{}", r),
+        Reply::Cache(usage) => {
+            if usage.max == 0 {
+                println!("The cache is turned off.");
+            }
+            println!(
+                "{} songs ({} pinned), {} of {} MB used.",
+                usage.songs,
+                usage.pinned,
+                usage.bytes / 1024 / 1024,
+                usage.max / 1024 / 1024
+            );
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn radio(cfg: &Conf, cmd: RadioCommand) -> Result {
+    let cmd = match cmd {
+        RadioCommand::List => Command::Stations,
+        RadioCommand::Play { name } => Command::RadioPlay(::collapse(name)),
+        RadioCommand::Create {
+            name,
+            url,
+            homepage,
+        } => Command::RadioCreate(name, url, homepage),
+        RadioCommand::Delete { name } => Command::RadioDelete(name),
+    };
+
+    match daemon::send_recv(cfg, cmd)? {
+        Reply::Other(r) => println!("{}", r),
+        Reply::Stations(stations) => {
+            if stations.is_empty() {
+                println!("No stations.");
+            }
+            for station in stations {
+                println!("{}", station);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn download(cfg: &Conf, args: AppCommand) -> Result {
+    let (cmd, to, transcode) = match args {
+        AppCommand::Download {
+            album,
+            artist,
+            playlist: Some(name),
+            to,
+            transcode,
+            ..
+        } => {
+            if album || artist {
+                return Err("--playlist can't be used with --album or \
+                            --artist"
+                    .into())
+            }
+            (Command::PlaylistDownloads(name), to, transcode)
+        }
+        AppCommand::Download {
+            album,
+            artist,
+            to,
+            transcode,
+            query,
+            ..
+        } => {
+            let query = if query.len() == 1 && query[0] == "current" {
+                None
+            } else {
+                query_or_current(query)
+            };
+            (Command::Downloads(kind(album, artist), query), to, transcode)
+        }
+        _ => unreachable!(),
+    };
+
+    let songs = match daemon::send_recv(cfg, cmd)? {
+        Reply::Downloads(songs) => songs,
+        _ => unreachable!(),
+    };
+    if songs.is_empty() {
+        return Err("Nothing to download".into())
     }
+    download::run(cfg, &songs, Path::new(&to), transcode)?;
+    println!("Downloaded {} songs to {}.", songs.len(), to);
+    Ok(())
 }
 
-pub fn load(name: String) -> Result { unimplemented!() }
+pub fn quality(
+    cfg: &Conf,
+    setting: Option<String>,
+    format: Option<String>,
+) -> Result {
+    let quality = match setting.as_ref().map(|s| s.as_str()) {
+        None if format.is_none() => None,
+        None => Some(Some(Quality {
+            max_bit_rate: None,
+            format,
+        })),
+        Some("auto") => Some(None),
+        Some("original") => Some(Some(Quality::default())),
+        Some(rate) => match rate.trim_end_matches("kbps").parse() {
+            Ok(rate) => Some(Some(Quality {
+                max_bit_rate: Some(rate),
+                format,
+            })),
+            Err(_) => {
+                return Err(Error::Response(format!(
+                    "Expected a bitrate, \"original\" or \"auto\", not \
+                     \"{}\".",
+                    rate
+                )))
+            }
+        },
+    };
+    if let Some(quality) = quality {
+        daemon::send_recv(cfg, Command::SetQuality(quality))?;
+    }
+
+    match daemon::send_recv(cfg, Command::QualityReq)? {
+        Reply::Quality(quality, session, metered) => {
+            print!("Streaming {}", quality);
+            if session {
+                print!(" (set with `subs quality`)");
+            } else if metered {
+                print!(" (metered network)");
+            }
+            println!(".");
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
 
-pub fn search(args: AppCommand) -> Result {
+pub fn search(cfg: &Conf, args: AppCommand) -> Result {
     if let AppCommand::Search {
         query,
         only_artists,
@@ -46,13 +292,14 @@ pub fn search(args: AppCommand) -> Result {
         number,
     } = args
     {
-        let resp = daemon::send_recv(Command::Search(
+        let cmd = Command::Search(
             ::collapse(query),
             only_artists,
             only_albums,
             !(only_artists || only_albums),
             number,
-        ))?;
+        );
+        let resp = daemon::send_recv(cfg, cmd)?;
 
         if let Reply::Search {
             artists,
@@ -77,8 +324,8 @@ pub fn search(args: AppCommand) -> Result {
     Ok(())
 }
 
-pub fn status() -> Result {
-    if let Reply::Other(r) = daemon::send_recv(Command::StatusReq)? {
+pub fn status(cfg: &Conf) -> Result {
+    if let Reply::Status(r) = daemon::send_recv(cfg, Command::StatusReq)? {
         println!("{}", r);
         Ok(())
     } else {
@@ -86,10 +333,592 @@ pub fn status() -> Result {
     }
 }
 
-pub fn ls_playlists() -> Result {
-    unimplemented!()
+pub fn info(cfg: &Conf, args: AppCommand) -> Result {
+    match daemon::send_recv(cfg, command(args)?)? {
+        Reply::SongInfo(i) => println!("{}", i),
+        Reply::AlbumInfo(i) => print!("{}", i),
+        Reply::ArtistInfo(i) => print!("{}", i),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn cover(cfg: &Conf, out: Option<String>) -> Result {
+    let out = match out {
+        Some(o) => o,
+        None => {
+            match daemon::send_recv(cfg, Command::Cover(false))? {
+                Reply::Cover(path, _) => println!("{}", path),
+                _ => unreachable!(),
+            }
+            return Ok(())
+        }
+    };
+
+    match daemon::send_recv(cfg, Command::Cover(true))? {
+        Reply::Cover(_, Some(image)) => File::create(&out)?.write_all(&image)?,
+        _ => unreachable!(),
+    }
+    println!("Wrote cover to {}.", out);
+    Ok(())
+}
+
+pub fn lyrics(cfg: &Conf, follow: bool) -> Result {
+    let lyrics = current_lyrics(cfg)?;
+    if !follow {
+        if lyrics.lines.is_empty() {
+            return Err(Error::Response(format!(
+                "No lyrics found for {}.",
+                lyrics.title
+            )))
+        }
+        print!("{}", lyrics);
+        return Ok(())
+    }
+
+    let mut lyrics = lyrics;
+    loop {
+        follow_lyrics(cfg, &lyrics)?;
+        lyrics = current_lyrics(cfg)?;
+        println!();
+    }
+}
+
+fn current_lyrics(cfg: &Conf) -> ::std::result::Result<Lyrics, Error> {
+    match daemon::send_recv(cfg, Command::Lyrics)? {
+        Reply::Lyrics(l) => Ok(l),
+        _ => unreachable!(),
+    }
 }
 
-pub fn ls_artists(number: usize) -> Result {
-    unimplemented!()
+/// Prints each line as the player reaches it, until the song changes. The
+/// position is kept locally, and only corrected when the daemon says the
+/// player has paused, resumed or seeked.
+fn follow_lyrics(cfg: &Conf, lyrics: &Lyrics) -> Result {
+    let heading = match lyrics.artist {
+        Some(ref a) => format!("{} - {}", a, lyrics.title),
+        None => lyrics.title.clone(),
+    };
+    println!("{}\n", heading);
+    if lyrics.lines.is_empty() {
+        println!("(no lyrics)");
+    } else if !lyrics.synced {
+        print!("{}", lyrics);
+    }
+
+    // Subscribing first means nothing is missed between the two.
+    let events = daemon::subscribe(cfg)?;
+    let (send, recv) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        for ev in events {
+            if send.send(ev).is_err() {
+                break
+            }
+        }
+    });
+
+    let st = match daemon::send_recv(cfg, Command::StatusReq)? {
+        Reply::Status(st) => st,
+        _ => unreachable!(),
+    };
+    match st.track {
+        Some(ref t) if t.id == lyrics.id => (),
+        _ => return Ok(()),
+    }
+
+    // Where the player was at `since`, in milliseconds.
+    let mut base = st.elapsed_ms;
+    let mut since = Instant::now();
+    let mut playing = st.state == State::Playing;
+    let position = |base: u64, since: Instant, playing: bool| {
+        if playing {
+            let e = since.elapsed();
+            base + e.as_secs() * 1000 + u64::from(e.subsec_nanos() / 1_000_000)
+        } else {
+            base
+        }
+    };
+    let at = |i: usize| lyrics.lines[i].start.unwrap_or(0);
+
+    let mut next = 0;
+    loop {
+        let now = position(base, since, playing);
+        if lyrics.synced {
+            // Seeking back skips to where the player is without repeating
+            // what's been printed.
+            if next > 0 && at(next - 1) > now {
+                next = lyrics
+                    .lines
+                    .iter()
+                    .take_while(|l| l.start.unwrap_or(0) <= now)
+                    .count();
+            }
+            while next < lyrics.lines.len() && at(next) <= now {
+                println!("{}", lyrics.lines[next].text);
+                next += 1;
+            }
+        }
+
+        let ev = if playing && lyrics.synced && next < lyrics.lines.len() {
+            let wait = Duration::from_millis(at(next) - now);
+            match recv.recv_timeout(wait) {
+                Ok(ev) => ev,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(_) => return Ok(()),
+            }
+        } else {
+            match recv.recv() {
+                Ok(ev) => ev,
+                Err(_) => return Ok(()),
+            }
+        };
+        match ev {
+            Event::Pause => {
+                base = position(base, since, playing);
+                playing = false;
+            }
+            Event::Resume => {
+                since = Instant::now();
+                playing = true;
+            }
+            Event::Seek(to) => {
+                base = to * 1000;
+                since = Instant::now();
+            }
+            // The same song again, from the start.
+            Event::TrackStart(id) if id == lyrics.id => {
+                base = 0;
+                since = Instant::now();
+                playing = true;
+            }
+            Event::TrackStart(_) => return Ok(()),
+            Event::StreamStart | Event::QueueEmpty => return Ok(()),
+            _ => (),
+        }
+    }
+}
+
+pub fn annotate(cfg: &Conf, args: AppCommand) -> Result {
+    match daemon::send_recv(cfg, command(args)?)? {
+        Reply::Other(r) => println!("{}", r),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn update(cfg: &Conf, wait: bool) -> Result {
+    let (scanning, count) = scan_status(cfg, Command::Scan)?;
+    if !wait {
+        println!("Started scanning the library.");
+        return Ok(())
+    }
+
+    let mut stdout = io::stdout();
+    let count = wait_for_scan(
+        scanning,
+        count,
+        || {
+            thread::sleep(Duration::from_secs(1));
+            scan_status(cfg, Command::ScanStatus)
+        },
+        |count| {
+            print!("\rScanning... {} items", count);
+            stdout.flush()
+        },
+    )?;
+    println!("\rFinished scanning, {} items.", count);
+    Ok(())
+}
+
+fn scan_status(
+    cfg: &Conf,
+    cmd: Command,
+) -> ::std::result::Result<(bool, u64), Error> {
+    match daemon::send_recv(cfg, cmd)? {
+        Reply::Scan(scanning, count) => Ok((scanning, count)),
+        _ => unreachable!(),
+    }
+}
+
+/// Polls until the scan is done, returning how many items were scanned.
+///
+/// The server can take a moment to start, so a scan is only done once it's
+/// been seen running, or has counted something, or nothing has happened for
+/// `SCAN_START_TIMEOUT` polls.
+fn wait_for_scan<P, S>(
+    mut scanning: bool,
+    mut count: u64,
+    mut poll: P,
+    mut show: S,
+) -> ::std::result::Result<u64, Error>
+where
+    P: FnMut() -> ::std::result::Result<(bool, u64), Error>,
+    S: FnMut(u64) -> io::Result<()>,
+{
+    let first = count;
+    let mut started = scanning;
+    let mut waited = 0;
+    while scanning || (!started && waited < SCAN_START_TIMEOUT) {
+        show(count)?;
+        waited += 1;
+        let (s, c) = poll()?;
+        scanning = s;
+        count = c;
+        started = started || s || c != first;
+    }
+    Ok(count)
+}
+
+pub fn ls_starred(cfg: &Conf, add: bool) -> Result {
+    if let Reply::Search {
+        artists,
+        albums,
+        songs,
+    } = daemon::send_recv(cfg, Command::Starred(add))?
+    {
+        for (heading, items) in
+            &[("Artists", artists), ("Albums", albums), ("Songs", songs)]
+        {
+            if !items.is_empty() {
+                println!("{}:", heading);
+                for item in items {
+                    println!("  {}", item);
+                }
+            }
+        }
+        Ok(())
+    } else {
+        unreachable!()
+    }
+}
+
+pub fn ls_playlists(cfg: &Conf) -> Result {
+    match daemon::send_recv(cfg, Command::Playlists)? {
+        Reply::Playlists(lists) => for (name, count) in lists {
+            println!("{} ({} songs)", name, count);
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn ls_artists(cfg: &Conf, number: usize) -> Result {
+    match daemon::send_recv(cfg, Command::Artists)? {
+        Reply::Listing(artists) => {
+            for &(_, ref name) in artists.iter().take(number) {
+                println!("{}", name);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+pub fn batch(cfg: &Conf, stop_on_error: bool) -> Result {
+    let stdin = io::stdin();
+    let mut cmds = Vec::new();
+    for line in stdin.lock().lines() {
+        if let Some(cmd) = batch_line(&line?)? {
+            cmds.push(cmd);
+        }
+    }
+
+    let cmd = Command::Batch(cmds, stop_on_error);
+    let replies = match daemon::send_recv(cfg, cmd)? {
+        Reply::Batch(r) => r,
+        _ => unreachable!(),
+    };
+
+    let mut failed = false;
+    for reply in replies {
+        match reply {
+            Reply::Error(e) => {
+                error!("{}", e);
+                failed = true;
+            }
+            Reply::Other(r) => println!("{}", r),
+            Reply::Status(r) => println!("{}", r),
+            Reply::Search { songs, .. } => for song in songs {
+                println!("{}", song);
+            },
+            _ => (),
+        }
+    }
+
+    if failed {
+        Err("Some commands in the batch failed".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// The request for a line of a batch, or `None` for blank lines and
+/// comments.
+fn batch_line(line: &str) -> ::std::result::Result<Option<Command>, Error> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None)
+    }
+
+    let args = ::std::iter::once("subs").chain(line.split_whitespace());
+    let app =
+        cli::App::from_clap(cli::App::clap().get_matches_from_safe(args)?);
+    command(app.cmd).map(Some)
+}
+
+/// Converts a command line invocation into the request it would send to the
+/// daemon, for commands that map onto a single request.
+fn command(cmd: AppCommand) -> ::std::result::Result<Command, Error> {
+    use cli::AppCommand::*;
+    Ok(match cmd {
+        Add { query } => add_command(query, false),
+        AddNext { query } => add_command(query, true),
+        Clear => Command::Clear,
+        Next => Command::Next,
+        Pause => Command::Pause,
+        Play => Command::Play,
+        Prev => Command::Prev,
+        Random { number } => Command::Random(number),
+        Status => Command::StatusReq,
+        Toggle => Command::Toggle,
+        Update { wait: false } => Command::Scan,
+        Search {
+            query,
+            only_artists,
+            only_albums,
+            number,
+        } => Command::Search(
+            ::collapse(query),
+            only_artists,
+            only_albums,
+            !(only_artists || only_albums),
+            number,
+        ),
+        Star {
+            album,
+            artist,
+            query,
+        } => Command::Star(kind(album, artist), query_or_current(query)),
+        Unstar {
+            album,
+            artist,
+            query,
+        } => Command::Unstar(kind(album, artist), query_or_current(query)),
+        Rate {
+            rating,
+            album,
+            artist,
+            query,
+        } => Command::Rate(
+            kind(album, artist),
+            query_or_current(query),
+            rating,
+        ),
+        Info {
+            album,
+            artist,
+            query,
+        } => {
+            let query = if query.len() == 1 && query[0] == "current" {
+                None
+            } else {
+                query_or_current(query)
+            };
+            Command::Info(kind(album, artist), query)
+        }
+        Save {
+            name,
+            public,
+            comment,
+            force,
+        } => Command::Save(name, public, comment, force),
+        _ => return Err("Command can't be used in a batch".into()),
+    })
+}
+
+fn kind(album: bool, artist: bool) -> Kind {
+    if album {
+        Kind::Album
+    } else if artist {
+        Kind::Artist
+    } else {
+        Kind::Song
+    }
+}
+
+/// A file or URL is added as it is; anything else is searched for.
+fn add_command(query: Vec<String>, next: bool) -> Command {
+    if query.len() == 1 {
+        if let Some(source) = Source::from_arg(&query[0]) {
+            return Command::AddSource(source, next)
+        }
+    }
+    let query = ::collapse(query);
+    if next {
+        Command::AddNextSearch(query)
+    } else {
+        Command::AddSearch(query)
+    }
+}
+
+/// An empty query means the command applies to the current song.
+fn query_or_current(query: Vec<String>) -> Option<String> {
+    if query.is_empty() {
+        None
+    } else {
+        Some(::collapse(query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_line, playlist_command, wait_for_scan};
+    use super::SCAN_START_TIMEOUT;
+    use cli::PlaylistCommand;
+    use daemon::Command::{self, *};
+    use daemon::Kind;
+    use queue::Source;
+
+    fn cmd(line: &str) -> Option<Command> { batch_line(line).unwrap() }
+
+    fn err(line: &str) -> String { batch_line(line).unwrap_err().to_string() }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        assert_eq!(cmd("   "), None);
+        assert_eq!(cmd("# add everything"), None);
+    }
+
+    #[test]
+    fn translates_each_line() {
+        assert_eq!(cmd("next"), Some(Next));
+        assert_eq!(cmd("  toggle  "), Some(Toggle));
+        assert_eq!(cmd("random 5"), Some(Random(5)));
+        assert_eq!(cmd("status"), Some(StatusReq));
+        assert_eq!(
+            cmd("search  blue  monday"),
+            Some(Search("blue monday".into(), false, false, true, 20))
+        );
+    }
+
+    #[test]
+    fn rejects_what_a_batch_cannot_do() {
+        assert_eq!(err("daemon stop"), "Command can't be used in a batch");
+        assert_eq!(err("shuffle"), "Command can't be used in a batch");
+        assert_eq!(err("lyrics"), "Command can't be used in a batch");
+        assert!(err("random lots").contains("invalid digit"));
+        assert!(err("frobnicate").contains("frobnicate"));
+    }
+
+    #[test]
+    fn stars_the_song_unless_told_otherwise() {
+        assert_eq!(cmd("star"), Some(Star(Kind::Song, None)));
+        assert_eq!(cmd("star --album"), Some(Star(Kind::Album, None)));
+        assert_eq!(
+            cmd("unstar -a new order"),
+            Some(Unstar(Kind::Artist, Some("new order".into())))
+        );
+        assert!(err("star -a -b").contains("cannot be used with"));
+    }
+
+    #[test]
+    fn reads_the_rating_before_the_query() {
+        assert_eq!(cmd("rate 4"), Some(Rate(Kind::Song, None, 4)));
+        assert_eq!(
+            cmd("rate 4 -a new order"),
+            Some(Rate(Kind::Artist, Some("new order".into()), 4))
+        );
+        assert!(err("rate loud").contains("invalid digit"));
+        assert!(err("rate 300").contains("too large"));
+    }
+
+    #[test]
+    fn saves_with_the_given_options() {
+        assert_eq!(
+            cmd("save mix --public -f"),
+            Some(Save("mix".into(), true, None, true))
+        );
+        assert_eq!(
+            cmd("save mix --comment road"),
+            Some(Save("mix".into(), false, Some("road".into()), false))
+        );
+    }
+
+    #[test]
+    fn only_starts_a_scan_in_a_batch() {
+        assert_eq!(cmd("update"), Some(Scan));
+        assert_eq!(err("update --wait"), "Command can't be used in a batch");
+    }
+
+    #[test]
+    fn shows_info_for_the_current_song_or_a_query() {
+        assert_eq!(cmd("info current"), Some(Info(Kind::Song, None)));
+        assert_eq!(
+            cmd("info -b 1999"),
+            Some(Info(Kind::Album, Some("1999".into())))
+        );
+    }
+
+    #[test]
+    fn adds_urls_as_they_are_and_searches_for_the_rest() {
+        let url = "https://radio.example/live";
+        assert_eq!(
+            cmd(&format!("addnext {}", url)),
+            Some(AddSource(Source::Url(url.into()), true))
+        );
+        assert_eq!(
+            cmd("add  blue  monday"),
+            Some(AddSearch("blue monday".into()))
+        );
+        assert_eq!(
+            cmd("addnext ceremony"),
+            Some(AddNextSearch("ceremony".into()))
+        );
+    }
+
+    #[test]
+    fn playlist_add_defaults_to_the_current_song() {
+        let add = |query: &[&str]| {
+            playlist_command(PlaylistCommand::Add {
+                name: "Mix".into(),
+                query: query.iter().map(|q| q.to_string()).collect(),
+            })
+        };
+        assert_eq!(add(&[]), PlaylistAdd("Mix".into(), None));
+        assert_eq!(add(&["current"]), PlaylistAdd("Mix".into(), None));
+        assert_eq!(
+            add(&["current", "joys"]),
+            PlaylistAdd("Mix".into(), Some("current joys".into()))
+        );
+    }
+
+    /// Runs `wait_for_scan` against the given poll results, returning the
+    /// final count and how many times it polled.
+    fn scan(first: (bool, u64), polls: &[(bool, u64)]) -> (u64, usize) {
+        let mut n = 0;
+        let count = wait_for_scan(
+            first.0,
+            first.1,
+            || {
+                n += 1;
+                Ok(polls.get(n - 1).cloned().unwrap_or(first))
+            },
+            |_| Ok(()),
+        );
+        (count.unwrap(), n)
+    }
+
+    #[test]
+    fn waits_for_a_scan_to_start_and_finish() {
+        let polls = [(false, 0), (true, 5), (true, 9), (false, 12)];
+        assert_eq!(scan((false, 0), &polls), (12, 4));
+
+        // Scans too quick to be seen running still count items.
+        assert_eq!(scan((false, 3), &[(false, 7)]), (7, 1));
+    }
+
+    #[test]
+    fn gives_up_on_a_scan_that_never_starts() {
+        assert_eq!(scan((false, 3), &[]), (3, SCAN_START_TIMEOUT as usize));
+    }
 }
@@ -0,0 +1,45 @@
+//! A private D-Bus daemon for tests, so they don't touch the desktop's bus.
+
+use dbus::Connection;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+pub struct PrivateBus {
+    child: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    /// Starts a bus. Tests using one are ignored by default, since they
+    /// need `dbus-daemon` installed.
+    pub fn start() -> PrivateBus {
+        let mut child = Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("unable to start dbus-daemon");
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .expect("unable to read the bus address");
+        PrivateBus {
+            child,
+            address: address.trim().to_string(),
+        }
+    }
+
+    pub fn connect(&self) -> Connection {
+        let conn = Connection::open_private(&self.address)
+            .expect("unable to connect to private bus");
+        conn.register().expect("unable to register on private bus");
+        conn
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,50 @@
+//! A local HTTP server for tests, standing in for the Subsonic server and
+//! scrobbling services.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Answers requests to a local server with each status and body in turn,
+/// passing on the head and body of every request it gets.
+pub fn serve(
+    responses: Vec<(u16, &'static str)>,
+) -> (String, Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break
+                }
+                let lower = line.to_lowercase();
+                if lower.starts_with("content-length:") {
+                    len = lower[15..].trim().parse().unwrap();
+                }
+                head.push_str(&line);
+            }
+            let mut req = vec![0; len];
+            reader.read_exact(&mut req).unwrap();
+            let _ = send.send((head, String::from_utf8(req).unwrap()));
+
+            write!(
+                reader.into_inner(),
+                "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            ).unwrap();
+        }
+    });
+    (url, recv)
+}
@@ -0,0 +1,686 @@
+//! A full-screen client, talking to the daemon like any other.
+//!
+//! There are four panes: the queue, the library (artists, then their albums,
+//! then the songs on them), search results and playlists. The now-playing
+//! bar at the bottom follows the daemon's events, and ticks along every
+//! second while a song plays.
+
+use crossbeam_channel::{self, RecvTimeoutError, Sender};
+use std::cmp;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{clear, cursor, style, terminal_size};
+
+use conf::Conf;
+use daemon::{self, Command, Reply};
+use error::{Error, Result};
+use event::Event;
+use player::{State, Status, Track};
+
+/// How far the arrow keys seek, in seconds.
+const SEEK_STEP: u64 = 10;
+/// Most search results to show.
+const SEARCH_SIZE: usize = 100;
+
+const HELP: &str = "1-4 panes  enter open/add  a add  A add next  \
+                    space toggle  < > prev/next  left/right seek  \
+                    / search  c clear  q quit";
+
+enum Input {
+    Key(Key),
+    Event(Event),
+    /// The daemon went away.
+    Lost,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pane {
+    Queue,
+    Library,
+    Search,
+    Playlists,
+}
+
+/// What a list is showing, which decides what selecting a row does.
+#[derive(Clone, PartialEq)]
+enum Kind {
+    Songs,
+    Artists,
+    Albums,
+    Playlists,
+}
+
+struct Row {
+    id: u64,
+    label: String,
+    /// Shown on the right.
+    detail: String,
+}
+
+struct View {
+    kind: Kind,
+    title: String,
+    rows: Vec<Row>,
+    selected: usize,
+    /// First row on screen.
+    offset: usize,
+}
+
+impl View {
+    fn new(kind: Kind, title: String, rows: Vec<Row>) -> View {
+        View {
+            kind,
+            title,
+            rows,
+            selected: 0,
+            offset: 0,
+        }
+    }
+
+    fn songs(title: String, tracks: Vec<Track>) -> View {
+        let rows = tracks
+            .into_iter()
+            .map(|t| Row {
+                id: t.id,
+                label: t.to_string(),
+                detail: minsec(t.duration),
+            })
+            .collect();
+        View::new(Kind::Songs, title, rows)
+    }
+
+    fn current(&self) -> Option<&Row> { self.rows.get(self.selected) }
+
+    fn select(&mut self, to: isize) {
+        let max = self.rows.len() as isize - 1;
+        self.selected = cmp::max(0, cmp::min(to, max)) as usize;
+    }
+
+    fn shift(&mut self, by: isize) {
+        let to = self.selected as isize + by;
+        self.select(to);
+    }
+}
+
+struct App {
+    cfg: Conf,
+    pane: Pane,
+    queue: View,
+    /// Each pane other than the queue can be drilled into, so it's a stack.
+    library: Vec<View>,
+    search: Vec<View>,
+    playlists: Vec<View>,
+    status: Option<Status>,
+    /// The search being typed, if there is one.
+    query: Option<String>,
+    message: String,
+}
+
+/// Runs the interface until the user quits.
+pub fn run(cfg: Conf) -> Result {
+    let events = daemon::subscribe(&cfg)?;
+
+    let (send, recv) = crossbeam_channel::unbounded();
+    keys(send.clone());
+    thread::spawn(move || {
+        for ev in events {
+            if send.send(Input::Event(ev)).is_err() {
+                return
+            }
+        }
+        let _ = send.send(Input::Lost);
+    });
+
+    let mut app = App {
+        cfg,
+        pane: Pane::Queue,
+        queue: View::songs("Queue".into(), Vec::new()),
+        library: Vec::new(),
+        search: vec![View::songs("Search".into(), Vec::new())],
+        playlists: Vec::new(),
+        status: None,
+        query: None,
+        message: HELP.into(),
+    };
+    app.refresh_queue()?;
+    app.refresh_status()?;
+
+    let screen = AlternateScreen::from(io::stdout().into_raw_mode()?);
+    let mut out = io::BufWriter::new(screen);
+    write!(out, "{}", cursor::Hide)?;
+
+    let res = (|| -> Result {
+        loop {
+            app.draw(&mut out)?;
+
+            let input = match recv.recv_timeout(Duration::from_secs(1)) {
+                Ok(i) => i,
+                Err(RecvTimeoutError::Timeout) => {
+                    app.tick();
+                    continue
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            match input {
+                Input::Key(key) => {
+                    let res = app.key(key);
+                    match res {
+                        Ok(true) => return Ok(()),
+                        Ok(false) => (),
+                        Err(e) => app.message = e.to_string(),
+                    }
+                }
+                Input::Event(ev) => {
+                    if let Err(e) = app.event(&ev) {
+                        app.message = e.to_string();
+                    }
+                }
+                Input::Lost => {
+                    return Err("Lost connection to the daemon".into())
+                }
+            }
+        }
+    })();
+
+    write!(out, "{}{}", clear::All, cursor::Show)?;
+    out.flush()?;
+    res
+}
+
+/// Reads keys on their own thread.
+fn keys(send: Sender<Input>) {
+    thread::spawn(move || {
+        for key in io::stdin().keys() {
+            match key {
+                Ok(k) => if send.send(Input::Key(k)).is_err() {
+                    return
+                },
+                Err(e) => return error!("unable to read key: {}", e),
+            }
+        }
+    });
+}
+
+impl App {
+    fn request(&self, cmd: Command) -> ::std::result::Result<Reply, Error> {
+        daemon::send_recv(&self.cfg, cmd)
+    }
+
+    fn songs(&self, cmd: Command) -> ::std::result::Result<Vec<Track>, Error> {
+        match self.request(cmd)? {
+            Reply::Songs(s) => Ok(s),
+            _ => unreachable!(),
+        }
+    }
+
+    fn listing(
+        &self,
+        cmd: Command,
+    ) -> ::std::result::Result<Vec<(u64, String)>, Error> {
+        match self.request(cmd)? {
+            Reply::Listing(l) => Ok(l),
+            _ => unreachable!(),
+        }
+    }
+
+    fn refresh_queue(&mut self) -> Result {
+        let selected = self.queue.selected;
+        let queue = self.songs(Command::QueueReq)?;
+        self.queue = View::songs("Queue".into(), queue);
+        self.queue.select(selected as isize);
+        Ok(())
+    }
+
+    fn refresh_status(&mut self) -> Result {
+        match self.request(Command::StatusReq)? {
+            Reply::Status(st) => self.status = Some(st),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Moves the progress along between events.
+    fn tick(&mut self) {
+        if let Some(ref mut st) = self.status {
+            if st.state == State::Playing {
+                st.elapsed += 1;
+            }
+        }
+    }
+
+    fn event(&mut self, ev: &Event) -> Result {
+        match *ev {
+            Event::Queue => self.refresh_queue()?,
+            Event::Error(ref e) => self.message = e.clone(),
+            _ => (),
+        }
+        self.refresh_status()
+    }
+
+    fn stack(&mut self) -> Option<&mut Vec<View>> {
+        match self.pane {
+            Pane::Queue => None,
+            Pane::Library => Some(&mut self.library),
+            Pane::Search => Some(&mut self.search),
+            Pane::Playlists => Some(&mut self.playlists),
+        }
+    }
+
+    fn view(&mut self) -> Option<&mut View> {
+        match self.pane {
+            Pane::Queue => Some(&mut self.queue),
+            _ => self.stack().and_then(|s| s.last_mut()),
+        }
+    }
+
+    fn switch(&mut self, pane: Pane) -> Result {
+        self.pane = pane;
+        match pane {
+            Pane::Library if self.library.is_empty() => {
+                let rows = self.listing(Command::Artists)?
+                    .into_iter()
+                    .map(|(id, label)| Row {
+                        id,
+                        label,
+                        detail: String::new(),
+                    })
+                    .collect();
+                self.library
+                    .push(View::new(Kind::Artists, "Artists".into(), rows));
+            }
+            Pane::Playlists if self.playlists.is_empty() => {
+                let lists = match self.request(Command::Playlists)? {
+                    Reply::Playlists(l) => l,
+                    _ => unreachable!(),
+                };
+                let rows = lists
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (name, count))| Row {
+                        id: i as u64,
+                        label: name,
+                        detail: format!("{} songs", count),
+                    })
+                    .collect();
+                self.playlists.push(View::new(
+                    Kind::Playlists,
+                    "Playlists".into(),
+                    rows,
+                ));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Handles a key, returning whether to quit.
+    fn key(&mut self, key: Key) -> ::std::result::Result<bool, Error> {
+        if self.query.is_some() {
+            self.edit_query(key)?;
+            return Ok(false)
+        }
+
+        match key {
+            Key::Char('q') | Key::Ctrl('c') => return Ok(true),
+            Key::Char('1') => self.switch(Pane::Queue)?,
+            Key::Char('2') => self.switch(Pane::Library)?,
+            Key::Char('3') => self.switch(Pane::Search)?,
+            Key::Char('4') => self.switch(Pane::Playlists)?,
+            Key::Char('\t') => {
+                let next = match self.pane {
+                    Pane::Queue => Pane::Library,
+                    Pane::Library => Pane::Search,
+                    Pane::Search => Pane::Playlists,
+                    Pane::Playlists => Pane::Queue,
+                };
+                self.switch(next)?
+            }
+            Key::Char('j') | Key::Down => self.move_by(1),
+            Key::Char('k') | Key::Up => self.move_by(-1),
+            Key::PageDown => self.move_by(10),
+            Key::PageUp => self.move_by(-10),
+            Key::Char('g') | Key::Home => self.move_to(0),
+            Key::Char('G') | Key::End => self.move_to(isize::max_value()),
+            Key::Backspace | Key::Char('h') => {
+                if let Some(stack) = self.stack() {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                }
+            }
+            Key::Char('\n') | Key::Char('l') => self.open()?,
+            Key::Char('a') => self.add(false)?,
+            Key::Char('A') => self.add(true)?,
+            Key::Char(' ') => daemon::send(&self.cfg, Command::Toggle)?,
+            Key::Char('>') => daemon::send(&self.cfg, Command::Next)?,
+            Key::Char('<') => daemon::send(&self.cfg, Command::Prev)?,
+            Key::Right => self.seek(SEEK_STEP as i64)?,
+            Key::Left => self.seek(-(SEEK_STEP as i64))?,
+            Key::Char('c') => daemon::send(&self.cfg, Command::Clear)?,
+            Key::Char('/') => {
+                self.switch(Pane::Search)?;
+                self.query = Some(String::new());
+            }
+            _ => (),
+        }
+        Ok(false)
+    }
+
+    fn edit_query(&mut self, key: Key) -> Result {
+        match key {
+            Key::Char('\n') => {
+                let q = self.query.take().unwrap_or_default();
+                if q.is_empty() {
+                    return Ok(())
+                }
+                let cmd = Command::SearchSongs(q.clone(), SEARCH_SIZE);
+                let found = self.songs(cmd)?;
+                self.message =
+                    format!("{} results for \"{}\".", found.len(), q);
+                let title = format!("Search: {}", q);
+                self.search = vec![View::songs(title, found)];
+            }
+            Key::Esc => self.query = None,
+            Key::Backspace => if let Some(ref mut q) = self.query {
+                q.pop();
+            },
+            Key::Char(c) => if let Some(ref mut q) = self.query {
+                q.push(c);
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn move_by(&mut self, by: isize) {
+        if let Some(v) = self.view() {
+            v.shift(by);
+        }
+    }
+
+    fn move_to(&mut self, to: isize) {
+        if let Some(v) = self.view() {
+            v.select(to);
+        }
+    }
+
+    /// Opens the selected artist, album or playlist, or adds the selected
+    /// song.
+    fn open(&mut self) -> Result {
+        let (kind, id, label) = match self.view() {
+            Some(v) => match v.current() {
+                Some(r) => (v.kind.clone(), r.id, r.label.clone()),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        let view = match kind {
+            Kind::Songs => return self.add(false),
+            Kind::Artists => {
+                let rows = self.listing(Command::ArtistAlbums(id))?
+                    .into_iter()
+                    .map(|(id, label)| Row {
+                        id,
+                        label,
+                        detail: String::new(),
+                    })
+                    .collect();
+                View::new(Kind::Albums, label, rows)
+            }
+            Kind::Albums => {
+                View::songs(label, self.songs(Command::AlbumSongs(id))?)
+            }
+            Kind::Playlists => View::songs(
+                label.clone(),
+                self.songs(Command::PlaylistShow(label))?,
+            ),
+        };
+        if let Some(stack) = self.stack() {
+            stack.push(view);
+        }
+        Ok(())
+    }
+
+    /// Adds the selected song, or everything on the selected album.
+    fn add(&mut self, next: bool) -> Result {
+        let (kind, id, label) = match self.view() {
+            Some(v) => match v.current() {
+                Some(r) => (v.kind.clone(), r.id, r.label.clone()),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        match kind {
+            Kind::Songs if self.pane != Pane::Queue => {
+                let cmd = if next {
+                    Command::AddNext(id)
+                } else {
+                    Command::Add(id)
+                };
+                daemon::send(&self.cfg, cmd)?;
+                self.message = format!("Added {}.", label);
+            }
+            Kind::Albums => {
+                let ids = self.songs(Command::AlbumSongs(id))?
+                    .iter()
+                    .map(|t| t.id)
+                    .collect();
+                daemon::send(&self.cfg, Command::AddMany(ids))?;
+                self.message = format!("Added {}.", label);
+            }
+            Kind::Playlists => {
+                self.request(Command::Load(label.clone()))?;
+                self.message = format!("Loaded {}.", label);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, by: i64) -> Result {
+        let elapsed = match self.status {
+            Some(ref st) if st.track.is_some() => st.elapsed as i64,
+            _ => return Ok(()),
+        };
+        let to = cmp::max(0, elapsed + by) as u64;
+        daemon::send(&self.cfg, Command::Seek(to))
+    }
+
+    fn draw<W: Write>(&mut self, out: &mut W) -> Result {
+        let (width, height) = terminal_size()?;
+        let (width, height) = (width as usize, height as usize);
+        write!(out, "{}", clear::All)?;
+
+        // Tabs along the top.
+        write!(out, "{}", cursor::Goto(1, 1))?;
+        for &(pane, name) in &[
+            (Pane::Queue, "1 Queue"),
+            (Pane::Library, "2 Library"),
+            (Pane::Search, "3 Search"),
+            (Pane::Playlists, "4 Playlists"),
+        ] {
+            if pane == self.pane {
+                write!(out, "{} {} {}", style::Invert, name, style::Reset)?;
+            } else {
+                write!(out, " {} ", name)?;
+            }
+        }
+
+        // The list takes whatever the tabs, title and bottom bar leave.
+        let rows = height.saturating_sub(6);
+        let query = self.query.clone();
+        if let Some(view) = self.view() {
+            if view.selected < view.offset {
+                view.offset = view.selected;
+            } else if rows > 0 && view.selected >= view.offset + rows {
+                view.offset = view.selected + 1 - rows;
+            }
+
+            write!(
+                out,
+                "{}{}{}{}",
+                cursor::Goto(1, 2),
+                style::Bold,
+                fit(&view.title, width),
+                style::Reset
+            )?;
+            for (i, row) in
+                view.rows.iter().enumerate().skip(view.offset).take(rows)
+            {
+                let y = (i - view.offset + 3) as u16;
+                let detail = fit(&row.detail, width / 3);
+                let room = width.saturating_sub(detail.chars().count() + 1);
+                let line = format!(
+                    "{:<room$} {}",
+                    fit(&row.label, room),
+                    detail,
+                    room = room
+                );
+                if i == view.selected {
+                    write!(
+                        out,
+                        "{}{}{}{}",
+                        cursor::Goto(1, y),
+                        style::Invert,
+                        line,
+                        style::Reset
+                    )?;
+                } else {
+                    write!(out, "{}{}", cursor::Goto(1, y), line)?;
+                }
+            }
+        }
+
+        // Now playing, progress, then messages or the search being typed.
+        let y = height.saturating_sub(2) as u16;
+        let (now, progress) = now_playing(&self.status, width);
+        write!(out, "{}{}", cursor::Goto(1, y), fit(&now, width))?;
+        write!(out, "{}{}", cursor::Goto(1, y + 1), progress)?;
+        let bottom = match query {
+            Some(q) => format!("/{}", q),
+            None => self.message.clone(),
+        };
+        write!(out, "{}{}", cursor::Goto(1, y + 2), fit(&bottom, width))?;
+
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// The now-playing line and a progress meter as wide as the screen.
+fn now_playing(status: &Option<Status>, width: usize) -> (String, String) {
+    let st = match *status {
+        Some(ref st) => st,
+        None => return (String::new(), String::new()),
+    };
+    let track = match st.track {
+        Some(ref t) => t,
+        None => return ("[stopped]".into(), String::new()),
+    };
+
+    let state = match st.state {
+        State::Playing => "playing",
+        State::Paused => "paused",
+        State::Stopped => "stopped",
+    };
+    let now = format!("[{}] {}", state, track);
+
+    let elapsed = cmp::min(st.elapsed, track.duration);
+    let times = format!(" {}/{}", minsec(elapsed), minsec(track.duration));
+    let bar = width.saturating_sub(times.len() + 2);
+    let done = if track.duration > 0 {
+        bar * elapsed as usize / track.duration as usize
+    } else {
+        0
+    };
+    let progress = format!(
+        "[{}{}]{}",
+        "=".repeat(done),
+        " ".repeat(bar - done),
+        times
+    );
+    (now, progress)
+}
+
+/// Cuts a string down to `width` characters.
+fn fit(s: &str, width: usize) -> String { s.chars().take(width).collect() }
+
+fn minsec(secs: u64) -> String { format!("{}:{:02}", secs / 60, secs % 60) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(duration: u64) -> Track {
+        Track {
+            id: 1,
+            title: "Roygbiv".into(),
+            artist: Some("Boards of Canada".into()),
+            album: None,
+            duration,
+            cover_url: None,
+            cover_path: None,
+            location: None,
+        }
+    }
+
+    fn status(elapsed: u64, track: Option<Track>) -> Option<Status> {
+        Some(Status {
+            state: State::Playing,
+            position: 0,
+            len: 1,
+            elapsed,
+            elapsed_ms: elapsed * 1000,
+            volume: 100,
+            track,
+        })
+    }
+
+    #[test]
+    fn selection_stays_in_the_list() {
+        let tracks = (0..3).map(|_| track(60)).collect();
+        let mut view = View::songs("Queue".into(), tracks);
+        view.shift(-1);
+        assert_eq!(view.selected, 0);
+        view.shift(5);
+        assert_eq!(view.selected, 2);
+        view.select(1);
+        assert_eq!(view.current().unwrap().detail, "1:00");
+
+        let mut empty = View::songs("Queue".into(), Vec::new());
+        empty.shift(1);
+        assert_eq!(empty.selected, 0);
+        assert!(empty.current().is_none());
+    }
+
+    #[test]
+    fn progress_fills_the_width() {
+        let (now, bar) = now_playing(&status(60, Some(track(120))), 30);
+        assert_eq!(now, "[playing] Boards of Canada - Roygbiv");
+        assert_eq!(bar, "[=========         ] 1:00/2:00");
+        assert_eq!(bar.len(), 30);
+
+        // Streams have no duration, and elapsed time can run past it.
+        let (_, bar) = now_playing(&status(90, Some(track(0))), 20);
+        assert_eq!(bar, "[        ] 0:00/0:00");
+        let (_, bar) = now_playing(&status(999, Some(track(60))), 20);
+        assert_eq!(bar, "[========] 1:00/1:00");
+    }
+
+    #[test]
+    fn nothing_playing() {
+        assert_eq!(now_playing(&None, 20), (String::new(), String::new()));
+        assert_eq!(
+            now_playing(&status(0, None), 20),
+            ("[stopped]".to_string(), String::new())
+        );
+        assert_eq!(fit("Roygbiv", 3), "Roy");
+    }
+}